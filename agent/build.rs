@@ -1,4 +1,12 @@
+use std::env;
+
 fn main() {
+    // libdtrace is only linked on FreeBSD, elsewhere (such as Linux CI)
+    // the instrumentation is tested with the mock tracing backend
+    if env::var("CARGO_CFG_TARGET_OS").map(|os| os != "freebsd").unwrap_or(true) {
+        return;
+    }
+
     println!("cargo:rustc-link-search=native=/usr/local/lib");
     println!("cargo:rustc-link-lib=dylib=dtrace");
     println!("cargo:rustc-link-lib=dylib=proc");
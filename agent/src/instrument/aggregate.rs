@@ -0,0 +1,630 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::slice;
+use std::time::{Duration, Instant};
+use rustc_serialize::{Decodable, Decoder};
+use super::ddtrace_record::{AggregationEntry, Bucket, RecordValue};
+use super::libdtrace;
+use super::record::{c_str, decode_value};
+
+// Aggregating actions (from sys/dtrace.h)
+pub const DTRACEAGG_COUNT: u16 = 0x0701;
+pub const DTRACEAGG_MAX: u16 = 0x0702;
+pub const DTRACEAGG_MIN: u16 = 0x0703;
+pub const DTRACEAGG_SUM: u16 = 0x0704;
+pub const DTRACEAGG_AVG: u16 = 0x0705;
+pub const DTRACEAGG_QUANTIZE: u16 = 0x0706;
+pub const DTRACEAGG_LQUANTIZE: u16 = 0x0707;
+pub const DTRACEAGG_STDDEV: u16 = 0x0708;
+pub const DTRACEAGG_LLQUANTIZE: u16 = 0x0709;
+
+// dtrace_aggregate_walk() callback return value
+pub const DTRACE_AGGWALK_NEXT: i32 = 1;
+
+// quantize() buckets: powers of two from -2^62 to 2^62 either side of zero
+const DTRACE_QUANTIZE_NBUCKETS: usize = 127;
+const DTRACE_QUANTIZE_ZEROBUCKET: usize = 63;
+
+// Whether reports carry the aggregations' values since the script started
+// or only those accumulated during the reporting interval
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AggregationMode {
+    Cumulative,
+    // The aggregations are cleared after each report
+    Delta,
+}
+
+impl AggregationMode {
+    pub fn from_name(name: &str) -> Option<AggregationMode> {
+        match name {
+            "cumulative" => Some(AggregationMode::Cumulative),
+            "delta" => Some(AggregationMode::Delta),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            AggregationMode::Cumulative => "cumulative",
+            AggregationMode::Delta => "delta",
+        }
+    }
+}
+
+// Export settings of a single aggregation, configured in the
+// [instrumentation.aggregations.<name>] table
+#[derive(Debug)]
+pub struct AggregationConfig {
+    top_n: Option<usize>,
+    sort: Option<String>,
+}
+
+impl Decodable for AggregationConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<AggregationConfig, D::Error> {
+        d.read_struct("AggregationConfig", 2, |d| Ok(AggregationConfig {
+            top_n: d.read_struct_field("top_n", 0, Decodable::decode)?,
+            sort: d.read_struct_field("sort", 1, Decodable::decode)?,
+        }))
+    }
+}
+
+// Order of an aggregation's entries in reports
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    // Descending value (dtrace_aggregate_walk_valrevsorted)
+    Value,
+    // Ascending key (dtrace_aggregate_walk_keysorted)
+    Key,
+}
+
+impl SortOrder {
+    pub fn from_name(name: &str) -> Option<SortOrder> {
+        match name {
+            "value" => Some(SortOrder::Value),
+            "key" => Some(SortOrder::Key),
+            _ => None,
+        }
+    }
+}
+
+struct AggregationExport {
+    sort: Option<SortOrder>,
+    top_n: Option<usize>,
+}
+
+// Schedules the periodic snapshots of the script's aggregations
+pub struct AggregationReporter {
+    mode: AggregationMode,
+    exports: HashMap<String, AggregationExport>,
+    interval: Duration,
+    last_report: Instant,
+    // Wall-clock start of the current reporting interval
+    interval_start: u64,
+}
+
+impl AggregationReporter {
+    pub fn new(interval: u64, mode: AggregationMode, start: u64,
+        config: Option<&HashMap<String, AggregationConfig>>)
+        -> Result<AggregationReporter, String> {
+
+        if interval == 0 {
+            return Err("aggregation_interval must be at least 1 second".to_string());
+        }
+
+        let mut exports = HashMap::new();
+        for (name, config) in config.into_iter().flat_map(|config| config.iter()) {
+            let sort = match config.sort.as_ref() {
+                Some(sort) => match SortOrder::from_name(sort) {
+                    Some(sort) => Some(sort),
+                    None => return Err(format!("unknown sort order {} for @{}", sort, name)),
+                },
+                // top_n selects the largest values unless told otherwise
+                None if config.top_n.is_some() => Some(SortOrder::Value),
+                None => None,
+            };
+            exports.insert(name.trim_left_matches('@').to_string(),
+                AggregationExport { sort: sort, top_n: config.top_n });
+        }
+
+        Ok(AggregationReporter {
+            mode: mode,
+            exports: exports,
+            interval: Duration::from_secs(interval),
+            last_report: Instant::now(),
+            interval_start: start,
+        })
+    }
+
+    // The sorted walks required by the configured aggregations
+    pub fn sort_orders(&self) -> Vec<SortOrder> {
+        let mut orders: Vec<SortOrder> = Vec::new();
+        for order in self.exports.values().filter_map(|export| export.sort) {
+            if !orders.contains(&order) {
+                orders.push(order);
+            }
+        }
+        orders
+    }
+
+    // Select the entries to report from the unsorted walk of the
+    // aggregations and the walks in each of the required sort orders:
+    // each aggregation's entries are taken from the walk in its sort order
+    // and limited to its top_n, the remaining entries being combined into
+    // a single "others" entry
+    pub fn select(&self, unsorted: Vec<AggregationEntry>,
+        sorted: Vec<(SortOrder, Vec<AggregationEntry>)>) -> Vec<AggregationEntry> {
+
+        if self.exports.is_empty() {
+            return unsorted;
+        }
+
+        let mut names: Vec<String> = Vec::new();
+        let mut groups: HashMap<String, Vec<AggregationEntry>> = HashMap::new();
+        for entry in unsorted {
+            if !groups.contains_key(&entry.name) {
+                names.push(entry.name.clone());
+            }
+            groups.entry(entry.name.clone()).or_insert_with(Vec::new).push(entry);
+        }
+        for (order, entries) in sorted {
+            let mut sorted_groups: HashMap<String, Vec<AggregationEntry>> = HashMap::new();
+            for entry in entries {
+                let selected = self.exports.get(&entry.name)
+                    .map_or(false, |export| export.sort == Some(order));
+                if selected {
+                    sorted_groups.entry(entry.name.clone()).or_insert_with(Vec::new).push(entry);
+                }
+            }
+            groups.extend(sorted_groups);
+        }
+
+        let mut selected = Vec::new();
+        for name in names {
+            let mut entries = groups.remove(&name).unwrap_or_default();
+            if let Some(top_n) = self.exports.get(&name).and_then(|export| export.top_n) {
+                if entries.len() > top_n {
+                    let rest = entries.split_off(top_n);
+                    entries.push(others_entry(&rest));
+                }
+            }
+            selected.extend(entries);
+        }
+        selected
+    }
+
+    pub fn mode(&self) -> AggregationMode {
+        self.mode
+    }
+
+    // Returns true if the reporting interval has elapsed
+    pub fn due(&self) -> bool {
+        self.last_report.elapsed() >= self.interval
+    }
+
+    // End the current reporting interval at the given wall-clock time,
+    // returning its start and end
+    pub fn end_interval(&mut self, end: u64) -> (u64, u64) {
+        let start = self.interval_start;
+        self.interval_start = end;
+        self.last_report = Instant::now();
+        (start, end)
+    }
+}
+
+fn function_name(action: u16) -> &'static str {
+    match action {
+        DTRACEAGG_COUNT => "count",
+        DTRACEAGG_MAX => "max",
+        DTRACEAGG_MIN => "min",
+        DTRACEAGG_SUM => "sum",
+        DTRACEAGG_AVG => "avg",
+        DTRACEAGG_QUANTIZE => "quantize",
+        DTRACEAGG_LQUANTIZE => "lquantize",
+        DTRACEAGG_STDDEV => "stddev",
+        DTRACEAGG_LLQUANTIZE => "llquantize",
+        _ => "unknown",
+    }
+}
+
+// Value of the lower bound of a quantize() bucket (DTRACE_QUANTIZE_BUCKETVAL)
+fn quantize_bucket_value(bucket: usize) -> i64 {
+    if bucket < DTRACE_QUANTIZE_ZEROBUCKET {
+        -(1i64 << (DTRACE_QUANTIZE_ZEROBUCKET - 1 - bucket))
+    } else if bucket == DTRACE_QUANTIZE_ZEROBUCKET {
+        0
+    } else {
+        1i64 << (bucket - DTRACE_QUANTIZE_ZEROBUCKET - 1)
+    }
+}
+
+// Build the non-empty buckets given the lower bound of each bucket (the
+// first bucket also holds any values below its lower bound, the last any
+// values above)
+fn buckets(lower: &[i64], counts: &[i64], normal: i64) -> Vec<Bucket> {
+    counts.iter().enumerate()
+        .filter(|&(_, count)| *count != 0)
+        .map(|(i, count)| Bucket {
+            lower: if i == 0 { i64::min_value() } else { lower[i] },
+            upper: lower.get(i + 1).map(|next| next - 1).unwrap_or(i64::max_value()),
+            count: count / normal,
+        })
+        .collect()
+}
+
+fn quantize_buckets(counts: &[i64], normal: i64) -> Vec<Bucket> {
+    let lower = (0..DTRACE_QUANTIZE_NBUCKETS)
+        .map(quantize_bucket_value)
+        .collect::<Vec<i64>>();
+    buckets(&lower, counts, normal)
+}
+
+// lquantize() data is prefixed with its parameters: the buckets are an
+// underflow bucket, levels buckets of width step from base and an overflow
+// bucket
+fn lquantize_buckets(data: &[i64], normal: i64) -> Vec<Bucket> {
+    let arg = data[0] as u64;
+    let step = ((arg >> 48) & 0xffff) as i64;
+    let levels = ((arg >> 32) & 0xffff) as i64;
+    let base = (arg & 0xffff_ffff) as i32 as i64;
+
+    let mut lower = vec![i64::min_value()];
+    lower.extend((0..levels + 1).map(|level| base + level * step));
+    buckets(&lower, &data[1..], normal)
+}
+
+// llquantize() data is prefixed with its parameters: the buckets are an
+// underflow bucket, steps buckets per power of factor between factor^low
+// and factor^high and an overflow bucket (following dt_print_llquantize)
+fn llquantize_buckets(data: &[i64], normal: i64) -> Vec<Bucket> {
+    let arg = data[0] as u64;
+    let factor = ((arg >> 48) & 0xffff) as i64;
+    let low = ((arg >> 32) & 0xffff) as u32;
+    let high = ((arg >> 16) & 0xffff) as u32;
+    let nsteps = (arg & 0xffff) as i64;
+
+    let mut lower = vec![i64::min_value()];
+    let mut value = (0..low).fold(1i64, |v, _| v.saturating_mul(factor));
+    let mut next = value.saturating_mul(factor);
+    let mut order = low;
+    while order <= high && lower.len() < data.len() - 1 {
+        lower.push(value);
+        value += if next > nsteps { next / nsteps } else { 1 };
+        if value >= next {
+            order += 1;
+            next = value.saturating_mul(factor);
+        }
+    }
+    lower.push(value);
+    buckets(&lower, &data[1..], normal)
+}
+
+// stddev() data is the count, sum and (128-bit) sum of squares
+fn stddev(data: &[i64], normal: i64) -> i64 {
+    let count = data[0] as f64;
+    let variance = if count == 0.0 || data.len() < 4 {
+        0.0
+    } else {
+        let mean = data[1] as f64 / count;
+        let sumsq = data[2] as u64 as f64 + data[3] as u64 as f64 * 18446744073709551616.0;
+        (sumsq / count - mean * mean).max(0.0)
+    };
+    (variance.sqrt() / normal as f64) as i64
+}
+
+// Combine the entries beyond an aggregation's top_n into a single entry
+// without keys: counts and sums are added, min and max are the minimum and
+// maximum, averages and standard deviations are (unweighted) means of the
+// entries' values and the buckets of quantizations are merged
+fn others_entry(rest: &[AggregationEntry]) -> AggregationEntry {
+    let values = rest.iter().map(|entry| entry.value);
+    let value = match rest[0].function.as_str() {
+        "max" => values.max().unwrap_or(0),
+        "min" => values.min().unwrap_or(0),
+        "avg" | "stddev" => values.sum::<i64>() / rest.len() as i64,
+        _ => values.sum(),
+    };
+
+    let mut merged: BTreeMap<(i64, i64), i64> = BTreeMap::new();
+    for bucket in rest.iter().flat_map(|entry| entry.buckets.iter()) {
+        *merged.entry((bucket.lower, bucket.upper)).or_insert(0) += bucket.count;
+    }
+
+    AggregationEntry {
+        name: rest[0].name.clone(),
+        function: rest[0].function.clone(),
+        keys: Vec::new(),
+        value: value,
+        buckets: merged.into_iter()
+            .map(|((lower, upper), count)| Bucket { lower: lower, upper: upper, count: count })
+            .collect(),
+        others: rest.len() as u64,
+    }
+}
+
+// Decode a single entry of an aggregation: the first record of the
+// aggregation is its variable id, the last the aggregating action and the
+// records between them the keys
+pub unsafe fn aggregation_entry(agg: *const libdtrace::dtrace_aggdata_t)
+    -> Option<AggregationEntry> {
+
+    let desc = (* agg).dtada_desc;
+    let nrecs = (* desc).dtagd_nrecs as usize;
+    if nrecs < 2 {
+        return None;
+    }
+    let recs = slice::from_raw_parts((* desc).dtagd_rec.as_ptr(), nrecs);
+    let base = (* agg).dtada_data as *const u8;
+
+    // Keys that can't be decoded are kept as their raw bytes, so that every
+    // entry of the aggregation has as many keys
+    let keys = recs[1..nrecs - 1].iter()
+        .map(|rec| {
+            let addr = base.offset(rec.dtrd_offset as isize);
+            decode_value(addr, rec).unwrap_or_else(|| {
+                RecordValue::Bytes(slice::from_raw_parts(addr, rec.dtrd_size as usize).to_vec())
+            })
+        })
+        .collect();
+
+    let aggrec = &recs[nrecs - 1];
+    let addr = base.offset(aggrec.dtrd_offset as isize);
+    let data = (0..aggrec.dtrd_size as usize / 8)
+        .map(|i| ::std::ptr::read_unaligned((addr as *const i64).offset(i as isize)))
+        .collect::<Vec<i64>>();
+    if data.is_empty() {
+        return None;
+    }
+    let normal = if (* agg).dtada_normal == 0 { 1 } else { (* agg).dtada_normal as i64 };
+
+    let action = aggrec.dtrd_action;
+    let (value, buckets) = match action {
+        DTRACEAGG_AVG => {
+            (if data[0] == 0 { 0 } else { data[1] / data[0] / normal }, Vec::new())
+        },
+        DTRACEAGG_STDDEV => (stddev(&data, normal), Vec::new()),
+        DTRACEAGG_QUANTIZE => {
+            (data.iter().sum::<i64>() / normal, quantize_buckets(&data, normal))
+        },
+        DTRACEAGG_LQUANTIZE => {
+            (data[1..].iter().sum::<i64>() / normal, lquantize_buckets(&data, normal))
+        },
+        DTRACEAGG_LLQUANTIZE => {
+            (data[1..].iter().sum::<i64>() / normal, llquantize_buckets(&data, normal))
+        },
+        _ => (data[0] / normal, Vec::new()),
+    };
+
+    let name = if (* desc).dtagd_name.is_null() {
+        String::new()
+    } else {
+        c_str((* desc).dtagd_name)
+    };
+
+    Some(AggregationEntry {
+        name: name,
+        function: function_name(action).to_string(),
+        keys: keys,
+        value: value,
+        buckets: buckets,
+        others: 0,
+    })
+}
+
+// dtrace_aggregate_walk() callback collecting the entries of every
+// aggregation into the Vec<AggregationEntry> pointed to by arg
+pub unsafe extern fn walk_entry(agg: *const libdtrace::dtrace_aggdata_t,
+    arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
+
+    let entries = arg as *mut Vec<AggregationEntry>;
+    match aggregation_entry(agg) {
+        Some(entry) => (* entries).push(entry),
+        None => warn!("failed decoding aggregation entry"),
+    }
+    DTRACE_AGGWALK_NEXT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::record::{DTRACEACT_DIFEXPR, DTRACEACT_PRINTF};
+
+    fn bucket(lower: i64, upper: i64, count: i64) -> Bucket {
+        Bucket { lower: lower, upper: upper, count: count }
+    }
+
+    #[test]
+    fn quantizes_by_powers_of_two() {
+        let mut counts = vec![0; DTRACE_QUANTIZE_NBUCKETS];
+        counts[0] = 1;
+        counts[DTRACE_QUANTIZE_ZEROBUCKET - 1] = 3;
+        counts[DTRACE_QUANTIZE_ZEROBUCKET] = 2;
+        counts[DTRACE_QUANTIZE_ZEROBUCKET + 3] = 4;
+        counts[DTRACE_QUANTIZE_NBUCKETS - 1] = 6;
+
+        assert_eq!(quantize_buckets(&counts, 2), vec![
+            bucket(i64::min_value(), -(1 << 61) - 1, 0),
+            bucket(-1, -1, 1),
+            bucket(0, 0, 1),
+            bucket(4, 7, 2),
+            bucket(1 << 62, i64::max_value(), 3),
+        ]);
+    }
+
+    #[test]
+    fn quantizes_linearly() {
+        // lquantize(x, -10, 10, 5): underflow, 4 levels and overflow
+        let arg = (5u64 << 48) | (4u64 << 32) | (-10i32 as u32 as u64);
+        let data = vec![arg as i64, 1, 2, 0, 0, 3, 4];
+
+        assert_eq!(lquantize_buckets(&data, 1), vec![
+            bucket(i64::min_value(), -11, 1),
+            bucket(-10, -6, 2),
+            bucket(5, 9, 3),
+            bucket(10, i64::max_value(), 4),
+        ]);
+    }
+
+    #[test]
+    fn quantizes_log_linearly() {
+        // llquantize(x, 10, 0, 2, 10): underflow, 1..9, 10..90, 100..900
+        // and overflow
+        let arg = (10u64 << 48) | (0u64 << 32) | (2u64 << 16) | 10;
+        let mut data = vec![0i64; 30];
+        data[0] = arg as i64;
+        data[1] = 1;
+        data[2] = 2;
+        data[12] = 3;
+        data[28] = 5;
+        data[29] = 4;
+
+        assert_eq!(llquantize_buckets(&data, 1), vec![
+            bucket(i64::min_value(), 0, 1),
+            bucket(1, 1, 2),
+            bucket(20, 29, 3),
+            bucket(900, 999, 5),
+            bucket(1000, i64::max_value(), 4),
+        ]);
+    }
+
+    #[test]
+    fn computes_standard_deviation() {
+        // 2, 4, 4, 4, 5, 5, 7, 9
+        assert_eq!(stddev(&[8, 40, 232, 0], 1), 2);
+        assert_eq!(stddev(&[8, 40, 232, 0], 2), 1);
+        // 0 and 2^33, whose sum of squares (2^66) overflows 64 bits
+        assert_eq!(stddev(&[2, 1 << 33, 0, 4], 1), 1 << 32);
+        assert_eq!(stddev(&[0, 0, 0, 0], 1), 0);
+    }
+
+    fn entry(function: &str, key: &str, value: i64, buckets: Vec<Bucket>) -> AggregationEntry {
+        AggregationEntry {
+            name: "syscalls".to_string(),
+            function: function.to_string(),
+            keys: vec![RecordValue::String(key.to_string())],
+            value: value,
+            buckets: buckets,
+            others: 0,
+        }
+    }
+
+    #[test]
+    fn merges_others_by_function() {
+        let counts = vec![entry("count", "read", 5, vec![]), entry("count", "write", 3, vec![])];
+        let others = others_entry(&counts);
+        assert_eq!((others.value, others.others, others.keys.len()), (8, 2, 0));
+
+        let maxima = vec![entry("max", "read", 5, vec![]), entry("max", "write", 9, vec![])];
+        assert_eq!(others_entry(&maxima).value, 9);
+        let minima = vec![entry("min", "read", 5, vec![]), entry("min", "write", 9, vec![])];
+        assert_eq!(others_entry(&minima).value, 5);
+        let averages = vec![entry("avg", "read", 4, vec![]), entry("avg", "write", 8, vec![])];
+        assert_eq!(others_entry(&averages).value, 6);
+
+        let quantized = vec![
+            entry("quantize", "read", 3, vec![bucket(1, 1, 1), bucket(2, 3, 2)]),
+            entry("quantize", "write", 4, vec![bucket(2, 3, 3), bucket(4, 7, 1)]),
+        ];
+        assert_eq!(others_entry(&quantized).buckets,
+            vec![bucket(1, 1, 1), bucket(2, 3, 5), bucket(4, 7, 1)]);
+    }
+
+    #[test]
+    fn selects_top_n_in_sort_order() {
+        let mut config = HashMap::new();
+        config.insert("@syscalls".to_string(), AggregationConfig { top_n: Some(2), sort: None });
+        let reporter = AggregationReporter::new(10, AggregationMode::Cumulative, 0,
+            Some(&config)).unwrap();
+        assert_eq!(reporter.sort_orders(), vec![SortOrder::Value]);
+
+        let unsorted = vec![entry("count", "close", 1, vec![]), entry("count", "read", 9, vec![]),
+            entry("count", "open", 2, vec![]), entry("count", "write", 7, vec![])];
+        let mut sorted = unsorted.clone();
+        sorted.sort_by(|a, b| b.value.cmp(&a.value));
+        let selected = reporter.select(unsorted, vec![(SortOrder::Value, sorted)]);
+
+        let values: Vec<(i64, u64)> = selected.iter()
+            .map(|entry| (entry.value, entry.others)).collect();
+        assert_eq!(values, vec![(9, 0), (7, 0), (3, 2)]);
+    }
+
+    #[test]
+    fn keeps_undecodable_keys() {
+        // The aggregation's description with its records following on
+        #[repr(C)]
+        struct Desc {
+            desc: libdtrace::dtrace_aggdesc_t,
+            recs: [libdtrace::dtrace_recdesc_t; 3],
+        }
+        let rec = |action, offset, size| libdtrace::dtrace_recdesc_t {
+            dtrd_action: action,
+            dtrd_offset: offset,
+            dtrd_size: size,
+            ..Default::default()
+        };
+        let mut desc = Desc {
+            desc: libdtrace::dtrace_aggdesc_t {
+                dtagd_nrecs: 4,
+                ..Default::default()
+            },
+            recs: [rec(DTRACEACT_PRINTF, 8, 4), rec(DTRACEACT_DIFEXPR, 16, 8),
+                rec(DTRACEAGG_COUNT, 24, 8)],
+        };
+        desc.desc.dtagd_rec[0] = rec(0, 0, 8);
+
+        let mut data = vec![0u8; 32];
+        data[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        data[16..24].copy_from_slice(&812i64.to_le_bytes());
+        data[24..32].copy_from_slice(&5i64.to_le_bytes());
+        let agg = libdtrace::dtrace_aggdata_t {
+            dtada_desc: &mut desc.desc,
+            dtada_data: data.as_mut_ptr() as libdtrace::caddr_t,
+            ..Default::default()
+        };
+
+        let entry = unsafe { aggregation_entry(&agg) }.unwrap();
+        assert_eq!(entry.keys, vec![RecordValue::Bytes(vec![1, 2, 3, 4]), RecordValue::Int(812)]);
+        assert_eq!((entry.function.as_str(), entry.value), ("count", 5));
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        assert!(AggregationReporter::new(0, AggregationMode::Cumulative, 0, None).is_err());
+
+        let mut config = HashMap::new();
+        config.insert("syscalls".to_string(),
+            AggregationConfig { top_n: None, sort: Some("random".to_string()) });
+        assert!(AggregationReporter::new(10, AggregationMode::Cumulative, 0,
+            Some(&config)).is_err());
+    }
+}
@@ -0,0 +1,88 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+// Built-in values substituted for ${hostname}, ${hostuuid} and ${script_id}
+// in the script's macro arguments and defines
+pub struct Substitutions<'a> {
+    pub hostname: &'a str,
+    pub hostuuid: Option<&'a str>,
+    pub script_id: &'a str,
+}
+
+impl<'a> Substitutions<'a> {
+    fn value(&self, name: &str) -> Result<&'a str, String> {
+        match name {
+            "hostname" => Ok(self.hostname),
+            "hostuuid" => self.hostuuid.ok_or("kern.hostuuid is unavailable".to_string()),
+            "script_id" => Ok(self.script_id),
+            _ => Err(format!("unknown substitution ${{{}}}", name)),
+        }
+    }
+
+    // Expand the substitutions in a macro argument or define
+    pub fn expand(&self, value: &str) -> Result<String, String> {
+        let mut expanded = String::new();
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => return Err(format!("unterminated substitution in {}", value)),
+            };
+            expanded.push_str(&rest[..start]);
+            expanded.push_str(self.value(&rest[start + 2..end])?);
+            rest = &rest[end + 1..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_substitutions() {
+        let substitutions = Substitutions {
+            hostname: "db1.example.com",
+            hostuuid: None,
+            script_id: "syscalls",
+        };
+
+        assert_eq!(substitutions.expand("\"${hostname}\"").unwrap(), "\"db1.example.com\"");
+        assert_eq!(substitutions.expand("${script_id}-42").unwrap(), "syscalls-42");
+        assert_eq!(substitutions.expand("10").unwrap(), "10");
+        assert!(substitutions.expand("${hostuuid}").is_err());
+        assert!(substitutions.expand("${nodename}").is_err());
+        assert!(substitutions.expand("${hostname").is_err());
+    }
+}
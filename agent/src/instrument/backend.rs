@@ -0,0 +1,127 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use super::ConsumerContext;
+use super::aggregate::SortOrder;
+use super::ddtrace_record::{AggregationEntry, Frame};
+use super::catalog::ProbeDescription;
+use super::dof::Dof;
+use super::error::DTraceError;
+use super::handle::DTraceHandle;
+use super::program::ProgramInfo;
+
+// libdtrace interface version
+const DTRACE_VERSION: i32 = 3;
+
+// Opens the tracing sessions running instrumentation scripts
+pub trait Backend {
+    fn open(&self) -> Result<Box<Session>, DTraceError>;
+}
+
+// A tracing session (a DTrace consumer), which delivers the traced data to
+// the consumer context it's registered with
+pub trait Session {
+    fn setopt(&mut self, opt: &str, val: &str) -> Result<(), DTraceError>;
+    // The option's current value (None if there's no such option)
+    fn getopt(&self, opt: &str) -> Option<i64>;
+    // Compile the D program, passing args as the macro arguments $0..$n
+    fn compile(&mut self, script: &str, args: &[String]) -> Result<(), DTraceError>;
+    // Compile the D program in the file, as with compile
+    fn compile_file(&mut self, path: &str, args: &[String]) -> Result<(), DTraceError>;
+    // The compiled programs, as DOF
+    fn dof(&self) -> Result<Dof, DTraceError>;
+    // Load programs compiled earlier, whose probes are enabled by exec
+    // (instead of compiling them)
+    fn load_dof(&mut self, dof: &Dof);
+    // The DTrace version and the kernel build the programs are compiled for
+    fn dtrace_version(&self) -> String;
+    fn kernel_build(&self) -> String;
+    // The probes the kernel provides
+    fn probes(&self) -> Vec<ProbeDescription>;
+    // The kernel modules DTrace knows of, and those providing probes
+    fn modules(&self) -> Vec<String>;
+    fn provider_modules(&self) -> Vec<String>;
+    // Refresh DTrace's view of the kernel modules (dtrace_update)
+    fn update(&mut self);
+    // Describe the compiled programs
+    fn program_info(&self) -> ProgramInfo;
+
+    // Grab a running process, or create one from the command, stopped until
+    // continued once tracing has started
+    fn proc_grab(&mut self, pid: i32) -> Result<(), DTraceError>;
+    fn proc_create(&mut self, command: &[String]) -> Result<i32, DTraceError>;
+    fn proc_continue(&mut self);
+    // Release the grabbed processes, killing the created ones
+    fn proc_release(&mut self);
+    // Enable the compiled program's probes
+    fn exec(&mut self) -> Result<(), DTraceError>;
+    // Register the consumer handlers, the context must remain valid until
+    // the session is dropped
+    unsafe fn register(&mut self, context: *mut ConsumerContext) -> Result<(), DTraceError>;
+    // Start tracing
+    fn go(&mut self) -> Result<(), DTraceError>;
+    // Wait until the consumer has work to do
+    fn sleep(&self);
+    // Consume the traced data, passing it to the registered context
+    unsafe fn work(&mut self, context: *mut ConsumerContext) -> Result<WorkStatus, DTraceError>;
+    // Stop tracing (dropping the session stops it if this hasn't been called)
+    fn stop(&mut self) -> Result<(), DTraceError>;
+    fn aggregate_snap(&mut self) -> Result<(), DTraceError>;
+    fn aggregate_clear(&mut self);
+    // Print the aggregations using the session's default formatting
+    // (passing the output to the context)
+    fn aggregate_print(&mut self) -> Result<(), DTraceError>;
+    // Collect the entries of every aggregation, in the given sort order
+    // (or unsorted)
+    fn aggregate_entries(&self, order: Option<SortOrder>)
+        -> Result<Vec<AggregationEntry>, DTraceError>;
+    // Resolve a kernel or (a process's) user address to a symbol
+    fn lookup_kernel(&self, addr: u64) -> Frame;
+    fn lookup_user(&self, pid: u64, addr: u64) -> Frame;
+}
+
+// Outcome of a successful call to Session::work()
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkStatus {
+    Okay,
+    // The D program has exited
+    Done,
+}
+
+// Traces with libdtrace
+pub struct DTraceBackend;
+
+impl Backend for DTraceBackend {
+    fn open(&self) -> Result<Box<Session>, DTraceError> {
+        Ok(Box::new(DTraceHandle::open(DTRACE_VERSION, 0)?))
+    }
+}
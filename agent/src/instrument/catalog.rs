@@ -0,0 +1,229 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::mpsc;
+use std::time::Duration;
+use rustc_serialize::json::{Json, ToJson};
+use super::InstrumentationThreadMessage;
+use super::backend::{Backend, Session};
+use super::error::DTraceError;
+
+// A probe, as listed by dtrace -l
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProbeDescription {
+    pub provider: String,
+    pub module: String,
+    pub function: String,
+    pub name: String,
+}
+
+impl fmt::Display for ProbeDescription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}:{}", self.provider, self.module, self.function, self.name)
+    }
+}
+
+// Pattern matching probes by provider, module, function and name, each of
+// which may use the * and ? wildcards (an empty field matches anything)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProbePattern {
+    pub provider: String,
+    pub module: String,
+    pub function: String,
+    pub name: String,
+}
+
+impl ProbePattern {
+    // Parse a probe description such as syscall::read*:entry, in which
+    // (as with dtrace -n) omitted leading fields match anything
+    pub fn parse(spec: &str) -> ProbePattern {
+        let mut fields: Vec<String> = spec.rsplitn(4, ':').map(|s| s.to_string()).collect();
+        fields.resize(4, String::new());
+        ProbePattern {
+            name: fields[0].clone(),
+            function: fields[1].clone(),
+            module: fields[2].clone(),
+            provider: fields[3].clone(),
+        }
+    }
+
+    pub fn matches(&self, probe: &ProbeDescription) -> bool {
+        glob(&self.provider, &probe.provider) && glob(&self.module, &probe.module) &&
+            glob(&self.function, &probe.function) && glob(&self.name, &probe.name)
+    }
+}
+
+fn glob(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some((&'*', rest)) => (0..text.len() + 1).any(|skip| matches(rest, &text[skip..])),
+            Some((&'?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+            Some((c, rest)) => text.first() == Some(c) && matches(rest, &text[1..]),
+        }
+    }
+
+    pattern.is_empty() ||
+        matches(&pattern.chars().collect::<Vec<_>>(), &text.chars().collect::<Vec<_>>())
+}
+
+// The probes available on the endpoint, with the kernel modules DTrace
+// knows of and those providing probes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProbeCatalog {
+    pub probes: Vec<ProbeDescription>,
+    pub modules: Vec<String>,
+    pub provider_modules: Vec<String>,
+}
+
+impl ProbeCatalog {
+    pub fn new(session: &Session) -> ProbeCatalog {
+        let mut probes = session.probes();
+        probes.sort();
+        ProbeCatalog {
+            probes: probes,
+            modules: session.modules(),
+            provider_modules: session.provider_modules(),
+        }
+    }
+
+    pub fn query(&self, pattern: &ProbePattern) -> Vec<&ProbeDescription> {
+        self.probes.iter().filter(|probe| pattern.matches(probe)).collect()
+    }
+
+    // The catalog as JSON, grouping the probes' names by provider, module
+    // and function (which is far more compact than listing each probe)
+    pub fn to_json(&self) -> String {
+        let mut providers: BTreeMap<String, BTreeMap<String, BTreeMap<String, Vec<String>>>> =
+            BTreeMap::new();
+        for probe in &self.probes {
+            providers.entry(probe.provider.clone()).or_insert_with(BTreeMap::new)
+                .entry(probe.module.clone()).or_insert_with(BTreeMap::new)
+                .entry(probe.function.clone()).or_insert_with(Vec::new)
+                .push(probe.name.clone());
+        }
+
+        let mut catalog = BTreeMap::new();
+        catalog.insert("modules".to_string(), self.modules.to_json());
+        catalog.insert("provider_modules".to_string(), self.provider_modules.to_json());
+        catalog.insert("providers".to_string(), providers.to_json());
+        Json::Object(catalog).to_string()
+    }
+}
+
+// Publish the endpoint's probe catalog, then again whenever dtrace_update
+// reveals kernel modules loaded or unloaded since, until stopped
+pub fn watch(backend: &Backend, interval: Duration, publish: &Fn(&ProbeCatalog),
+    rx: &mpsc::Receiver<InstrumentationThreadMessage>) -> Result<(), DTraceError> {
+
+    let mut session = backend.open()?;
+    let mut catalog = ProbeCatalog::new(&*session);
+    publish(&catalog);
+
+    loop {
+        match rx.recv_timeout(interval) {
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            _ => return Ok(()),
+        }
+
+        session.update();
+        if session.modules() != catalog.modules ||
+            session.provider_modules() != catalog.provider_modules {
+            catalog = ProbeCatalog::new(&*session);
+            info!("kernel modules changed, {} probes", catalog.probes.len());
+            publish(&catalog);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::super::mock::MockBackend;
+
+    fn probe(provider: &str, module: &str, function: &str, name: &str) -> ProbeDescription {
+        ProbeDescription {
+            provider: provider.to_string(),
+            module: module.to_string(),
+            function: function.to_string(),
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn queries_probe_catalog_by_pattern() {
+        let catalog = ProbeCatalog {
+            probes: vec![probe("fbt", "kernel", "vm_fault", "entry"),
+                probe("syscall", "freebsd", "read", "entry"),
+                probe("syscall", "freebsd", "read", "return"),
+                probe("syscall", "freebsd", "readv", "entry")],
+            ..Default::default()
+        };
+        let query = |spec: &str| catalog.query(&ProbePattern::parse(spec)).iter()
+            .map(|probe| probe.to_string()).collect::<Vec<String>>();
+
+        assert_eq!(query("syscall::read*:entry"),
+            vec!["syscall:freebsd:read:entry", "syscall:freebsd:readv:entry"]);
+        assert_eq!(query("read:ret???"), vec!["syscall:freebsd:read:return"]);
+        assert_eq!(query("fbt:kernel::"), vec!["fbt:kernel:vm_fault:entry"]);
+        assert_eq!(query("").len(), 4);
+        assert!(catalog.to_json().contains(
+            "\"syscall\":{\"freebsd\":{\"read\":[\"entry\",\"return\"]"));
+    }
+
+    #[test]
+    fn refreshes_probe_catalog_when_modules_change() {
+        let backend = MockBackend {
+            probes: vec![probe("fbt", "kernel", "vm_fault", "entry"),
+                probe("fbt", "zfs", "zio_wait", "entry")],
+            modules: vec!["kernel".to_string()],
+            updates: Rc::new(RefCell::new(vec![vec!["kernel".to_string()],
+                vec!["kernel".to_string(), "zfs".to_string()]].into_iter().collect())),
+            ..Default::default()
+        };
+        let catalogs = RefCell::new(Vec::new());
+        let (tx, rx) = mpsc::channel();
+        let publish = |catalog: &ProbeCatalog| {
+            catalogs.borrow_mut().push(catalog.probes.len());
+            if catalogs.borrow().len() == 2 {
+                tx.send(InstrumentationThreadMessage::Stop).unwrap();
+            }
+        };
+
+        watch(&backend, Duration::from_millis(1), &publish, &rx).unwrap();
+        assert_eq!(*catalogs.borrow(), vec![1, 2]);
+    }
+}
@@ -0,0 +1,298 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use super::program::{Attributes, ProgramInfo};
+
+// Compiled programs, as DOF, with the values of the options set by their
+// #pragmas (by option name) and what dtrace_program_info reported of them
+// (libdtrace has no dtrace_prog_t for programs loaded from DOF, though it
+// looks up their probes', aggregations' and formats' descriptions in the
+// kernel as it would those of compiled programs)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dof {
+    pub options: Vec<(String, u64)>,
+    pub info: ProgramInfo,
+    pub programs: Vec<Vec<u8>>,
+}
+
+// Identifies the compiled programs of a script: the hash of the script's
+// sources, arguments and options, with the DTrace version and the kernel
+// build they were compiled for
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheKey {
+    pub hash: u64,
+    pub dtrace: String,
+    pub kernel: String,
+}
+
+// 64 bit FNV-1a, which (unlike the standard library's hashers) is stable
+// across builds of the agent
+pub struct Fnv(u64);
+
+impl Fnv {
+    pub fn new() -> Fnv {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    // Add a length-delimited item to the hash
+    pub fn add(&mut self, data: &[u8]) {
+        for &byte in (data.len() as u64).to_string().as_bytes().iter().chain(b":").chain(data) {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.0
+    }
+}
+
+// Directory of compiled programs, one file per key's hash holding the
+// DTrace version and kernel build, the options, the program info and the
+// length of each program followed by its DOF
+pub struct DofCache {
+    dir: PathBuf,
+}
+
+impl DofCache {
+    pub fn new(dir: &str) -> DofCache {
+        if let Err(e) = fs::create_dir_all(dir) {
+            warn!("failed creating DOF cache {}: {}", dir, e);
+        }
+        DofCache { dir: PathBuf::from(dir) }
+    }
+
+    fn path(&self, hash: u64) -> PathBuf {
+        self.dir.join(format!("{:016x}.dof", hash))
+    }
+
+    // The compiled programs for the key, discarding the cache's programs
+    // compiled for another DTrace version or kernel
+    pub fn load(&self, key: &CacheKey) -> Option<Dof> {
+        match read_file(&self.path(key.hash)) {
+            Some((ref dtrace, ref kernel, ref dof)) if *dtrace == key.dtrace &&
+                *kernel == key.kernel => Some(dof.clone()),
+            _ => {
+                self.purge(key);
+                None
+            },
+        }
+    }
+
+    pub fn store(&self, key: &CacheKey, dof: &Dof) {
+        // Write a temporary file (unique to the writer) renamed into place,
+        // so that a partially written entry is never loaded
+        let path = self.path(key.hash);
+        let thread: String = format!("{:?}", thread::current().id()).chars()
+            .filter(|c| c.is_ascii_digit()).collect();
+        let tmp = self.dir.join(format!("{:016x}.{}-{}.tmp", key.hash, process::id(), thread));
+        let result = fs::File::create(&tmp)
+            .and_then(|mut file| write_entry(&mut file, key, dof))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = result {
+            warn!("failed caching DOF in {:?}: {}", path, e);
+            let _ = fs::remove_file(&tmp);
+        }
+    }
+
+    // Remove the entries compiled for another DTrace version or kernel
+    fn purge(&self, key: &CacheKey) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            if path.extension().map_or(true, |extension| extension != "dof") {
+                continue;
+            }
+            let current = read_file(&path)
+                .map_or(false, |(dtrace, kernel, _)| dtrace == key.dtrace && kernel == key.kernel);
+            if !current {
+                info!("discarding stale DOF {:?}", path);
+                let _ = fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+fn write_entry(file: &mut Write, key: &CacheKey, dof: &Dof) -> ::std::io::Result<()> {
+    writeln!(file, "{}", key.dtrace)?;
+    writeln!(file, "{}", key.kernel)?;
+    let options: Vec<String> = dof.options.iter()
+        .map(|&(ref option, value)| format!("{}={}", option, value)).collect();
+    writeln!(file, "{}", options.join(" "))?;
+    // The program info, tab separated (stability names have no tabs)
+    let info = &dof.info;
+    let attributes = [&info.description, &info.statement].iter()
+        .map(|attr| format!("{}\t{}\t{}", attr.name, attr.data, attr.class))
+        .collect::<Vec<String>>();
+    writeln!(file, "{}\t{}\t{}\t{}\t{}", info.matches, info.aggregates, info.recgens,
+        info.speculations, attributes.join("\t"))?;
+    for program in &dof.programs {
+        writeln!(file, "{}", program.len())?;
+        file.write_all(program)?;
+    }
+    Ok(())
+}
+
+fn read_line(reader: &mut BufRead) -> Option<String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(n) if n > 0 && line.ends_with('\n') => {
+            line.pop();
+            Some(line)
+        },
+        _ => None,
+    }
+}
+
+fn read_file(path: &Path) -> Option<(String, String, Dof)> {
+    let file = fs::File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    read_entry(&mut BufReader::new(file), size)
+}
+
+fn read_info(line: &str) -> Option<ProgramInfo> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 10 {
+        return None;
+    }
+    let attributes = |fields: &[&str]| Attributes {
+        name: fields[0].to_string(),
+        data: fields[1].to_string(),
+        class: fields[2].to_string(),
+    };
+    Some(ProgramInfo {
+        matches: fields[0].parse().ok()?,
+        aggregates: fields[1].parse().ok()?,
+        recgens: fields[2].parse().ok()?,
+        speculations: fields[3].parse().ok()?,
+        description: attributes(&fields[4..7]),
+        statement: attributes(&fields[7..10]),
+    })
+}
+
+// Read an entry of the cache from a file of the given size (which no
+// program can be longer than)
+fn read_entry(reader: &mut BufRead, size: u64) -> Option<(String, String, Dof)> {
+    let dtrace = read_line(reader)?;
+    let kernel = read_line(reader)?;
+    let mut dof = Dof::default();
+    for option in read_line(reader)?.split_whitespace() {
+        let mut parts = option.splitn(2, '=');
+        match (parts.next().map(|o| o.to_string()),
+            parts.next().and_then(|v| v.parse().ok())) {
+            (Some(option), Some(value)) => dof.options.push((option, value)),
+            _ => return None,
+        }
+    }
+    dof.info = read_info(&read_line(reader)?)?;
+    while let Some(len) = read_line(reader) {
+        let len: u64 = len.parse().ok()?;
+        if len > size {
+            return None;
+        }
+        let mut program = vec![0; len as usize];
+        reader.read_exact(&mut program).ok()?;
+        dof.programs.push(program);
+    }
+    Some((dtrace, kernel, dof))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(kernel: &str) -> CacheKey {
+        CacheKey {
+            hash: 0x2a,
+            dtrace: "1.13".to_string(),
+            kernel: kernel.to_string(),
+        }
+    }
+
+    #[test]
+    fn hashes_items_with_their_lengths() {
+        let hash = |items: &[&str]| {
+            let mut hash = Fnv::new();
+            for item in items {
+                hash.add(item.as_bytes());
+            }
+            hash.hash()
+        };
+
+        assert_eq!(hash(&["ab", "c"]), hash(&["ab", "c"]));
+        assert!(hash(&["ab", "c"]) != hash(&["a", "bc"]));
+    }
+
+    #[test]
+    fn discards_programs_compiled_for_another_kernel() {
+        let dir = ::std::env::temp_dir().join(format!("ddtrace-dof-{}", ::std::process::id()));
+        let cache = DofCache::new(&dir.to_string_lossy());
+        let dof = Dof {
+            options: vec![("quiet".to_string(), 1), ("strsize".to_string(), 4096)],
+            info: ProgramInfo {
+                matches: 2,
+                aggregates: 1,
+                description: Attributes {
+                    name: "Evolving".to_string(),
+                    data: "Evolving".to_string(),
+                    class: "Common".to_string(),
+                },
+                ..Default::default()
+            },
+            programs: vec![b"\x7fdof\n1".to_vec(), Vec::new()],
+        };
+
+        assert_eq!(cache.load(&key("11.0-RELEASE-p1")), None);
+        cache.store(&key("11.0-RELEASE-p1"), &dof);
+        assert_eq!(cache.load(&key("11.0-RELEASE-p1")), Some(dof));
+        assert_eq!(cache.load(&key("11.0-RELEASE-p2")), None);
+        assert_eq!(cache.load(&key("11.0-RELEASE-p1")), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_programs_longer_than_the_entry() {
+        let entry = b"1.13\n11.0-RELEASE-p1\n\n0\t0\t0\t0\t\t\t\t\t\t\n18446744073709551615\n";
+        assert_eq!(read_entry(&mut &entry[..], entry.len() as u64), None);
+        let entry = b"1.13\n11.0-RELEASE-p1\n\n0\t0\t0\t0\t\t\t\t\t\t\n2\nab";
+        assert_eq!(read_entry(&mut &entry[..], entry.len() as u64).map(|(_, _, dof)| dof.programs),
+            Some(vec![b"ab".to_vec()]));
+    }
+}
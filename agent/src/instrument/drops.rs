@@ -0,0 +1,323 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::collections::BTreeMap;
+use rustc_serialize::{Decodable, Decoder};
+use super::ddtrace_record::DropRecord;
+use super::libdtrace;
+use super::libdtrace::dtrace_dropkind_t;
+use super::record::c_str;
+
+// Buffer sizes used until the script's drops call for larger ones
+const DEFAULT_BUFSIZE: u64 = 4 << 20;
+const DEFAULT_AGGSIZE: u64 = 4 << 20;
+const DEFAULT_DYNVARSIZE: u64 = 1 << 20;
+
+// Default limit on the automatically raised buffer sizes
+const DEFAULT_MAX_SIZE: u64 = 64 << 20;
+
+// Default number of consecutive stats intervals with drops after which the
+// buffers are raised
+const DEFAULT_PERSIST_INTERVALS: u32 = 3;
+
+#[derive(Debug)]
+pub struct DropConfig {
+    auto_resize: Option<bool>,
+    max_size: Option<String>,
+    intervals: Option<u32>,
+}
+
+impl Decodable for DropConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<DropConfig, D::Error> {
+        d.read_struct("DropConfig", 3, |d| Ok(DropConfig {
+            auto_resize: d.read_struct_field("auto_resize", 0, Decodable::decode)?,
+            max_size: d.read_struct_field("max_size", 1, Decodable::decode)?,
+            intervals: d.read_struct_field("intervals", 2, Decodable::decode)?,
+        }))
+    }
+}
+
+// Name of a kind of drop (as used in drop events and the stats counters)
+pub fn kind_name(kind: dtrace_dropkind_t) -> &'static str {
+    match kind {
+        dtrace_dropkind_t::DTRACEDROP_PRINCIPAL => "principal",
+        dtrace_dropkind_t::DTRACEDROP_AGGREGATION => "aggregation",
+        dtrace_dropkind_t::DTRACEDROP_DYNAMIC => "dynamic",
+        dtrace_dropkind_t::DTRACEDROP_DYNRINSE => "dynrinse",
+        dtrace_dropkind_t::DTRACEDROP_DYNDIRTY => "dyndirty",
+        dtrace_dropkind_t::DTRACEDROP_SPEC => "speculation",
+        dtrace_dropkind_t::DTRACEDROP_SPECBUSY => "speculation_busy",
+        dtrace_dropkind_t::DTRACEDROP_SPECUNAVAIL => "speculation_unavailable",
+        dtrace_dropkind_t::DTRACEDROP_STKSTROVERFLOW => "stack_string_overflow",
+        dtrace_dropkind_t::DTRACEDROP_DBLERROR => "double_error",
+    }
+}
+
+// Describe the drops reported to the drop handler
+pub unsafe fn drop_record(data: *const libdtrace::dtrace_dropdata_t) -> DropRecord {
+    DropRecord {
+        kind: kind_name((* data).dtdda_kind).to_string(),
+        cpu: (* data).dtdda_cpu,
+        count: (* data).dtdda_drops,
+        total: (* data).dtdda_total,
+        message: if (* data).dtdda_msg.is_null() {
+            String::new()
+        } else {
+            c_str((* data).dtdda_msg).trim().to_string()
+        },
+    }
+}
+
+// Parse a DTrace size option value such as 4m or 512k
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_lowercase();
+    let (digits, shift) = match size.chars().last() {
+        Some('k') => (&size[..size.len() - 1], 10),
+        Some('m') => (&size[..size.len() - 1], 20),
+        Some('g') => (&size[..size.len() - 1], 30),
+        Some('t') => (&size[..size.len() - 1], 40),
+        _ => (&size[..], 0),
+    };
+    digits.parse::<u64>().ok().map(|n| n << shift)
+}
+
+// Sizes of the buffers whose exhaustion causes drops
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferSizes {
+    pub bufsize: u64,
+    pub aggsize: u64,
+    pub dynvarsize: u64,
+}
+
+impl Default for BufferSizes {
+    fn default() -> BufferSizes {
+        BufferSizes {
+            bufsize: DEFAULT_BUFSIZE,
+            aggsize: DEFAULT_AGGSIZE,
+            dynvarsize: DEFAULT_DYNVARSIZE,
+        }
+    }
+}
+
+impl BufferSizes {
+    // The DTrace options setting the buffer sizes
+    pub fn options(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("bufsize", self.bufsize.to_string()),
+            ("aggsize", self.aggsize.to_string()),
+            ("dynvarsize", self.dynvarsize.to_string()),
+        ]
+    }
+
+    // The size set by the given option (None if it isn't a buffer size)
+    pub fn option_mut(&mut self, option: &str) -> Option<&mut u64> {
+        match option {
+            "bufsize" => Some(&mut self.bufsize),
+            "aggsize" => Some(&mut self.aggsize),
+            "dynvarsize" => Some(&mut self.dynvarsize),
+            _ => None,
+        }
+    }
+
+    // The size governing drops of the given kind (None for drops that
+    // can't be avoided by a larger buffer)
+    fn size_mut(&mut self, kind: &str) -> Option<&mut u64> {
+        match kind {
+            "principal" => Some(&mut self.bufsize),
+            "aggregation" => Some(&mut self.aggsize),
+            "dynamic" | "dynrinse" | "dyndirty" => Some(&mut self.dynvarsize),
+            _ => None,
+        }
+    }
+}
+
+// Watches the drop counters at each stats interval and, if configured to,
+// doubles the size of the buffers whose drops persist for several
+// consecutive intervals (up to a maximum size). Buffer sizes can't be
+// changed once tracing has started, so larger buffers take effect when the
+// script's tracing is restarted.
+pub struct DropMonitor {
+    auto_resize: bool,
+    max_size: u64,
+    intervals: u32,
+    previous: BTreeMap<String, u64>,
+    persisting: BTreeMap<String, u32>,
+}
+
+impl DropMonitor {
+    pub fn new(config: Option<&DropConfig>) -> Result<DropMonitor, String> {
+        let max_size = match config.and_then(|c| c.max_size.as_ref()) {
+            Some(size) => match parse_size(size) {
+                Some(size) => size,
+                None => return Err(format!("invalid max_size {}", size)),
+            },
+            None => DEFAULT_MAX_SIZE,
+        };
+
+        Ok(DropMonitor {
+            auto_resize: config.and_then(|c| c.auto_resize).unwrap_or(false),
+            max_size: max_size,
+            intervals: config.and_then(|c| c.intervals).unwrap_or(DEFAULT_PERSIST_INTERVALS),
+            previous: BTreeMap::new(),
+            persisting: BTreeMap::new(),
+        })
+    }
+
+    // Called at the end of each stats interval with the cumulative drop
+    // counters, returns the raised buffer sizes if drops have persisted
+    pub fn interval(&mut self, drops: &BTreeMap<String, u64>, sizes: &BufferSizes)
+        -> Option<BufferSizes> {
+
+        let mut raised = *sizes;
+        let mut resize = false;
+        for (kind, count) in drops {
+            let dropped = self.previous.get(kind).map_or(true, |previous| count > previous);
+            let intervals = self.persisting.entry(kind.clone()).or_insert(0);
+            *intervals = if dropped { *intervals + 1 } else { 0 };
+            if !self.auto_resize || *intervals < self.intervals {
+                continue;
+            }
+
+            if let Some(size) = raised.size_mut(kind) {
+                if *size < self.max_size {
+                    *size = (*size * 2).min(self.max_size);
+                    resize = true;
+                    *intervals = 0;
+                } else if *intervals == self.intervals {
+                    warn!("{} drops persist at the maximum buffer size {}", kind, size);
+                }
+            }
+        }
+        self.previous = drops.clone();
+
+        if resize { Some(raised) } else { None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn describes_drops() {
+        let msg = CString::new("512 dynamic variable drops\n").unwrap();
+        let data = libdtrace::dtrace_dropdata_t {
+            dtdda_cpu: 3,
+            dtdda_kind: dtrace_dropkind_t::DTRACEDROP_DYNAMIC,
+            dtdda_drops: 512,
+            dtdda_total: 1024,
+            dtdda_msg: msg.as_ptr(),
+            ..Default::default()
+        };
+
+        assert_eq!(unsafe { drop_record(&data) }, DropRecord {
+            kind: "dynamic".to_string(),
+            cpu: 3,
+            count: 512,
+            total: 1024,
+            message: "512 dynamic variable drops".to_string(),
+        });
+
+        let data = libdtrace::dtrace_dropdata_t {
+            dtdda_kind: dtrace_dropkind_t::DTRACEDROP_SPECBUSY,
+            ..Default::default()
+        };
+        let record = unsafe { drop_record(&data) };
+        assert_eq!(record.kind, "speculation_busy");
+        assert!(record.message.is_empty());
+    }
+
+    #[test]
+    fn parses_buffer_sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4k"), Some(4 << 10));
+        assert_eq!(parse_size(" 16M "), Some(16 << 20));
+        assert_eq!(parse_size("1g"), Some(1 << 30));
+        assert_eq!(parse_size("m"), None);
+        assert_eq!(parse_size("-4m"), None);
+    }
+
+    #[test]
+    fn raises_buffers_with_persistent_drops() {
+        let config = DropConfig {
+            auto_resize: Some(true),
+            max_size: Some("12m".to_string()),
+            intervals: Some(2),
+        };
+        let mut monitor = DropMonitor::new(Some(&config)).unwrap();
+        let mut sizes = BufferSizes::default();
+
+        // Cumulative principal drops at the end of each interval, and the
+        // bufsize raised to (speculation drops are counted throughout, but
+        // can't be helped by a larger buffer)
+        let intervals: &[(u64, Option<u64>)] = &[
+            (10, None),
+            // Drops persisting for two intervals double the buffer
+            (20, Some(8 << 20)),
+            // An interval without drops resets the count
+            (20, None),
+            (30, None),
+            // The buffer is raised no further than max_size
+            (40, Some(12 << 20)),
+            (50, None),
+            (60, None),
+            (70, None),
+        ];
+        for (i, &(principal, expected)) in intervals.iter().enumerate() {
+            let mut drops = BTreeMap::new();
+            drops.insert("principal".to_string(), principal);
+            drops.insert("speculation".to_string(), i as u64 + 1);
+            let raised = monitor.interval(&drops, &sizes);
+            assert_eq!(raised.map(|raised| raised.bufsize), expected, "interval {}", i);
+            if let Some(raised) = raised {
+                assert_eq!((raised.aggsize, raised.dynvarsize),
+                    (DEFAULT_AGGSIZE, DEFAULT_DYNVARSIZE));
+                sizes = raised;
+            }
+        }
+    }
+
+    #[test]
+    fn only_raises_buffers_when_configured_to() {
+        let mut monitor = DropMonitor::new(None).unwrap();
+        for count in 1..10 {
+            let mut drops = BTreeMap::new();
+            drops.insert("dynamic".to_string(), count);
+            assert_eq!(monitor.interval(&drops, &BufferSizes::default()), None);
+        }
+
+        let config = DropConfig { auto_resize: None, max_size: Some("big".to_string()),
+            intervals: None };
+        assert!(DropMonitor::new(Some(&config)).is_err());
+    }
+}
@@ -0,0 +1,151 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::fmt;
+
+// A D compiler error, with the location of the offending source if the
+// compiler reported it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompileError {
+    pub message: String,
+    // Compiler error tag (such as D_SYNTAX)
+    pub tag: Option<String>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(ref tag) = self.tag {
+            write!(f, " [{}]", tag)?;
+        }
+        Ok(())
+    }
+}
+
+// Failure of a tracing backend operation, with libdtrace's error message
+#[derive(Debug)]
+pub enum DTraceError {
+    Open(String),
+    SetOpt(String, String),
+    Compile(CompileError),
+    Exec(String),
+    // Registration of the named consumer handler failed
+    Handler(&'static str, String),
+    Go(String),
+    Work(String),
+    Stop(String),
+    Aggregate(String),
+    Dof(String),
+    Process(String),
+}
+
+impl fmt::Display for DTraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DTraceError::Open(ref e) => write!(f, "failed to initialize dtrace: {}", e),
+            DTraceError::SetOpt(ref option, ref e) =>
+                write!(f, "failed to set option {}: {}", option, e),
+            DTraceError::Compile(ref e) => write!(f, "failed to compile dtrace program: {}", e),
+            DTraceError::Exec(ref e) => write!(f, "failed to enable dtrace probes: {}", e),
+            DTraceError::Handler(handler, ref e) =>
+                write!(f, "failed to register dtrace {} handler: {}", handler, e),
+            DTraceError::Go(ref e) => write!(f, "could not start dtrace instrumentation: {}", e),
+            DTraceError::Work(ref e) => write!(f, "dtrace_work failed: {}", e),
+            DTraceError::Stop(ref e) => write!(f, "failed to stop dtrace instrumentation: {}", e),
+            DTraceError::Aggregate(ref e) => write!(f, "failed to read aggregations: {}", e),
+            DTraceError::Dof(ref e) => write!(f, "failed to create DOF: {}", e),
+            DTraceError::Process(ref e) => write!(f, "failed to attach target process: {}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for DTraceError {}
+
+// Failure of an instrumentation script, reported to whoever deployed it
+#[derive(Debug)]
+pub enum InstrumentationError {
+    // The instrumentation's TOML couldn't be decoded or its settings are
+    // invalid
+    Config(String),
+    // The transport plugin couldn't be loaded or opened
+    Transport(String),
+    DTrace(DTraceError),
+}
+
+impl InstrumentationError {
+    // Short name of the kind of failure
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            InstrumentationError::Config(_) => "config",
+            InstrumentationError::Transport(_) => "transport",
+            InstrumentationError::DTrace(ref e) => match *e {
+                DTraceError::Open(_) => "open",
+                DTraceError::SetOpt(_, _) => "setopt",
+                DTraceError::Compile(_) => "compile",
+                DTraceError::Exec(_) => "exec",
+                DTraceError::Handler(_, _) => "handler",
+                DTraceError::Go(_) => "go",
+                DTraceError::Work(_) => "work",
+                DTraceError::Stop(_) => "stop",
+                DTraceError::Aggregate(_) => "aggregate",
+                DTraceError::Dof(_) => "dof",
+                DTraceError::Process(_) => "process",
+            },
+        }
+    }
+
+    // The D compiler's error, if the script failed to compile
+    pub fn compile_error(&self) -> Option<&CompileError> {
+        match *self {
+            InstrumentationError::DTrace(DTraceError::Compile(ref e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InstrumentationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InstrumentationError::Config(ref e) => write!(f, "invalid instrumentation: {}", e),
+            InstrumentationError::Transport(ref e) => write!(f, "failed to open transport: {}", e),
+            InstrumentationError::DTrace(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl ::std::error::Error for InstrumentationError {}
+
+impl From<DTraceError> for InstrumentationError {
+    fn from(e: DTraceError) -> InstrumentationError { InstrumentationError::DTrace(e) }
+}
@@ -0,0 +1,681 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::ffi::{CStr, CString};
+use std::mem::ManuallyDrop;
+use std::os::raw::{c_char, c_int, c_void};
+use super::ConsumerContext;
+use super::aggregate::{self, SortOrder};
+use super::backend::{Session, WorkStatus};
+use super::ddtrace_record::{AggregationEntry, Frame};
+use super::catalog::ProbeDescription;
+use super::dof::Dof;
+use super::error::{CompileError, DTraceError};
+use super::libdtrace;
+use super::libdtrace::dtrace_workstatus_t;
+use super::options::{DTRACEOPT_NAMES, DTRACEOPT_UNSET};
+use super::program::{Attributes, ProgramInfo};
+use super::record::optional_str;
+use super::symbols;
+
+// libdtrace's errno when dtrace_work() is interrupted
+const EINTR: i32 = 9959;
+
+// libdtrace constants;
+const DTRACE_HANDLE_OK: i32 = 0;
+
+// Compiler flags (DTRACE_C_ZDEFS: permit probe descriptions matching
+// no probes)
+const DTRACE_CFLAGS: u32 = 0x0080;
+
+// Compiler flag permitting library files without probe clauses
+const DTRACE_C_EMPTY: u32 = 0x0002;
+
+// DOF flag stripping the programs' comments and descriptions (as
+// dtrace_program_exec does)
+const DTRACE_D_STRIP: u32 = 0x01;
+
+// Most provider modules listed
+const MAX_PROVIDER_MODULES: usize = 256;
+
+// _IOWR('x', 6, dtrace_enable_io_t): enable the probes of a DOF program
+const DTRACEIOC_ENABLE: libdtrace::u_long = 0xc0107806;
+
+// An open libdtrace consumer handle, stopped (if tracing was started) and
+// closed when dropped
+pub struct DTraceHandle {
+    handle: *mut libdtrace::dtrace_hdl_t,
+    // Programs compiled by (and freed with) the handle
+    programs: Vec<*mut libdtrace::dtrace_prog_t>,
+    // Options before the programs were compiled (the ones that differ
+    // afterwards were set by the programs' #pragmas)
+    defaults: Vec<u64>,
+    // DOF of the programs loaded instead of compiled
+    loaded: Vec<Vec<u8>>,
+    // What dtrace_program_info reported of them when they were compiled
+    loaded_info: Option<ProgramInfo>,
+    // Processes grabbed or created by the handle, released when dropped
+    processes: Vec<*mut libdtrace::ps_prochandle>,
+    started: bool,
+}
+
+impl DTraceHandle {
+    pub fn open(version: i32, flags: i32) -> Result<DTraceHandle, DTraceError> {
+        let mut err: c_int = 0;
+        let handle = unsafe { libdtrace::dtrace_open(version, flags, &mut err) };
+        if handle.is_null() {
+            return Err(DTraceError::Open(errmsg(handle, err)));
+        }
+
+        Ok(DTraceHandle {
+            handle: handle,
+            programs: Vec::new(),
+            defaults: Vec::new(),
+            loaded: Vec::new(),
+            loaded_info: None,
+            processes: Vec::new(),
+            started: false,
+        })
+    }
+
+    // The handle passed to a consumer callback, which remains owned (and is
+    // closed) by its DTraceHandle
+    unsafe fn from_callback(handle: *mut libdtrace::dtrace_hdl_t)
+        -> ManuallyDrop<DTraceHandle> {
+
+        ManuallyDrop::new(DTraceHandle {
+            handle: handle,
+            programs: Vec::new(),
+            defaults: Vec::new(),
+            loaded: Vec::new(),
+            loaded_info: None,
+            processes: Vec::new(),
+            started: false,
+        })
+    }
+
+    // Message describing the handle's most recent error
+    fn last_error(&self) -> String {
+        errmsg(self.handle, unsafe { libdtrace::dtrace_errno(self.handle) })
+    }
+
+    // The D compiler's most recent error, with the location libdtrace
+    // recorded for it
+    fn compile_error(&self) -> CompileError {
+        let (tag, file, line) = unsafe {
+            ((* self.handle).dt_errtag, (* self.handle).dt_errfile, (* self.handle).dt_errline)
+        };
+        CompileError {
+            message: self.last_error(),
+            tag: optional_str(tag),
+            file: optional_str(file),
+            line: if line > 0 { Some(line as u32) } else { None },
+        }
+    }
+
+    // Record the options before the first program is compiled
+    fn compiling(&mut self) {
+        if self.programs.is_empty() {
+            self.defaults = unsafe { (* self.handle).dt_options.to_vec() };
+        }
+    }
+
+    fn compiled(&mut self, prog: *mut libdtrace::dtrace_prog_t) -> Result<(), DTraceError> {
+        if prog.is_null() {
+            return Err(DTraceError::Compile(self.compile_error()));
+        }
+        self.programs.push(prog);
+        Ok(())
+    }
+
+    fn registered(&self, handler: &'static str, status: c_int) -> Result<(), DTraceError> {
+        match status {
+            -1 => Err(DTraceError::Handler(handler, self.last_error())),
+            _ => Ok(()),
+        }
+    }
+}
+
+// libdtrace's strings are NUL terminated, so can't contain NULs
+fn c_string(s: &str) -> Result<CString, String> {
+    CString::new(s).map_err(|_| format!("{:?} contains a NUL character", s))
+}
+
+fn c_strings(strings: &[String]) -> Result<Vec<CString>, String> {
+    strings.iter().map(|s| c_string(s.as_str())).collect()
+}
+
+fn compile_error(message: String) -> DTraceError {
+    DTraceError::Compile(CompileError { message: message, ..Default::default() })
+}
+
+impl Session for DTraceHandle {
+    fn setopt(&mut self, opt: &str, val: &str) -> Result<(), DTraceError> {
+        let (opt_c, val_c) = match (c_string(opt), c_string(val)) {
+            (Ok(opt_c), Ok(val_c)) => (opt_c, val_c),
+            (Err(e), _) | (_, Err(e)) => return Err(DTraceError::SetOpt(opt.to_string(), e)),
+        };
+        match unsafe { libdtrace::dtrace_setopt(self.handle, opt_c.as_ptr(), val_c.as_ptr()) } {
+            0 => Ok(()),
+            _ => Err(DTraceError::SetOpt(opt.to_string(), self.last_error())),
+        }
+    }
+
+    fn getopt(&self, opt: &str) -> Option<i64> {
+        let opt_c = c_string(opt).ok()?;
+        let mut val: libdtrace::dtrace_optval_t = DTRACEOPT_UNSET;
+        match unsafe { libdtrace::dtrace_getopt(self.handle, opt_c.as_ptr(), &mut val) } {
+            0 => Some(val),
+            _ => None,
+        }
+    }
+
+    fn compile(&mut self, script: &str, args: &[String]) -> Result<(), DTraceError> {
+        self.compiling();
+        let script_c = c_string(script).map_err(compile_error)?;
+        let args_c = c_strings(args).map_err(compile_error)?;
+        let argv = args_c.iter().map(|arg| arg.as_ptr()).collect::<Vec<*const c_char>>();
+        let prog = unsafe {
+            libdtrace::dtrace_program_strcompile(self.handle, script_c.as_ptr(),
+                libdtrace::dtrace_probespec::DTRACE_PROBESPEC_NAME, DTRACE_CFLAGS,
+                argv.len() as c_int, if argv.is_empty() { ::std::ptr::null() } else { argv.as_ptr() })
+        };
+        self.compiled(prog)
+    }
+
+    fn compile_file(&mut self, path: &str, args: &[String]) -> Result<(), DTraceError> {
+        self.compiling();
+        let path_c = c_string(path).map_err(compile_error)?;
+        let args_c = c_strings(args).map_err(compile_error)?;
+        let mode_c = CString::new("r").unwrap();
+        let file = unsafe { libdtrace::fopen(path_c.as_ptr(), mode_c.as_ptr()) };
+        if file.is_null() {
+            return Err(DTraceError::Compile(CompileError {
+                message: format!("failed to open {}: {}", path,
+                    ::std::io::Error::last_os_error()),
+                tag: None,
+                file: Some(path.to_string()),
+                line: None,
+            }));
+        }
+
+        let mut argv = args_c.iter().map(|arg| arg.as_ptr() as *mut c_char)
+            .collect::<Vec<*mut c_char>>();
+        let prog = unsafe {
+            let prog = libdtrace::dtrace_program_fcompile(self.handle, file,
+                DTRACE_CFLAGS | DTRACE_C_EMPTY, argv.len() as c_int,
+                if argv.is_empty() { ::std::ptr::null_mut() } else { argv.as_mut_ptr() });
+            libdtrace::fclose(file);
+            prog
+        };
+        self.compiled(prog)
+    }
+
+    fn dof(&self) -> Result<Dof, DTraceError> {
+        let options = unsafe { (* self.handle).dt_options }.iter().zip(DTRACEOPT_NAMES)
+            .enumerate()
+            .filter(|&(option, (value, _))| self.defaults.get(option) != Some(value))
+            .map(|(_, (&value, name))| (name.to_string(), value)).collect();
+
+        let mut programs = Vec::new();
+        for &program in &self.programs {
+            let dof = unsafe {
+                libdtrace::dtrace_dof_create(self.handle, program, DTRACE_D_STRIP)
+            };
+            if dof.is_null() {
+                return Err(DTraceError::Dof(self.last_error()));
+            }
+            unsafe {
+                let size = (* (dof as *const libdtrace::dof_hdr_t)).dofh_filesz as usize;
+                programs.push(::std::slice::from_raw_parts(dof as *const u8, size).to_vec());
+                libdtrace::dtrace_dof_destroy(self.handle, dof);
+            }
+        }
+        Ok(Dof { options: options, info: self.program_info(), programs: programs })
+    }
+
+    fn load_dof(&mut self, dof: &Dof) {
+        // Restore the options set by the programs' #pragmas
+        for &(ref option, value) in &dof.options {
+            match DTRACEOPT_NAMES.iter().position(|name| name == option) {
+                Some(index) => unsafe { (* self.handle).dt_options[index] = value },
+                None => warn!("ignoring unknown cached option {}", option),
+            }
+        }
+        self.loaded = dof.programs.clone();
+        self.loaded_info = Some(dof.info.clone());
+    }
+
+    fn dtrace_version(&self) -> String {
+        let mut version = [0 as c_char; 64];
+        unsafe {
+            libdtrace::dt_version_num2str((* self.handle).dt_vmax, version.as_mut_ptr(),
+                version.len() as libdtrace::size_t);
+            super::record::c_str(version.as_ptr())
+        }
+    }
+
+    fn kernel_build(&self) -> String {
+        let uts = unsafe { &(* self.handle).dt_uts };
+        let release = unsafe { super::record::c_str(uts.release.as_ptr()) };
+        let version = unsafe { super::record::c_str(uts.version.as_ptr()) };
+        format!("{} {}", release, version.trim()).replace('\n', " ")
+    }
+
+    fn probes(&self) -> Vec<ProbeDescription> {
+        let mut probes: Vec<ProbeDescription> = Vec::new();
+        let arg = &mut probes as *mut Vec<ProbeDescription> as *mut c_void;
+        unsafe {
+            libdtrace::dtrace_probe_iter(self.handle, ::std::ptr::null(), probe_handler, arg);
+        }
+        probes
+    }
+
+    fn modules(&self) -> Vec<String> {
+        let mut modules = Vec::new();
+        unsafe {
+            let head = &(* self.handle).dt_modlist as *const libdtrace::dt_list_t;
+            let mut entry = (* head).dl_next;
+            while !entry.is_null() && entry as *const libdtrace::dt_list_t != head {
+                // The list entry is the first member of the module
+                let module = entry as *const libdtrace::dt_module_t;
+                modules.push(super::record::c_str((* module).dm_name.as_ptr()));
+                entry = (* entry).dl_next;
+            }
+        }
+        modules
+    }
+
+    fn provider_modules(&self) -> Vec<String> {
+        let mut modules = [::std::ptr::null::<c_char>(); MAX_PROVIDER_MODULES];
+        let count = unsafe {
+            libdtrace::dtrace_provider_modules(self.handle, modules.as_mut_ptr(),
+                MAX_PROVIDER_MODULES as c_int)
+        };
+        modules.iter().take(count.max(0) as usize)
+            .map(|&module| unsafe { super::record::c_str(module) }).collect()
+    }
+
+    fn update(&mut self) {
+        unsafe { libdtrace::dtrace_update(self.handle) };
+    }
+
+    fn program_info(&self) -> ProgramInfo {
+        // Loaded programs have no dtrace_prog_t to describe
+        if let Some(ref info) = self.loaded_info {
+            return info.clone();
+        }
+        // The programs' counts are summed, and their attributes are the
+        // least stable of any of them
+        let mut total = ProgramInfo::default();
+        let mut descattr: Option<libdtrace::dtrace_attribute_t> = None;
+        let mut stmtattr: Option<libdtrace::dtrace_attribute_t> = None;
+        for &program in &self.programs {
+            let mut info: libdtrace::dtrace_proginfo_t = Default::default();
+            unsafe { libdtrace::dtrace_program_info(self.handle, program, &mut info) };
+            total.matches += info.dpi_matches;
+            total.aggregates += info.dpi_aggregates;
+            total.recgens += info.dpi_recgens;
+            total.speculations += info.dpi_speculations;
+            descattr = Some(min_attributes(descattr, info.dpi_descattr));
+            stmtattr = Some(min_attributes(stmtattr, info.dpi_stmtattr));
+        }
+        total.description = descattr.map(attributes).unwrap_or_default();
+        total.statement = stmtattr.map(attributes).unwrap_or_default();
+        total
+    }
+
+    fn proc_grab(&mut self, pid: i32) -> Result<(), DTraceError> {
+        let p = unsafe { libdtrace::dtrace_proc_grab(self.handle, pid, 0) };
+        if p.is_null() {
+            return Err(DTraceError::Process(format!("pid {}: {}", pid, self.last_error())));
+        }
+        self.processes.push(p);
+        Ok(())
+    }
+
+    fn proc_create(&mut self, command: &[String]) -> Result<i32, DTraceError> {
+        let command_c = c_strings(command).map_err(DTraceError::Process)?;
+        let mut argv = command_c.iter().map(|arg| arg.as_ptr() as *mut c_char)
+            .collect::<Vec<*mut c_char>>();
+        argv.push(::std::ptr::null_mut());
+        let p = unsafe {
+            libdtrace::dtrace_proc_create(self.handle, command_c[0].as_ptr(), argv.as_ptr(),
+                ::std::ptr::null_mut(), ::std::ptr::null_mut())
+        };
+        if p.is_null() {
+            return Err(DTraceError::Process(format!("{}: {}", command[0], self.last_error())));
+        }
+        self.processes.push(p);
+        Ok(unsafe { libdtrace::proc_getpid(p as *mut libdtrace::proc_handle) })
+    }
+
+    fn proc_continue(&mut self) {
+        for &p in &self.processes {
+            unsafe { libdtrace::dtrace_proc_continue(self.handle, p) };
+        }
+    }
+
+    fn proc_release(&mut self) {
+        for p in self.processes.drain(..) {
+            unsafe { libdtrace::dtrace_proc_release(self.handle, p) };
+        }
+    }
+
+    fn exec(&mut self) -> Result<(), DTraceError> {
+        for &program in &self.programs {
+            let mut info: libdtrace::dtrace_proginfo_t = Default::default();
+            if unsafe { libdtrace::dtrace_program_exec(self.handle, program, &mut info) } == -1 {
+                return Err(DTraceError::Exec(self.last_error()));
+            }
+        }
+        // Loaded programs are enabled as dtrace_program_exec would, without
+        // the descriptions of their probes, aggregations and formats; as for
+        // anonymous enablings, libdtrace looks those up in the kernel by id
+        // (dt_epid_lookup, dt_aggid_lookup and dt_format_lookup) when
+        // consuming their records
+        for program in &self.loaded {
+            // The kernel reads the DOF through the pointer passed to it
+            let mut dof = program.clone();
+            let mut enable: libdtrace::dtrace_enable_io_t = Default::default();
+            enable.dof = dof.as_mut_ptr() as *mut c_void;
+            let arg = &mut enable as *mut libdtrace::dtrace_enable_io_t as *mut c_void;
+            if unsafe { libdtrace::dt_ioctl(self.handle, DTRACEIOC_ENABLE, arg) } == -1 {
+                return Err(DTraceError::Exec(::std::io::Error::last_os_error().to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn register(&mut self, context: *mut ConsumerContext) -> Result<(), DTraceError> {
+        // The handlers must be registered before the instrumentation
+        // is started (dtrace_handle_err enables the ERROR probe)
+        let arg = context as *mut c_void;
+        self.registered("error", libdtrace::dtrace_handle_err(self.handle, err_handler, arg))?;
+        self.registered("drop", libdtrace::dtrace_handle_drop(self.handle, drop_handler, arg))?;
+        self.registered("proc", libdtrace::dtrace_handle_proc(self.handle, proc_handler, arg))?;
+        self.registered("setopt",
+            libdtrace::dtrace_handle_setopt(self.handle, setopt_handler, arg))?;
+        self.registered("buffered",
+            libdtrace::dtrace_handle_buffered(self.handle, buffered_handler, arg))
+    }
+
+    fn go(&mut self) -> Result<(), DTraceError> {
+        match unsafe { libdtrace::dtrace_go(self.handle) } {
+            0 => {
+                self.started = true;
+                Ok(())
+            },
+            _ => Err(DTraceError::Go(self.last_error())),
+        }
+    }
+
+    fn sleep(&self) {
+        unsafe { libdtrace::dtrace_sleep(self.handle) }
+    }
+
+    unsafe fn work(&mut self, context: *mut ConsumerContext) -> Result<WorkStatus, DTraceError> {
+        match libdtrace::dtrace_work(self.handle, ::std::ptr::null_mut(), chew, chewrec,
+            context as *mut c_void) {
+            dtrace_workstatus_t::DTRACE_WORKSTATUS_ERROR => {
+                if libdtrace::dtrace_errno(self.handle) == EINTR {
+                    Ok(WorkStatus::Okay)
+                } else {
+                    Err(DTraceError::Work(self.last_error()))
+                }
+            },
+            dtrace_workstatus_t::DTRACE_WORKSTATUS_OKAY => Ok(WorkStatus::Okay),
+            dtrace_workstatus_t::DTRACE_WORKSTATUS_DONE => Ok(WorkStatus::Done),
+        }
+    }
+
+    fn stop(&mut self) -> Result<(), DTraceError> {
+        if !self.started {
+            return Ok(());
+        }
+        self.started = false;
+        match unsafe { libdtrace::dtrace_stop(self.handle) } {
+            0 => Ok(()),
+            _ => Err(DTraceError::Stop(self.last_error())),
+        }
+    }
+
+    fn aggregate_snap(&mut self) -> Result<(), DTraceError> {
+        match unsafe { libdtrace::dtrace_aggregate_snap(self.handle) } {
+            0 => Ok(()),
+            _ => Err(DTraceError::Aggregate(self.last_error())),
+        }
+    }
+
+    fn aggregate_clear(&mut self) {
+        unsafe { libdtrace::dtrace_aggregate_clear(self.handle) }
+    }
+
+    fn aggregate_print(&mut self) -> Result<(), DTraceError> {
+        match unsafe {
+            libdtrace::dtrace_aggregate_print(self.handle, ::std::ptr::null_mut(),
+                ::std::ptr::null_mut())
+        } {
+            0 => Ok(()),
+            _ => Err(DTraceError::Aggregate(self.last_error())),
+        }
+    }
+
+    fn aggregate_entries(&self, order: Option<SortOrder>)
+        -> Result<Vec<AggregationEntry>, DTraceError> {
+
+        let mut entries: Vec<AggregationEntry> = Vec::new();
+        let arg = &mut entries as *mut _ as *mut c_void;
+        let status = unsafe {
+            match order {
+                None => libdtrace::dtrace_aggregate_walk(self.handle,
+                    aggregate::walk_entry, arg),
+                Some(SortOrder::Value) => libdtrace::dtrace_aggregate_walk_valrevsorted(
+                    self.handle, aggregate::walk_entry, arg),
+                Some(SortOrder::Key) => libdtrace::dtrace_aggregate_walk_keysorted(
+                    self.handle, aggregate::walk_entry, arg),
+            }
+        };
+        match status {
+            0 => Ok(entries),
+            _ => Err(DTraceError::Aggregate(self.last_error())),
+        }
+    }
+
+    fn lookup_kernel(&self, addr: u64) -> Frame {
+        symbols::lookup_kernel(self.handle, addr)
+    }
+
+    fn lookup_user(&self, pid: u64, addr: u64) -> Frame {
+        symbols::lookup_user(self.handle, pid, addr)
+    }
+}
+
+impl Drop for DTraceHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            error!("{}", e);
+        }
+        self.proc_release();
+        unsafe { libdtrace::dtrace_close(self.handle) }
+    }
+}
+
+// The least stable of the attributes (as with libdtrace's dt_attr_min)
+fn min_attributes(attr: Option<libdtrace::dtrace_attribute_t>,
+    other: libdtrace::dtrace_attribute_t) -> libdtrace::dtrace_attribute_t {
+
+    match attr {
+        Some(attr) => libdtrace::dtrace_attribute_t {
+            dtat_name: attr.dtat_name.min(other.dtat_name),
+            dtat_data: attr.dtat_data.min(other.dtat_data),
+            dtat_class: attr.dtat_class.min(other.dtat_class),
+        },
+        None => other,
+    }
+}
+
+// Name the stability attributes
+fn attributes(attr: libdtrace::dtrace_attribute_t) -> Attributes {
+    unsafe {
+        Attributes {
+            name: super::record::c_str(libdtrace::dtrace_stability_name(attr.dtat_name)),
+            data: super::record::c_str(libdtrace::dtrace_stability_name(attr.dtat_data)),
+            class: super::record::c_str(libdtrace::dtrace_class_name(attr.dtat_class)),
+        }
+    }
+}
+
+// libdtrace's message for the error (the handle may be null if it
+// couldn't be opened)
+fn errmsg(handle: *mut libdtrace::dtrace_hdl_t, err: i32) -> String {
+    unsafe {
+        CStr::from_ptr(libdtrace::dtrace_errmsg(handle, err)).to_string_lossy().into_owned()
+    }
+}
+
+unsafe extern fn err_handler(
+   data: *const libdtrace::dtrace_errdata_t,
+   arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
+
+   // Send the fault upstream using the specified transport handler
+   let context = arg as *mut ConsumerContext;
+   (* context).write_error(super::record::error_record(data));
+   DTRACE_HANDLE_OK
+}
+
+unsafe extern fn drop_handler(
+   data : *const libdtrace::dtrace_dropdata_t,
+   arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
+
+   // Send the drops upstream using the specified transport handler
+   let context = arg as *mut ConsumerContext;
+   (* context).write_drop(super::drops::drop_record(data));
+   DTRACE_HANDLE_OK
+}
+
+unsafe extern fn proc_handler(
+   p: *mut libdtrace::ps_prochandle,
+   msg: *const c_char,
+   arg: *mut ::std::os::raw::c_void) {
+
+   // Send the process's state change upstream using the specified
+   // transport handler
+   let context = arg as *mut ConsumerContext;
+   (* context).write_process(super::process::process_record(p, msg));
+}
+
+unsafe extern fn setopt_handler(
+   data: *const libdtrace::dtrace_setoptdata_t,
+   arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
+
+   // Record the option changed by the D program, subject to the policy
+   let context = arg as *mut ConsumerContext;
+   let mut handle = DTraceHandle::from_callback((* data).dtsda_handle);
+   let option = super::record::c_str((* data).dtsda_option);
+   (* context).review_option(&mut *handle, option.as_str(), (* data).dtsda_oldval,
+       (* data).dtsda_newval);
+   DTRACE_HANDLE_OK
+}
+
+unsafe extern fn probe_handler(
+   _handle: *mut libdtrace::dtrace_hdl_t,
+   desc: *const libdtrace::dtrace_probedesc_t,
+   arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
+
+   // Collect the probes listed by dtrace_probe_iter
+   let probes = arg as *mut Vec<ProbeDescription>;
+   (* probes).push(ProbeDescription {
+       provider: super::record::c_str((* desc).dtpd_provider.as_ptr()),
+       module: super::record::c_str((* desc).dtpd_mod.as_ptr()),
+       function: super::record::c_str((* desc).dtpd_func.as_ptr()),
+       name: super::record::c_str((* desc).dtpd_name.as_ptr()),
+   });
+   0
+}
+
+unsafe extern fn buffered_handler(
+   bufdata : *const libdtrace::dtrace_bufdata_t,
+   arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
+       
+   // Write the records upstream using the specified transport handler
+   let context = arg as *mut ConsumerContext;
+   (* context).write_output(CStr::from_ptr((* bufdata).dtbda_buffered).to_bytes())
+}
+       
+unsafe extern fn chew(data: *const libdtrace::dtrace_probedata_t,
+    arg: *mut ::std::os::raw::c_void) -> i32 {
+    
+    info!("chew");
+
+    let pd: *mut libdtrace::dtrace_probedesc_t = (* data).dtpda_pdesc;
+    //trace!("id = {}", (* pd).dtpd_id); timestamp
+
+    //trace!("id = {}", (* data).processorid_t); cpu
+    trace!("id = {}", (* pd).dtpd_id);
+    trace!("func = {:?}", CStr::from_ptr((* pd).dtpd_func.as_ptr()));
+    trace!("name = {:?}", CStr::from_ptr((* pd).dtpd_name.as_ptr()));
+
+    // Start a structured record for the probe firing, deciding whether it's
+    // sampled and within the rate limit
+    let context = arg as *mut ConsumerContext;
+    (* context).consume_probe(data)
+}
+
+unsafe extern fn chewrec(data: *const libdtrace::dtrace_probedata_t,
+    rec: *const libdtrace::dtrace_recdesc_t,
+    arg: *mut ::std::os::raw::c_void) -> i32 {
+
+    trace!("chewing DTrace record");
+
+    // Decode the record into the probe firing's structured record, sending
+    // the firing upstream after its last record
+    let context = arg as *mut ConsumerContext;
+    let handle = DTraceHandle::from_callback((* data).dtpda_handle);
+    (* context).consume_record(&*handle, data, rec)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_strings_with_nuls() {
+        assert!(c_string("syscall:::entry { trace(pid); }").is_ok());
+        assert!(c_strings(&["sshd".to_string(), "ss\0hd".to_string()]).is_err());
+        match c_string("BEGIN\0").map_err(compile_error) {
+            Err(DTraceError::Compile(e)) => assert!(e.message.contains("NUL")),
+            _ => panic!("expected a compile error"),
+        }
+    }
+}
@@ -5789,7 +5789,7 @@ extern "C" {
                        arg5: *mut ::std::os::raw::c_void)
      -> dtrace_workstatus_t;
     pub fn dtrace_handle_err(arg1: *mut dtrace_hdl_t,
-                             arg2: *mut dtrace_handle_err_f,
+                             arg2: dtrace_handle_err_f,
                              arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_handle_drop(arg1: *mut dtrace_hdl_t,
@@ -5797,7 +5797,7 @@ extern "C" {
                               arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_handle_proc(arg1: *mut dtrace_hdl_t,
-                              arg2: *mut dtrace_handle_proc_f,
+                              arg2: dtrace_handle_proc_f,
                               arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_handle_buffered(arg1: *mut dtrace_hdl_t,
@@ -5805,7 +5805,7 @@ extern "C" {
                                   arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_handle_setopt(arg1: *mut dtrace_hdl_t,
-                                arg2: *mut dtrace_handle_setopt_f,
+                                arg2: dtrace_handle_setopt_f,
                                 arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_aggregate_clear(arg1: *mut dtrace_hdl_t);
@@ -5815,7 +5815,7 @@ extern "C" {
                                   arg3: *mut dtrace_aggregate_walk_f)
      -> ::std::os::raw::c_int;
     pub fn dtrace_aggregate_walk(arg1: *mut dtrace_hdl_t,
-                                 arg2: *mut dtrace_aggregate_f,
+                                 arg2: dtrace_aggregate_f,
                                  arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_aggregate_walk_joined(arg1: *mut dtrace_hdl_t,
@@ -5830,7 +5830,7 @@ extern "C" {
                                         arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_aggregate_walk_keysorted(arg1: *mut dtrace_hdl_t,
-                                           arg2: *mut dtrace_aggregate_f,
+                                           arg2: dtrace_aggregate_f,
                                            arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_aggregate_walk_valsorted(arg1: *mut dtrace_hdl_t,
@@ -5853,7 +5853,7 @@ extern "C" {
                                                   *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_aggregate_walk_valrevsorted(arg1: *mut dtrace_hdl_t,
-                                              arg2: *mut dtrace_aggregate_f,
+                                              arg2: dtrace_aggregate_f,
                                               arg3:
                                                   *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
@@ -5918,7 +5918,7 @@ extern "C" {
      -> ::std::os::raw::c_int;
     pub fn dtrace_probe_iter(arg1: *mut dtrace_hdl_t,
                              pdp: *const dtrace_probedesc_t,
-                             arg2: *mut dtrace_probe_f,
+                             arg2: dtrace_probe_f,
                              arg3: *mut ::std::os::raw::c_void)
      -> ::std::os::raw::c_int;
     pub fn dtrace_probe_info(arg1: *mut dtrace_hdl_t,
@@ -0,0 +1,879 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+// Scripted tracing backend and transport, replaying canned traced data
+// through the consumer loop so that it can be tested without libdtrace
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::os::raw::c_char;
+use std::ptr;
+use std::rc::Rc;
+use super::{ConsumerContext, Transport};
+use super::aggregate::SortOrder;
+use super::catalog::ProbeDescription;
+use super::backend::{Backend, Session, WorkStatus};
+use super::ddtrace_record::{AggregationEntry, DropRecord, ErrorRecord, Frame, ProbeRecord,
+    ProcessRecord, RecordValue};
+use super::dof::Dof;
+use super::drops::parse_size;
+use super::error::{CompileError, DTraceError};
+use super::libdtrace;
+use super::options::DTRACEOPT_UNSET;
+use super::program::{Attributes, ProgramInfo};
+use super::record::{DTRACEACT_DIFEXPR, DTRACEACT_EXIT, DTRACEACT_STACK, DTRACEACT_SYM,
+    DTRACEACT_TRACEMEM, DTRACEACT_UADDR, DTRACEACT_USTACK};
+
+// Traced data replayed by a mock session, one event per call to work()
+#[derive(Clone, Debug)]
+pub enum MockEvent {
+    // A probe firing with its record values and formatted output
+    Probe(ProbeRecord),
+    Error(ErrorRecord),
+    Drop(DropRecord),
+    Process(ProcessRecord),
+    // The D program setting an option with setopt()
+    SetOpt(String, i64),
+    // The D program exiting
+    Exit,
+}
+
+// Opens mock sessions replaying the scripted events
+#[derive(Clone, Debug, Default)]
+pub struct MockBackend {
+    pub events: Vec<MockEvent>,
+    // Entries of the aggregations reported by each snapshot
+    pub aggregations: Vec<AggregationEntry>,
+    // Number of times the aggregations were cleared
+    pub cleared: Rc<RefCell<u32>>,
+    // Failure of the program's compilation
+    pub compile_error: Option<CompileError>,
+    // Macro arguments the program was compiled with
+    pub args: Rc<RefCell<Vec<String>>>,
+    // Sources of the compiled programs (the paths of compiled files)
+    pub compiled: Rc<RefCell<Vec<String>>>,
+    // Description of the compiled program
+    pub info: ProgramInfo,
+    // Whether the program's probes were enabled
+    pub executed: Rc<RefCell<bool>>,
+    // Kernel build the programs are compiled for
+    pub kernel: String,
+    // Programs loaded from DOF instead of compiled
+    pub loaded: Rc<RefCell<Vec<Vec<u8>>>>,
+    // Probes of every kernel module, of which those of the loaded modules
+    // are listed
+    pub probes: Vec<ProbeDescription>,
+    pub modules: Vec<String>,
+    // Modules loaded as of each dtrace_update
+    pub updates: Rc<RefCell<VecDeque<Vec<String>>>>,
+    // The target process operations, in order
+    pub processes: Rc<RefCell<Vec<String>>>,
+    // Addresses looked up as symbols, in order
+    pub lookups: Rc<RefCell<Vec<u64>>>,
+}
+
+impl Backend for MockBackend {
+    fn open(&self) -> Result<Box<Session>, DTraceError> {
+        Ok(Box::new(MockSession {
+            events: self.events.iter().cloned().collect(),
+            aggregations: self.aggregations.clone(),
+            cleared: self.cleared.clone(),
+            compile_error: self.compile_error.clone(),
+            args: self.args.clone(),
+            compiled: self.compiled.clone(),
+            info: self.info.clone(),
+            executed: self.executed.clone(),
+            kernel: self.kernel.clone(),
+            loaded: self.loaded.clone(),
+            programs: Vec::new(),
+            probes: self.probes.clone(),
+            modules: self.modules.clone(),
+            updates: self.updates.clone(),
+            processes: self.processes.clone(),
+            lookups: self.lookups.clone(),
+            options: HashMap::new(),
+            context: None,
+        }))
+    }
+}
+
+pub struct MockSession {
+    events: VecDeque<MockEvent>,
+    aggregations: Vec<AggregationEntry>,
+    cleared: Rc<RefCell<u32>>,
+    compile_error: Option<CompileError>,
+    args: Rc<RefCell<Vec<String>>>,
+    compiled: Rc<RefCell<Vec<String>>>,
+    info: ProgramInfo,
+    executed: Rc<RefCell<bool>>,
+    kernel: String,
+    loaded: Rc<RefCell<Vec<Vec<u8>>>>,
+    // The DOF of each compiled program is its source
+    programs: Vec<Vec<u8>>,
+    probes: Vec<ProbeDescription>,
+    modules: Vec<String>,
+    updates: Rc<RefCell<VecDeque<Vec<String>>>>,
+    processes: Rc<RefCell<Vec<String>>>,
+    lookups: Rc<RefCell<Vec<u64>>>,
+    options: HashMap<String, i64>,
+    // Context of the buffered handler
+    context: Option<*mut ConsumerContext>,
+}
+
+impl Session for MockSession {
+    fn setopt(&mut self, opt: &str, val: &str) -> Result<(), DTraceError> {
+        // Flags are set without a value, defines and paths are taken as they
+        // are, other options take sizes
+        let value = match val {
+            _ if opt == "define" || opt == "incdir" => return Ok(()),
+            "" => Some(0),
+            _ => parse_size(val).map(|size| size as i64),
+        };
+        match value {
+            Some(value) => {
+                self.options.insert(opt.to_string(), value);
+                Ok(())
+            },
+            None => Err(DTraceError::SetOpt(opt.to_string(),
+                "Invalid value for specified option".to_string())),
+        }
+    }
+
+    fn getopt(&self, opt: &str) -> Option<i64> {
+        Some(*self.options.get(opt).unwrap_or(&DTRACEOPT_UNSET))
+    }
+
+    fn compile(&mut self, script: &str, args: &[String]) -> Result<(), DTraceError> {
+        self.compiled.borrow_mut().push(script.to_string());
+        self.programs.push(script.as_bytes().to_vec());
+        *self.args.borrow_mut() = args.to_vec();
+        match self.compile_error {
+            Some(ref e) => Err(DTraceError::Compile(e.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn compile_file(&mut self, path: &str, args: &[String]) -> Result<(), DTraceError> {
+        self.compile(path, args)
+    }
+
+    fn dof(&self) -> Result<Dof, DTraceError> {
+        Ok(Dof { options: Vec::new(), info: self.info.clone(), programs: self.programs.clone() })
+    }
+
+    fn load_dof(&mut self, dof: &Dof) {
+        self.loaded.borrow_mut().extend(dof.programs.iter().cloned());
+        self.info = dof.info.clone();
+    }
+
+    fn dtrace_version(&self) -> String {
+        "1.13".to_string()
+    }
+
+    fn kernel_build(&self) -> String {
+        self.kernel.clone()
+    }
+
+    fn probes(&self) -> Vec<ProbeDescription> {
+        self.probes.iter().filter(|probe| self.modules.contains(&probe.module)).cloned().collect()
+    }
+
+    fn modules(&self) -> Vec<String> {
+        self.modules.clone()
+    }
+
+    fn provider_modules(&self) -> Vec<String> {
+        vec!["dtrace".to_string()]
+    }
+
+    fn update(&mut self) {
+        if let Some(modules) = self.updates.borrow_mut().pop_front() {
+            self.modules = modules;
+        }
+    }
+
+    fn program_info(&self) -> ProgramInfo {
+        self.info.clone()
+    }
+
+    fn proc_grab(&mut self, pid: i32) -> Result<(), DTraceError> {
+        self.processes.borrow_mut().push(format!("grab {}", pid));
+        Ok(())
+    }
+
+    // Created processes are given pid 100
+    fn proc_create(&mut self, command: &[String]) -> Result<i32, DTraceError> {
+        self.processes.borrow_mut().push(format!("create {}", command.join(" ")));
+        Ok(100)
+    }
+
+    fn proc_continue(&mut self) {
+        self.processes.borrow_mut().push("continue".to_string());
+    }
+
+    fn proc_release(&mut self) {
+        self.processes.borrow_mut().push("release".to_string());
+    }
+
+    fn exec(&mut self) -> Result<(), DTraceError> {
+        *self.executed.borrow_mut() = true;
+        Ok(())
+    }
+
+    unsafe fn register(&mut self, context: *mut ConsumerContext) -> Result<(), DTraceError> {
+        self.context = Some(context);
+        Ok(())
+    }
+
+    fn go(&mut self) -> Result<(), DTraceError> {
+        Ok(())
+    }
+
+    fn sleep(&self) {}
+
+    unsafe fn work(&mut self, context: *mut ConsumerContext) -> Result<WorkStatus, DTraceError> {
+        let context = &mut *context;
+        match self.events.pop_front() {
+            Some(MockEvent::Probe(record)) => {
+                // The firing is consumed as dtrace_work() passes it to its
+                // callbacks: the probe, each of its records in their raw form
+                // and then a null record
+                let mut pdesc = libdtrace::dtrace_probedesc_t::default();
+                pdesc.dtpd_id = record.id;
+                set_str(&mut pdesc.dtpd_provider, &record.provider);
+                set_str(&mut pdesc.dtpd_mod, &record.module);
+                set_str(&mut pdesc.dtpd_func, &record.function);
+                set_str(&mut pdesc.dtpd_name, &record.name);
+                let mut data = libdtrace::dtrace_probedata_t {
+                    dtpda_pdesc: &mut pdesc,
+                    dtpda_cpu: record.cpu,
+                    dtpda_timestamp: record.timestamp,
+                    ..Default::default()
+                };
+                context.consume_probe(&data);
+                for value in &record.values {
+                    let (action, mut raw) = raw_record(value);
+                    let rec = libdtrace::dtrace_recdesc_t {
+                        dtrd_action: action,
+                        dtrd_size: raw.len() as u32,
+                        ..Default::default()
+                    };
+                    data.dtpda_data = raw.as_mut_ptr() as libdtrace::caddr_t;
+                    context.consume_record(self, &data, &rec);
+                }
+                // Each line is output separately, as by successive printf()s
+                for line in record.output.split_terminator('\n') {
+                    context.write_output(format!("{}\n", line).as_bytes());
+                }
+                context.consume_record(self, &data, ptr::null());
+            },
+            Some(MockEvent::Error(record)) => context.write_error(record),
+            Some(MockEvent::Drop(record)) => context.write_drop(record),
+            Some(MockEvent::Process(record)) => context.write_process(record),
+            Some(MockEvent::SetOpt(option, value)) => {
+                let old = self.getopt(option.as_str()).unwrap_or(DTRACEOPT_UNSET);
+                self.options.insert(option.clone(), value);
+                context.review_option(self, option.as_str(), old, value);
+            },
+            Some(MockEvent::Exit) | None => return Ok(WorkStatus::Done),
+        }
+        Ok(WorkStatus::Okay)
+    }
+
+    fn stop(&mut self) -> Result<(), DTraceError> {
+        Ok(())
+    }
+
+    fn aggregate_snap(&mut self) -> Result<(), DTraceError> {
+        Ok(())
+    }
+
+    fn aggregate_clear(&mut self) {
+        *self.cleared.borrow_mut() += 1;
+        self.aggregations.clear();
+    }
+
+    // Each entry is printed on a line, passed to the buffered handler
+    fn aggregate_print(&mut self) -> Result<(), DTraceError> {
+        let context = match self.context {
+            Some(context) => context,
+            None => return Err(DTraceError::Aggregate("no buffered handler".to_string())),
+        };
+        for entry in &self.aggregations {
+            let line = format!("@{}{:?} {}\n", entry.name, entry.keys, entry.value);
+            unsafe { (*context).write_output(line.as_bytes()) };
+        }
+        Ok(())
+    }
+
+    fn aggregate_entries(&self, order: Option<SortOrder>)
+        -> Result<Vec<AggregationEntry>, DTraceError> {
+
+        let mut entries = self.aggregations.clone();
+        match order {
+            Some(SortOrder::Value) => entries.sort_by(|a, b| b.value.cmp(&a.value)),
+            Some(SortOrder::Key) =>
+                entries.sort_by_key(|entry| format!("{:?}", entry.keys)),
+            None => {}
+        }
+        Ok(entries)
+    }
+
+    fn lookup_kernel(&self, addr: u64) -> Frame {
+        self.lookups.borrow_mut().push(addr);
+        Frame { addr: addr, module: "kernel".to_string(), ..Default::default() }
+    }
+
+    fn lookup_user(&self, _pid: u64, addr: u64) -> Frame {
+        self.lookups.borrow_mut().push(addr);
+        Frame { addr: addr, ..Default::default() }
+    }
+}
+
+fn set_str(field: &mut [c_char], value: &str) {
+    for (c, b) in field.iter_mut().zip(value.bytes()) {
+        *c = b as c_char;
+    }
+}
+
+// A record value as DTrace traces it: the action recording it and its data
+fn raw_record(value: &RecordValue) -> (u16, Vec<u8>) {
+    let u64s = |values: &[u64]| values.iter()
+        .flat_map(|value| value.to_le_bytes().to_vec())
+        .collect::<Vec<u8>>();
+    match *value {
+        RecordValue::Int(v) => (DTRACEACT_DIFEXPR, v.to_le_bytes().to_vec()),
+        RecordValue::String(ref v) => {
+            let mut raw = v.clone().into_bytes();
+            raw.resize(v.len() + 8, 0);
+            (DTRACEACT_DIFEXPR, raw)
+        },
+        RecordValue::Bytes(ref v) => (DTRACEACT_TRACEMEM, v.clone()),
+        RecordValue::Exit(v) => (DTRACEACT_EXIT, v.to_le_bytes().to_vec()),
+        RecordValue::Stack(ref frames) => (DTRACEACT_STACK, u64s(&[&frames[..], &[0]].concat())),
+        RecordValue::UserStack { pid, ref frames } => {
+            (DTRACEACT_USTACK, u64s(&[&[pid], &frames[..], &[0]].concat()))
+        },
+        RecordValue::Address(addr) => (DTRACEACT_SYM, u64s(&[addr])),
+        RecordValue::UserAddress { pid, addr } => (DTRACEACT_UADDR, u64s(&[pid, addr])),
+        ref value => panic!("{:?} isn't traced by DTrace", value),
+    }
+}
+
+// Transport collecting the records written to it
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    pub records: Rc<RefCell<Vec<Vec<u8>>>>,
+    pub closed: Rc<RefCell<bool>>,
+}
+
+impl Transport for MockTransport {
+    fn write(&self, data: &[u8]) -> i32 {
+        self.records.borrow_mut().push(data.to_vec());
+        0
+    }
+
+    fn flush(&self) -> i32 {
+        0
+    }
+
+    fn close(&mut self) -> i32 {
+        *self.closed.borrow_mut() = true;
+        0
+    }
+}
+
+mod tests {
+    use std::sync::mpsc;
+    use super::*;
+    use super::super::{instrument, validate, Endpoint, InstrumentationError, Transport};
+    use super::super::ddtrace_record::{AggregationSnapshot, Encoding, Envelope, Record,
+        RecordValue, StatsRecord};
+
+    fn endpoint() -> Endpoint {
+        Endpoint {
+            hostname: "db1.example.com".to_string(),
+            library: "/var/db/ddtrace".to_string(),
+            cache: None,
+            agent_name: "ddtrace",
+            agent_version: "0.1.0",
+        }
+    }
+
+    fn run(backend: MockBackend, config: &str)
+        -> (Result<(), InstrumentationError>, MockTransport) {
+
+        run_on(backend, endpoint(), config)
+    }
+
+    fn run_on(backend: MockBackend, endpoint: Endpoint, config: &str)
+        -> (Result<(), InstrumentationError>, MockTransport) {
+
+        let transport = MockTransport::default();
+        let (_tx, rx) = mpsc::channel();
+        let open = |_: &str, _: &str| Ok(Box::new(transport.clone()) as Box<Transport>);
+        let result = instrument(&backend, &open, endpoint, "syscalls".to_string(),
+            config.to_string(), rx);
+        (result, transport)
+    }
+
+    fn decode<T: Record>(data: &[u8], record_type: &str) -> T {
+        let envelope: Envelope<T> = Encoding::Json.decode(data).unwrap();
+        assert_eq!(envelope.record_type, record_type);
+        assert_eq!(envelope.metadata.script_id, "syscalls");
+        envelope.record
+    }
+
+    const CONFIG: &'static str = "
+        [instrumentation]
+        script = \"syscall:::entry { trace(pid); }\"
+        format = \"json\"
+        stop_on_exit = true
+    ";
+
+    #[test]
+    fn replays_traced_data_upstream() {
+        let probe = ProbeRecord {
+            provider: "syscall".to_string(),
+            function: "read".to_string(),
+            name: "entry".to_string(),
+            values: vec![RecordValue::Int(812)],
+            ..Default::default()
+        };
+        let drop = DropRecord {
+            kind: "principal".to_string(),
+            count: 16,
+            total: 16,
+            message: "16 drops on CPU 0".to_string(),
+            ..Default::default()
+        };
+        let exited = ProcessRecord {
+            pid: 812,
+            event: "exited".to_string(),
+            ..Default::default()
+        };
+        let backend = MockBackend {
+            events: vec![MockEvent::Probe(probe.clone()), MockEvent::Drop(drop.clone()),
+                MockEvent::Process(exited.clone()), MockEvent::Probe(probe.clone())],
+            ..Default::default()
+        };
+
+        // The script stops once its target process has exited
+        let (result, transport) = run(backend, CONFIG);
+        assert!(result.is_ok());
+        let records = transport.records.borrow();
+        assert_eq!(records.len(), 4);
+        assert_eq!(decode::<ProbeRecord>(&records[0], "probe"), probe);
+        assert_eq!(decode::<DropRecord>(&records[1], "drop"), drop);
+        assert_eq!(decode::<ProcessRecord>(&records[2], "process"), exited);
+
+        let stats = decode::<StatsRecord>(&records[3], "stats");
+        assert_eq!(stats.passed, 1);
+        assert_eq!(stats.drops.get("principal"), Some(&16));
+        assert_eq!(stats.processes.get(&812).map(|event| event.as_str()), Some("exited"));
+        assert!(*transport.closed.borrow());
+    }
+
+    #[test]
+    fn decodes_raw_records() {
+        let probe = ProbeRecord {
+            id: 42,
+            provider: "syscall".to_string(),
+            module: "freebsd".to_string(),
+            function: "open".to_string(),
+            name: "entry".to_string(),
+            cpu: 3,
+            timestamp: 1000,
+            values: vec![RecordValue::Int(812), RecordValue::String("/etc/passwd".to_string()),
+                RecordValue::Bytes(vec![1, 2, 3]), RecordValue::Stack(vec![0xffff0010]),
+                RecordValue::UserStack { pid: 812, frames: vec![0x400a10, 0x400b20] },
+                RecordValue::UserAddress { pid: 812, addr: 0x400a10 }],
+            ..Default::default()
+        };
+        let exiting = ProbeRecord {
+            values: vec![RecordValue::Exit(0), RecordValue::Int(1)],
+            ..Default::default()
+        };
+        let backend = MockBackend {
+            events: vec![MockEvent::Probe(probe.clone()), MockEvent::Probe(exiting)],
+            ..Default::default()
+        };
+        let config = format!("{}
+            stack_format = \"raw\"
+        ", CONFIG);
+
+        // The firing ends with exit(), whose record is the last decoded
+        let (_, transport) = run(backend, config.as_str());
+        let records = transport.records.borrow();
+        assert_eq!(decode::<ProbeRecord>(&records[0], "probe"), probe);
+        assert_eq!(decode::<ProbeRecord>(&records[1], "probe").values,
+            vec![RecordValue::Exit(0)]);
+    }
+
+    #[test]
+    fn samples_whole_probe_firings() {
+        let probe = ProbeRecord {
+            output: "open /etc/passwd\nread 4096\n".to_string(),
+            ..Default::default()
+        };
+        let backend = MockBackend {
+            events: vec![MockEvent::Probe(probe); 4],
+            ..Default::default()
+        };
+        let config = format!("{}
+            [instrumentation.sampling]
+            one_in = 2
+        ", CONFIG.replace("json", "text"));
+
+        // Every other firing is forwarded, with all of its output
+        let (_, transport) = run(backend, config.as_str());
+        let records = transport.records.borrow();
+        assert_eq!(records.len(), 5);
+        for firing in records[..4].chunks(2) {
+            assert!(firing[0].starts_with(b"host=db1.example.com "));
+            assert!(firing[0].ends_with(b" open /etc/passwd\n"));
+            assert_eq!(firing[1], b"read 4096\n");
+        }
+
+        // The counters are reported as a JSON stats record
+        let stats = decode::<StatsRecord>(&records[4], "stats");
+        assert_eq!((stats.passed, stats.sampled_out), (2, 2));
+    }
+
+    #[test]
+    fn forwards_every_error() {
+        let error = ErrorRecord {
+            message: "invalid address (0x0) in action #1".to_string(),
+            ..Default::default()
+        };
+        let backend = MockBackend {
+            events: vec![MockEvent::Error(error.clone()); 3],
+            ..Default::default()
+        };
+        let config = format!("{}
+            [instrumentation.sampling]
+            one_in = 2
+        ", CONFIG);
+
+        let (_, transport) = run(backend, config.as_str());
+        let records = transport.records.borrow();
+        for record in &records[..3] {
+            assert_eq!(decode::<ErrorRecord>(record, "error"), error);
+        }
+        let stats = decode::<StatsRecord>(&records[3], "stats");
+        assert_eq!((stats.errors, stats.sampled_out), (3, 0));
+    }
+
+    #[test]
+    fn writes_each_cdm_datum_separately() {
+        let probe = ProbeRecord {
+            provider: "syscall".to_string(),
+            function: "open".to_string(),
+            name: "entry".to_string(),
+            values: vec![RecordValue::Int(812), RecordValue::String("/etc/passwd".to_string())],
+            ..Default::default()
+        };
+        let backend = MockBackend {
+            events: vec![MockEvent::Probe(probe); 2],
+            ..Default::default()
+        };
+        let config = format!("{}
+            [instrumentation.cdm]
+            fields = [\"pid\", \"path\"]
+            schema_id = 7
+        ", CONFIG.replace("json", "cdm"));
+
+        // The Host, Principal, Subject, FileObject and Event of the first
+        // firing, then the Event of the second, each framed with the schema id
+        let (result, transport) = run(backend, config.as_str());
+        assert!(result.is_ok());
+        let records = transport.records.borrow();
+        assert_eq!(records.len(), 6);
+        for record in records.iter() {
+            assert_eq!(&record[..5], &[0, 0, 0, 0, 7]);
+        }
+    }
+
+    fn count(name: &str, key: &str, value: i64) -> AggregationEntry {
+        AggregationEntry {
+            name: name.to_string(),
+            function: "count".to_string(),
+            keys: vec![RecordValue::String(key.to_string())],
+            value: value,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn reports_aggregations_before_stopping() {
+        let backend = MockBackend {
+            aggregations: vec![count("syscalls", "read", 12), count("syscalls", "write", 3)],
+            ..Default::default()
+        };
+        let config = format!("{}
+            aggregation_interval = 60
+        ", CONFIG);
+
+        let (result, transport) = run(backend.clone(), config.as_str());
+        assert!(result.is_ok());
+        let records = transport.records.borrow();
+        let snapshot = decode::<AggregationSnapshot>(&records[0], "aggregation");
+        assert_eq!(snapshot.entries, backend.aggregations);
+        assert_eq!(snapshot.timestamp, snapshot.interval_end);
+
+        // As text, the header is followed by the output of libdtrace
+        let (_, transport) = run(backend, config.replace("json", "text").as_str());
+        let records = transport.records.borrow();
+        assert!(records[0].starts_with(b"host=db1.example.com "));
+        assert!(String::from_utf8_lossy(&records[0])
+            .contains("ddtrace aggregations: mode=cumulative"));
+        assert_eq!(records[1], b"@syscalls[String(\"read\")] 12\n");
+        assert_eq!(records[2], b"@syscalls[String(\"write\")] 3\n");
+    }
+
+    #[test]
+    fn reports_aggregations_regardless_of_sampling() {
+        let backend = MockBackend {
+            aggregations: vec![count("syscalls", "read", 12), count("syscalls", "write", 3)],
+            ..Default::default()
+        };
+        let config = format!("{}
+            aggregation_interval = 60
+
+            [instrumentation.sampling]
+            probability = 0.0
+        ", CONFIG.replace("json", "text"));
+
+        // Every line printed by libdtrace is forwarded, and none is counted
+        // as sampled out
+        let (_, transport) = run(backend, config.as_str());
+        let records = transport.records.borrow();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[1], b"@syscalls[String(\"read\")] 12\n");
+        assert_eq!(records[2], b"@syscalls[String(\"write\")] 3\n");
+        let stats = decode::<StatsRecord>(&records[3], "stats");
+        assert_eq!((stats.passed, stats.sampled_out), (0, 0));
+    }
+
+    #[test]
+    fn clears_aggregations_reported_as_deltas() {
+        for &(mode, clears) in &[("cumulative", 0), ("delta", 1)] {
+            let backend = MockBackend {
+                aggregations: vec![count("syscalls", "read", 12)],
+                ..Default::default()
+            };
+            let config = format!("{}
+                aggregation_interval = 60
+                aggregation_mode = \"{}\"
+            ", CONFIG, mode);
+
+            let (_, transport) = run(backend.clone(), config.as_str());
+            let records = transport.records.borrow();
+            let envelope: Envelope<AggregationSnapshot> =
+                Encoding::Json.decode(&records[0]).unwrap();
+            let snapshot = envelope.record;
+            assert_eq!(snapshot.mode, mode);
+            // The first interval starts when the script is deployed
+            assert_eq!(snapshot.interval_start, envelope.metadata.run_epoch * 1_000_000);
+            assert!(snapshot.interval_end >= snapshot.interval_start);
+            assert_eq!(*backend.cleared.borrow(), clears);
+        }
+    }
+
+    #[test]
+    fn reports_top_aggregation_keys() {
+        let backend = MockBackend {
+            aggregations: vec![count("syscalls", "close", 1), count("syscalls", "read", 9),
+                count("syscalls", "open", 2), count("syscalls", "write", 7),
+                count("execs", "sshd", 4), count("execs", "cron", 3)],
+            ..Default::default()
+        };
+        let config = format!("{}
+            aggregation_interval = 60
+
+            [instrumentation.aggregations.syscalls]
+            top_n = 2
+
+            [instrumentation.aggregations.execs]
+            sort = \"key\"
+        ", CONFIG);
+
+        let (_, transport) = run(backend, config.as_str());
+        let records = transport.records.borrow();
+        let snapshot = decode::<AggregationSnapshot>(&records[0], "aggregation");
+        let entries: Vec<(&str, i64, u64)> = snapshot.entries.iter()
+            .map(|entry| (entry.name.as_str(), entry.value, entry.others)).collect();
+        assert_eq!(entries, vec![("syscalls", 9, 0), ("syscalls", 7, 0), ("syscalls", 3, 2),
+            ("execs", 3, 0), ("execs", 4, 0)]);
+        assert!(snapshot.entries[2].keys.is_empty());
+    }
+
+    #[test]
+    fn rejects_zero_aggregation_interval() {
+        let config = format!("{}
+            aggregation_interval = 0
+        ", CONFIG);
+        let (result, _) = run(Default::default(), config.as_str());
+        assert_eq!(result.map_err(|e| e.kind()).err(), Some("config"));
+    }
+
+    #[test]
+    fn vetoes_option_changes_beyond_policy() {
+        let backend = MockBackend {
+            events: vec![MockEvent::SetOpt("switchrate".to_string(), 100),
+                MockEvent::SetOpt("bufsize".to_string(), 512 << 20), MockEvent::Exit],
+            ..Default::default()
+        };
+        let config = format!("{}
+            [instrumentation.option_policy]
+            max = {{ bufsize = \"16m\" }}
+        ", CONFIG);
+
+        let (_, transport) = run(backend, config.as_str());
+        let records = transport.records.borrow();
+        let stats = decode::<StatsRecord>(records.last().unwrap(), "stats");
+        assert_eq!(stats.options.get("bufsize"), Some(&(4 << 20)));
+    }
+
+    #[test]
+    fn reports_effective_script_options() {
+        let config = format!("{}
+            [instrumentation.options]
+            bufsize = \"8m\"
+            strsize = 512
+            quiet = true
+            flowindent = false
+        ", CONFIG);
+
+        let (result, transport) = run(MockBackend::default(), config.as_str());
+        assert!(result.is_ok());
+        let records = transport.records.borrow();
+        let stats = decode::<StatsRecord>(records.last().unwrap(), "stats");
+        assert_eq!(stats.options.get("bufsize"), Some(&(8 << 20)));
+        assert_eq!(stats.options.get("strsize"), Some(&512));
+        assert_eq!(stats.options.get("quiet"), Some(&0));
+        assert_eq!(stats.options.get("flowindent"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_script_options() {
+        let config = format!("{}
+            [instrumentation.options]
+            switchrate = \"often\"
+        ", CONFIG);
+
+        let (result, _) = run(MockBackend::default(), config.as_str());
+        assert_eq!(result.unwrap_err().kind(), "setopt");
+    }
+
+    #[test]
+    fn attaches_target_processes_until_stopped() {
+        let backend = MockBackend::default();
+        let config = format!("{}\ntarget_pid = 4242", CONFIG);
+        let (result, _) = run(backend.clone(), config.as_str());
+        assert!(result.is_ok());
+        assert_eq!(*backend.processes.borrow(), vec!["grab 4242", "continue", "release"]);
+
+        let backend = MockBackend::default();
+        let config = format!("{}\ncommand = ['/usr/bin/make', '-j4']", CONFIG);
+        let (result, _) = run(backend.clone(), config.as_str());
+        assert!(result.is_ok());
+        assert_eq!(*backend.processes.borrow(),
+            vec!["create /usr/bin/make -j4", "continue", "release"]);
+
+        let config = format!("{}\ntarget_pid = 4242\ncommand = ['/usr/bin/make']", CONFIG);
+        let (result, _) = run(MockBackend::default(), config.as_str());
+        assert_eq!(result.unwrap_err().kind(), "config");
+    }
+
+    #[test]
+    fn reports_compile_errors_with_location() {
+        let error = CompileError {
+            message: "line 3: syntax error near \"}\"".to_string(),
+            tag: Some("D_SYNTAX".to_string()),
+            file: None,
+            line: Some(3),
+        };
+        let backend = MockBackend {
+            compile_error: Some(error.clone()),
+            ..Default::default()
+        };
+
+        let (result, transport) = run(backend, CONFIG);
+        let failure = result.unwrap_err();
+        assert_eq!(failure.kind(), "compile");
+        assert_eq!(failure.compile_error(), Some(&error));
+        assert!(transport.records.borrow().is_empty());
+        assert!(*transport.closed.borrow());
+    }
+
+    #[test]
+    fn validates_without_enabling_probes() {
+        let info = ProgramInfo {
+            matches: 1042,
+            aggregates: 1,
+            description: Attributes {
+                name: "Evolving".to_string(),
+                data: "Evolving".to_string(),
+                class: "Common".to_string(),
+            },
+            ..Default::default()
+        };
+        let backend = MockBackend {
+            info: info.clone(),
+            ..Default::default()
+        };
+
+        let result = validate(&backend, endpoint(), "syscalls".to_string(), CONFIG.to_string());
+        assert_eq!(result.unwrap(), info);
+        assert!(!*backend.executed.borrow());
+    }
+
+    #[test]
+    fn reports_invalid_configuration() {
+        let config = CONFIG.replace("json", "yaml");
+        let (result, _) = run(MockBackend::default(), config.as_str());
+        assert_eq!(result.unwrap_err().kind(), "config");
+    }
+
+    #[test]
+    fn fails_script_whose_transport_cannot_be_opened() {
+        let backend = MockBackend::default();
+        let (_tx, rx) = mpsc::channel();
+        let open = |plugin: &str, _: &str| Err(format!("failed loading {}", plugin));
+        let config = format!("{}\ntransport = \"libddtrace_tpc.so\"", CONFIG);
+        let result = instrument(&backend, &open, endpoint(), "syscalls".to_string(),
+            config, rx);
+        assert_eq!(result.unwrap_err().kind(), "transport");
+        assert!(backend.compiled.borrow().is_empty());
+    }
+}
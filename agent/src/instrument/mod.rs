@@ -34,25 +34,154 @@ extern crate toml;
 extern crate libc;
 extern crate libloading;
 extern crate sysctl;
+extern crate rand;
+extern crate ddtrace_record;
 
-use std::ffi::{CString, CStr};
-use self::libdtrace::dtrace_workstatus_t;
+use std::ffi::CString;
+use std::collections::{BTreeMap, HashMap};
 use std::default::Default;
 use std::sync::mpsc;
 use std::os::raw::c_char;
-use std::str::from_utf8;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rustc_serialize::{Decodable, Decoder};
+use self::ddtrace_record::{AggregationSnapshot, DropRecord, Encoding, Envelope,
+    ErrorRecord, Metadata, ProbeRecord, ProcessRecord, Record, RecordValue, StatsRecord};
+use self::ddtrace_record::cdm::{CdmEncoder, Uuid};
+use self::dof::DofCache;
+use self::drops::{BufferSizes, DropConfig, DropMonitor};
+use self::error::DTraceError;
+pub use self::catalog::{ProbeCatalog, ProbeDescription, ProbePattern};
+pub use self::error::{CompileError, InstrumentationError};
+pub use self::program::{Attributes, ProgramInfo};
+use self::backend::{Backend, DTraceBackend, Session, WorkStatus};
+use self::options::{DTRACEOPT_UNSET, OptionPolicy, OptionPolicyConfig, OptionValue};
+use self::aggregate::{AggregationConfig, AggregationMode, AggregationReporter};
+use self::ratelimit::{RateLimitConfig, RecordFilter, SamplingConfig};
+use self::program::Program;
+use self::process::Target;
+use self::record::OutputFormat;
+use self::status::ScriptStatus;
+use self::symbols::{StackFormat, Symbolizer};
 
+mod aggregate;
+mod args;
+mod backend;
+mod catalog;
+mod dof;
+mod drops;
+mod error;
+mod handle;
 mod libdtrace;
+#[cfg(test)]
+mod mock;
+mod options;
+mod process;
+mod program;
+mod ratelimit;
+mod record;
+mod status;
+mod symbols;
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug)]
 struct Config {
     instrumentation: Option<Instrumentation>,
 }
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug)]
 struct Instrumentation {
     comment: Option<String>,
     script: Option<String>,
+    // Further fragments of the D program, and library files it includes
+    fragments: Option<Vec<String>>,
+    includes: Option<Vec<String>>,
+    transport: Option<String>,
+    format: Option<String>,
+    cdm: Option<CdmConfig>,
+    rate_limit: Option<RateLimitConfig>,
+    sampling: Option<SamplingConfig>,
+    stats_interval: Option<u64>,
+    aggregation_interval: Option<u64>,
+    aggregation_mode: Option<String>,
+    aggregations: Option<HashMap<String, AggregationConfig>>,
+    stack_format: Option<String>,
+    drops: Option<DropConfig>,
+    stop_on_exit: Option<bool>,
+    // Process traced by the pid provider and USDT probes ($target): a
+    // running process, or a command launched for the script
+    target_pid: Option<i32>,
+    command: Option<Vec<String>>,
+    option_policy: Option<OptionPolicyConfig>,
+    options: Option<BTreeMap<String, OptionValue>>,
+    // Macro arguments $1..$n and preprocessor defines, subject to the
+    // built-in substitutions
+    args: Option<Vec<String>>,
+    defines: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug)]
+struct CdmConfig {
+    fields: Option<Vec<String>>,
+    schema_id: Option<u32>,
+}
+
+// The script's configuration is decoded field by field, as
+// #[derive(RustcDecodable)] did before current compilers dropped it
+impl Decodable for Config {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Config, D::Error> {
+        d.read_struct("Config", 1, |d| Ok(Config {
+            instrumentation: d.read_struct_field("instrumentation", 0, Decodable::decode)?,
+        }))
+    }
+}
+
+impl Decodable for Instrumentation {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Instrumentation, D::Error> {
+        d.read_struct("Instrumentation", 22, |d| Ok(Instrumentation {
+            comment: d.read_struct_field("comment", 0, Decodable::decode)?,
+            script: d.read_struct_field("script", 1, Decodable::decode)?,
+            fragments: d.read_struct_field("fragments", 2, Decodable::decode)?,
+            includes: d.read_struct_field("includes", 3, Decodable::decode)?,
+            transport: d.read_struct_field("transport", 4, Decodable::decode)?,
+            format: d.read_struct_field("format", 5, Decodable::decode)?,
+            cdm: d.read_struct_field("cdm", 6, Decodable::decode)?,
+            rate_limit: d.read_struct_field("rate_limit", 7, Decodable::decode)?,
+            sampling: d.read_struct_field("sampling", 8, Decodable::decode)?,
+            stats_interval: d.read_struct_field("stats_interval", 9, Decodable::decode)?,
+            aggregation_interval:
+                d.read_struct_field("aggregation_interval", 10, Decodable::decode)?,
+            aggregation_mode: d.read_struct_field("aggregation_mode", 11, Decodable::decode)?,
+            aggregations: d.read_struct_field("aggregations", 12, Decodable::decode)?,
+            stack_format: d.read_struct_field("stack_format", 13, Decodable::decode)?,
+            drops: d.read_struct_field("drops", 14, Decodable::decode)?,
+            stop_on_exit: d.read_struct_field("stop_on_exit", 15, Decodable::decode)?,
+            target_pid: d.read_struct_field("target_pid", 16, Decodable::decode)?,
+            command: d.read_struct_field("command", 17, Decodable::decode)?,
+            option_policy: d.read_struct_field("option_policy", 18, Decodable::decode)?,
+            options: d.read_struct_field("options", 19, Decodable::decode)?,
+            args: d.read_struct_field("args", 20, Decodable::decode)?,
+            defines: d.read_struct_field("defines", 21, Decodable::decode)?,
+        }))
+    }
+}
+
+impl Decodable for CdmConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<CdmConfig, D::Error> {
+        d.read_struct("CdmConfig", 2, |d| Ok(CdmConfig {
+            fields: d.read_struct_field("fields", 0, Decodable::decode)?,
+            schema_id: d.read_struct_field("schema_id", 1, Decodable::decode)?,
+        }))
+    }
+}
+
+// Identifies the endpoint and agent running the instrumentation
+pub struct Endpoint {
+    pub hostname: String,
+    // Directory of the D files scripts may include
+    pub library: String,
+    // Directory of the cached compiled programs (if they're cached)
+    pub cache: Option<String>,
+    pub agent_name: &'static str,
+    pub agent_version: &'static str,
 }
 
 #[derive(PartialEq)]
@@ -60,6 +189,14 @@ pub enum InstrumentationThreadMessage {
    Stop,
 }
 
+// Sends an instrumentation's records upstream
+trait Transport {
+    fn write(&self, data: &[u8]) -> i32;
+    fn flush(&self) -> i32;
+    fn close(&mut self) -> i32;
+}
+
+// Transport implemented by a dt_transport_* plugin
 struct TransportBridge {
     handle: i32,
     lib: libloading::Library,
@@ -68,32 +205,47 @@ struct TransportBridge {
 // TODO replace fix handle values with those returns by dylib
 impl TransportBridge {
 		   
-    fn new(transport_plugin: &'static str) -> TransportBridge {
+    fn new(transport_plugin: &str) -> Result<TransportBridge, String> {
 
-       TransportBridge {
-          handle: -1,
-	      lib: libloading::Library::new(transport_plugin).unwrap(),
+       match libloading::Library::new(transport_plugin) {
+          Ok(lib) => Ok(TransportBridge {
+             handle: -1,
+             lib: lib,
+          }),
+          Err(e) => Err(format!("failed loading {}: {}", transport_plugin, e)),
        }
     }
 
-    fn close(&self) -> i32 {
-      trace!("close()");
+    fn open(&mut self, config: &str) -> Result<(), String> {
+      trace!("open()");
+      let config_c = match CString::new(config) {
+          Ok(config_c) => config_c,
+          Err(_) => return Err("configuration contains a NUL byte".to_string()),
+      };
       unsafe {
-           if let Ok(close_func) = self.lib.get::<libloading::Symbol<unsafe extern fn(i32) -> i32>>(DT_CLOSE_FCN) {
-               close_func(self.handle)
+           if let Ok(open_func) =
+               self.lib.get::<libloading::Symbol<unsafe extern fn(* const c_char) -> i32>>(DT_OPEN_FCN) {
+               let handle = open_func(config_c.as_ptr());
+               if handle < 0 {
+                   return Err(format!("dt_transport_open failed ({})", handle));
+               }
+               self.handle = handle;
+               Ok(())
            } else {
-               -1
+               Err("missing dt_transport_open".to_string())
            }
        }
     }
+}
 
-    fn open(&mut self, config: &str) -> i32 {
-      trace!("open()");
+impl Transport for TransportBridge {
+    fn close(&mut self) -> i32 {
+      trace!("close()");
       unsafe {
-           if let Ok(open_func) =
-               self.lib.get::<libloading::Symbol<unsafe extern fn(* const c_char) -> i32>>(DT_OPEN_FCN) {
-               self.handle = open_func(CString::new(config).unwrap().as_ptr());
-               0
+           if let Ok(close_func) = self.lib.get::<libloading::Symbol<unsafe extern fn(i32) -> i32>>(DT_CLOSE_FCN) {
+               let status = close_func(self.handle);
+               self.handle = -1;
+               status
            } else {
                -1
            }
@@ -101,7 +253,7 @@ impl TransportBridge {
     }
 
     fn write(&self, data: &[u8]) -> i32 {
-       trace!("write({:?})", from_utf8(data).unwrap());
+       trace!("write({} bytes)", data.len());
        unsafe {
            if let Ok(write_func) =
                self.lib.get::<libloading::Symbol<unsafe extern fn(i32, &[u8]) -> i32>>(DT_WRITE_FCN) {
@@ -116,8 +268,8 @@ impl TransportBridge {
        trace!("flush() {}", self.handle);
        unsafe {
            if let Ok(flush_func) =
-               self.lib.get::<libloading::Symbol<unsafe extern fn(i32) -> i32>>(b"dt_transport_flush") {
-               flush_func(100) //self.handle)
+               self.lib.get::<libloading::Symbol<unsafe extern fn(i32) -> i32>>(DT_FLUSH_FCN) {
+               flush_func(self.handle)
            } else {
                -1
            }
@@ -125,337 +277,857 @@ impl TransportBridge {
     }
 }
 
-const DT_OPEN_FCN: &'static[u8] = b"dt_transport_open";
-const DT_CLOSE_FCN: &'static[u8] = b"dt_transport_close";
-const DT_WRITE_FCN: &'static[u8] = b"dt_transport_write";
-const DT_FLUSH_FCN: &'static[u8] = b"dt_transport_flush";
+// State shared with the tracing session's consumer callbacks
+struct ConsumerContext {
+    transport: Box<Transport>,
+    filter: RecordFilter,
+    format: OutputFormat,
+    cdm: Option<CdmEncoder>,
+    metadata: Metadata,
+    aggregations: Option<AggregationReporter>,
+    symbolizer: Symbolizer,
+    status: ScriptStatus,
+    // Stats most recently reported upstream
+    reported: StatsRecord,
+    drops: DropMonitor,
+    sizes: BufferSizes,
+    // Buffer sizes the tracing is to be restarted with
+    restart: Option<BufferSizes>,
+    // Operator policy on the options the D program may change
+    policy: OptionPolicy,
+    // The D program with the options and arguments it's compiled with
+    program: Program,
+    // Cache of compiled programs, reused on restarts and redeployments
+    cache: Option<DofCache>,
+    // Process grabbed or created for the script ($target)
+    target: Option<Target>,
+    // Stop tracing once the script's target processes are gone
+    stop_on_exit: bool,
+    // Set when tracing is to be stopped at the script's request
+    stopped: bool,
+    pending: Option<ProbeRecord>,
+    // Sampling and rate limit decision for the current probe firing, made
+    // once so that all of its output is either forwarded or discarded
+    firing: Option<bool>,
+    // Whether the next text output starts a new record
+    new_record: bool,
+}
 
-// libdtrace constants;
-const EINTR: i32 = 9959;
+impl ConsumerContext {
+    // Write a record upstream, subject to the instrumentation's
+    // sampling and rate limit (decided once per probe firing)
+    fn write(&mut self, data: &[u8]) -> i32 {
+        let accepted = match self.firing {
+            Some(accepted) => accepted,
+            None => self.filter.accept(),
+        };
+        if accepted {
+            self.transport.write(data)
+        } else {
+            0
+        }
+    }
 
-const DTRACE_CONSUME_NEXT: i32 = 1;
-const DTRACE_CONSUME_THIS: i32 = 0;
+    // Consume a probe firing passed to dtrace_work()'s probe callback
+    unsafe fn consume_probe(&mut self, data: *const libdtrace::dtrace_probedata_t) -> i32 {
+        let record = if self.format != OutputFormat::Text {
+            Some(record::probe_record(data))
+        } else {
+            None
+        };
+        self.begin_firing(record);
+        record::DTRACE_CONSUME_THIS
+    }
 
-fn dtrace_open(version: i32, flags: i32)
-   -> (*mut self::libdtrace::dtrace_hdl_t, i32) {
+    // Consume one of the firing's records passed to dtrace_work()'s record
+    // callback (rec is null after the last of them), finishing the firing
+    // after its last record or exit()
+    unsafe fn consume_record(&mut self, session: &Session,
+        data: *const libdtrace::dtrace_probedata_t,
+        rec: *const libdtrace::dtrace_recdesc_t) -> i32 {
 
-   let mut err: libc::c_int = 0;    
-   let handle = unsafe {
-      self::libdtrace::dtrace_open(version, flags, &mut err)
-   };
-   (handle, err)
-}
+        if !rec.is_null() {
+            trace!("record action = {}", (* rec).dtrd_action);
+            if self.format != OutputFormat::Text {
+                if let Some(value) = record::decode_record(data, rec) {
+                    self.add_value(session, value);
+                }
+            }
+            if (* rec).dtrd_action != record::DTRACEACT_EXIT {
+                return record::DTRACE_CONSUME_THIS;
+            }
+        }
 
-fn dtrace_setopt(handle: *mut self::libdtrace::dtrace_hdl_t,
-    opt: &'static str, val: &'static str) {
+        // Send the firing upstream
+        self.end_probe();
+        self.flush();
+        record::DTRACE_CONSUME_NEXT
+    }
 
-    unsafe {
-        self::libdtrace::dtrace_setopt(handle,
-            CString::new(opt).unwrap().as_ptr(),
-            CString::new(val).unwrap().as_ptr());
+    // Start handling a probe firing, deciding whether its output is
+    // forwarded upstream
+    fn begin_firing(&mut self, record: Option<ProbeRecord>) {
+        if let Some(record) = record {
+            self.begin_probe(record);
+        }
+        self.firing = Some(self.filter.accept());
     }
-}
 
-fn dtrace_errmsg(handle: *mut self::libdtrace::dtrace_hdl_t, err: i32)
-    -> String {
+    fn flush(&self) -> i32 {
+        self.transport.flush()
+    }
 
-    unsafe {
-        CStr::from_ptr(self::libdtrace::dtrace_errmsg(handle, err))
-             .to_string_lossy().into_owned()
+    fn envelope<T: Record>(&self, record: T) -> Envelope<T> {
+        Envelope::new(self.metadata.clone(), now_nanos(), record)
     }
-}
 
-fn dtrace_program_strcompile(handle: *mut self::libdtrace::dtrace_hdl_t,
-    script: & str, spec: self::libdtrace::dtrace_probespec_t, cflags: u32)
-    -> *mut self::libdtrace::dtrace_prog_t {
+    // Prefix identifying the origin of a text record
+    fn text_prefix(&self) -> String {
+        let metadata = &self.metadata;
+        format!("host={} hostuuid={} agent={}/{} script={} epoch={} time={} ",
+            metadata.hostname, metadata.hostuuid, metadata.agent_name,
+            metadata.agent_version, metadata.script_id, metadata.run_epoch,
+            now_nanos())
+    }
 
-    if let sysctl::CtlValue::String(hostuuid) =
-        sysctl::value("kern.hostuuid").unwrap() {
-        let args = vec!(CString::new(format!("\"{}\"", hostuuid)).unwrap());
-        let c_args = args.iter().map(|arg| arg.as_ptr()).collect::<Vec<*const c_char>>();
-        unsafe {
-            self::libdtrace::dtrace_program_strcompile(handle,
-                CString::new(script).unwrap().as_ptr(), spec,
-                cflags, c_args.len() as ::std::os::raw::c_int, c_args.as_ptr())
+    // Start a new structured record for a probe firing
+    fn begin_probe(&mut self, record: ProbeRecord) {
+        if self.pending.is_some() {
+            self.end_probe();
         }
-    } else {
-unsafe {
-            self::libdtrace::dtrace_program_strcompile(handle,
-                CString::new(script).unwrap().as_ptr(), spec,
-                cflags, 0, ::std::ptr::null())
+        self.pending = Some(record);
+    }
+
+    fn add_value(&mut self, session: &Session, value: RecordValue) {
+        if let Some(ref mut record) = self.pending {
+            record.values.push(self.symbolizer.symbolize(session, value));
         }
     }
-}
-    
-fn dtrace_program_exec(handle: *mut self::libdtrace::dtrace_hdl_t,
-    prog: *mut self::libdtrace::dtrace_prog_t,
-    info: *mut self::libdtrace::dtrace_proginfo_t) -> i32 {
 
-    unsafe {
-        self::libdtrace::dtrace_program_exec(handle, prog, info)
+    // Handle output formatted by libdtrace (printf() and similar)
+    fn write_output(&mut self, output: &[u8]) -> i32 {
+        match self.format {
+            OutputFormat::Text => {
+                if self.new_record {
+                    self.new_record = false;
+                    let mut prefixed = self.text_prefix().into_bytes();
+                    prefixed.extend_from_slice(output);
+                    self.write(&prefixed)
+                } else {
+                    self.write(output)
+                }
+            },
+            _ => {
+                let text = String::from_utf8_lossy(output);
+                match self.pending {
+                    Some(ref mut record) => record.output.push_str(&text),
+                    None => {
+                        // Output not associated with a probe firing, handled
+                        // as a firing of its own
+                        let mut record: ProbeRecord = Default::default();
+                        record.output.push_str(&text);
+                        self.begin_firing(Some(record));
+                        self.end_probe();
+                    }
+                }
+                0
+            }
+        }
     }
-}
 
-fn dtrace_go(handle: *mut self::libdtrace::dtrace_hdl_t) -> i32 {
-    unsafe {
-        self::libdtrace::dtrace_go(handle)
+    // Finish handling the current probe firing
+    fn end_probe(&mut self) {
+        self.new_record = true;
+        self.write_probe();
+        self.firing = None;
     }
-}
 
-fn dtrace_work(handle: *mut self::libdtrace::dtrace_hdl_t,
-   fp: *mut self::libdtrace::__sFILE,
-   pfunc: self::libdtrace::dtrace_consume_probe_f,
-   rfunc: self::libdtrace::dtrace_consume_rec_f,
-   arg: *mut ::std::os::raw::c_void) -> self::libdtrace::dtrace_workstatus_t {
-    unsafe {
-        self::libdtrace::dtrace_work(handle, fp, pfunc, rfunc, arg)
+    // Encode the structured record for the current probe firing and send
+    // it upstream
+    fn write_probe(&mut self) {
+        if let Some(record) = self.pending.take() {
+            let envelope = self.envelope(record);
+            match self.format {
+                OutputFormat::Structured(encoding) => {
+                    match encoding.encode(&envelope) {
+                        Ok(encoded) => {
+                            self.write(&encoded);
+                        },
+                        Err(e) => {
+                            error!("failed encoding record as {}: {}", encoding.name(), e);
+                        }
+                    }
+                },
+                OutputFormat::Cdm => {
+                    let encoded = match self.cdm {
+                        Some(ref mut encoder) => encoder.encode_envelope(&envelope),
+                        None => return,
+                    };
+                    match encoded {
+                        // Each datum is a message of its own
+                        Ok(datums) => for datum in &datums {
+                            self.write(datum);
+                        },
+                        Err(e) => {
+                            error!("failed encoding record as CDM: {}", e);
+                        }
+                    }
+                },
+                OutputFormat::Text => {}
+            }
+        }
     }
-}
 
-fn dtrace_sleep(handle: *mut self::libdtrace::dtrace_hdl_t) {
-    unsafe {
-        self::libdtrace::dtrace_sleep(handle)
+    // Send a runtime fault upstream and count it in the script's status
+    // (errors aren't subject to the sampling and rate limit)
+    fn write_error(&mut self, record: ErrorRecord) {
+        self.status.errors += 1;
+        match self.format {
+            OutputFormat::Text => {
+                let line = format!("{}ddtrace error: {}\n", self.text_prefix(), record.message);
+                self.transport.write(line.as_bytes());
+            },
+            OutputFormat::Structured(encoding) => {
+                let envelope = self.envelope(record);
+                match encoding.encode(&envelope) {
+                    Ok(encoded) => {
+                        self.transport.write(&encoded);
+                    },
+                    Err(e) => {
+                        error!("failed encoding error as {}: {}", encoding.name(), e);
+                    }
+                }
+            },
+            OutputFormat::Cdm => {
+                // CDM has no record for DTrace runtime errors
+                debug!("{} error: {}", self.metadata.script_id, record.message);
+            }
+        }
+        self.transport.flush();
     }
-}
 
-fn dtrace_stop(handle: *mut self::libdtrace::dtrace_hdl_t) -> i32 {
-    unsafe {
-        self::libdtrace::dtrace_stop(handle)
+    // Send drops reported by DTrace upstream and count them in the
+    // script's status (drop events aren't subject to the sampling and rate
+    // limit)
+    fn write_drop(&mut self, record: DropRecord) {
+        *self.status.drops.entry(record.kind.clone()).or_insert(0) += record.count;
+        match self.format {
+            OutputFormat::Text => {
+                let line = format!("{}ddtrace drop: {}\n", self.text_prefix(), record.message);
+                self.transport.write(line.as_bytes());
+            },
+            OutputFormat::Structured(encoding) => {
+                match encoding.encode(&self.envelope(record)) {
+                    Ok(encoded) => {
+                        self.transport.write(&encoded);
+                    },
+                    Err(e) => {
+                        error!("failed encoding drop as {}: {}", encoding.name(), e);
+                    }
+                }
+            },
+            OutputFormat::Cdm => {
+                // CDM has no record for DTrace drops
+                warn!("{} {}", self.metadata.script_id, record.message);
+            }
+        }
+        self.transport.flush();
     }
-}
 
-fn dtrace_close(handle: *mut self::libdtrace::dtrace_hdl_t) {
-    unsafe {
-        self::libdtrace::dtrace_close(handle)
+    // Send a traced process's lifecycle event upstream and record it in
+    // the script's status (process events aren't subject to the sampling
+    // and rate limit)
+    fn write_process(&mut self, record: ProcessRecord) {
+        self.status.processes.insert(record.pid, record.event.clone());
+        match self.format {
+            OutputFormat::Text => {
+                let line = format!("{}ddtrace process: pid={} event={} exit_status={} signal={} {}\n",
+                    self.text_prefix(), record.pid, record.event, record.exit_status,
+                    record.signal, record.message);
+                self.transport.write(line.as_bytes());
+            },
+            OutputFormat::Structured(encoding) => {
+                match encoding.encode(&self.envelope(record.clone())) {
+                    Ok(encoded) => {
+                        self.transport.write(&encoded);
+                    },
+                    Err(e) => {
+                        error!("failed encoding process event as {}: {}", encoding.name(), e);
+                    }
+                }
+            },
+            OutputFormat::Cdm => {
+                // CDM has no record for DTrace process notifications
+                info!("{} pid {} {} {}", self.metadata.script_id, record.pid,
+                    record.event, record.message);
+            }
+        }
+        self.transport.flush();
+
+        if self.stop_on_exit && self::process::is_gone(record.event.as_str()) &&
+            self.status.processes.values().all(|event| self::process::is_gone(event)) {
+            info!("{} target processes are gone, stopping", self.metadata.script_id);
+            self.stopped = true;
+        }
     }
-}
 
-fn dtrace_errno(handle: *mut self::libdtrace::dtrace_hdl_t) -> i32 {
-    unsafe {
-        self::libdtrace::dtrace_errno(handle)
+    // Options whose effective values are recorded in the script's status
+    fn tracked_options(&self) -> Vec<String> {
+        let mut options: Vec<String> = self.sizes.options().into_iter()
+            .map(|(option, _)| option.to_string()).collect();
+        for option in self.program.options.iter().map(|&(ref option, _)| option.as_str())
+            .chain(self.policy.options()) {
+            if !options.iter().any(|tracked| tracked == option) {
+                options.push(option.to_string());
+            }
+        }
+        options
     }
-}
 
-fn dtrace_handle_drop(handle: *mut self::libdtrace::dtrace_hdl_t,
-    hdlr: self::libdtrace::dtrace_handle_drop_f,
-    arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
-    unsafe {
-        self::libdtrace::dtrace_handle_drop(handle, hdlr, arg)
+    // Record the effective value of an option changed by the D program,
+    // restoring its previous value (or its cap) if the policy vetoes the
+    // change
+    fn review_option(&mut self, session: &mut Session, option: &str, old: i64, new: i64) {
+        let value = match self.policy.review(option, old, new) {
+            Some(DTRACEOPT_UNSET) => {
+                warn!("{} can't veto setting unset option {} to {}",
+                    self.metadata.script_id, option, new);
+                new
+            },
+            Some(value) => {
+                warn!("{} vetoed setting option {} to {}, set to {}",
+                    self.metadata.script_id, option, new, value);
+                if let Err(e) = session.setopt(option, value.to_string().as_str()) {
+                    error!("{}", e);
+                }
+                session.getopt(option).unwrap_or(new)
+            },
+            None => new,
+        };
+        self.status.options.insert(option.to_string(), value);
     }
-}
 
-fn dtrace_handle_buffered(handle: *mut self::libdtrace::dtrace_hdl_t,
-    hdlr: self::libdtrace::dtrace_handle_buffered_f,
-    arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
-    unsafe {
-        self::libdtrace::dtrace_handle_buffered(handle, hdlr, arg)
+    // Send the script's counters upstream as a stats record (in JSON
+    // amongst text output)
+    fn write_stats(&self, record: &StatsRecord) {
+        let encoding = match self.format {
+            OutputFormat::Structured(encoding) => encoding,
+            OutputFormat::Text => Encoding::Json,
+            OutputFormat::Cdm => {
+                // CDM has no record for the agent's counters
+                info!("{} stats: passed={} sampled_out={} rate_limited={} errors={}",
+                    self.metadata.script_id, record.passed, record.sampled_out,
+                    record.rate_limited, record.errors);
+                return;
+            }
+        };
+        match encoding.encode(&self.envelope(record.clone())) {
+            Ok(encoded) => {
+                self.transport.write(&encoded);
+                self.transport.flush();
+            },
+            Err(e) => {
+                error!("failed encoding stats as {}: {}", encoding.name(), e);
+            }
+        }
     }
-}
 
-// TODO how to elegantly exit here
-// need to delete script from instrumentation or only add when successfully compiled?
-pub fn instrument_endpoint(script: String,
-    rx: mpsc::Receiver<InstrumentationThreadMessage>) {
+    // Snapshot the script's aggregations and send their contents upstream,
+    // clearing them in delta mode (aggregation reports aren't subject to the
+    // sampling and rate limit). libdtrace passes the text output of the
+    // aggregations to the buffered handler, which writes it through the
+    // registered context, so the context is only reached through it.
+    unsafe fn write_aggregations(context: *mut ConsumerContext, session: &mut Session) {
+        let (mode, (start, end)) = match (*context).aggregations {
+            Some(ref mut reporter) => (reporter.mode(), reporter.end_interval(now_nanos())),
+            None => return,
+        };
 
-    // Parse the configuration file specifying where the DTrace records are to
-    // be sent
-    unsafe {
-        match toml::decode_str::<Config>(script.as_str()) {
-            Some(config) => {
-                let dtrace_version = 3;
-                let flags = 0;
-                let (handle, err) = dtrace_open(dtrace_version, flags);
-                if err != 0 {
-                    error!("dtrace error {} initializing", dtrace_errmsg(handle, err));
-                    return;
-                }
-                info!("dtrace initialized");
-                
-                dtrace_setopt(handle, "bufsize", "4m");
-                dtrace_setopt(handle, "aggsize", "4m");
-                dtrace_setopt(handle, "temporal", "4m");;
-                dtrace_setopt(handle, "arch", "x86_64");;
-                info!("dtrace options set");
-
-                let instr_script = config.instrumentation.unwrap().script.unwrap();
-                let prog = dtrace_program_strcompile(handle, instr_script.as_str(),
-                    self::libdtrace::dtrace_probespec::DTRACE_PROBESPEC_NAME, 0x0080);
-                if prog.is_null() {
-                    error!("failed to compile dtrace program {}",
-                       dtrace_errmsg(handle, dtrace_errno(handle)));
-                    dtrace_close(handle);
-                    return;
+        if let Err(e) = session.aggregate_snap() {
+            error!("{}", e);
+            return;
+        }
+
+        match (*context).format {
+            OutputFormat::Text => {
+                let header = format!(
+                    "{}ddtrace aggregations: mode={} interval_start={} interval_end={}\n",
+                    (*context).text_prefix(), mode.name(), start, end);
+                (*context).transport.write(header.as_bytes());
+
+                // libdtrace formats the aggregations (top_n and sort
+                // settings only apply to structured reports), which are
+                // reported whole regardless of the sampling and rate limit
+                (*context).new_record = false;
+                (*context).firing = Some(true);
+                let printed = session.aggregate_print();
+                (*context).firing = None;
+                (*context).new_record = true;
+                if let Err(e) = printed {
+                    error!("{}", e);
                 }
-                info!("dtrace program compiled");
-
-                let mut info: self::libdtrace::dtrace_proginfo_t = Default::default();
-                let status = dtrace_program_exec(handle, prog, &mut info);
-                if status == -1 {
-                    error!("failed to enable dtrace probes {}",
-                       dtrace_errmsg(handle, dtrace_errno(handle)));
-                    dtrace_close(handle);
+            },
+            OutputFormat::Structured(encoding) => {
+                (*context).write_snapshot(session, encoding, mode, start, end);
+            },
+            OutputFormat::Cdm => {}
+        }
+        (*context).transport.flush();
+
+        if mode == AggregationMode::Delta {
+            session.aggregate_clear();
+        }
+    }
+
+    // Walk the snapshot of the aggregations and send it upstream as a
+    // structured record
+    fn write_snapshot(&mut self, session: &mut Session, encoding: Encoding,
+        mode: AggregationMode, start: u64, end: u64) {
+
+        let unsorted = match session.aggregate_entries(None) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+        let mut sorted = Vec::new();
+        let orders = self.aggregations.as_ref()
+            .map(|reporter| reporter.sort_orders())
+            .unwrap_or_default();
+        for order in orders {
+            match session.aggregate_entries(Some(order)) {
+                Ok(entries) => sorted.push((order, entries)),
+                Err(e) => {
+                    error!("{}", e);
                     return;
                 }
-                info!("dtrace probes enables");
+            }
+        }
+        let mut entries = match self.aggregations {
+            Some(ref reporter) => reporter.select(unsorted, sorted),
+            None => unsorted,
+        };
+        for entry in &mut entries {
+            let keys = ::std::mem::replace(&mut entry.keys, Vec::new());
+            entry.keys = keys.into_iter()
+                .map(|key| self.symbolizer.symbolize(session, key))
+                .collect();
+        }
+
+        let envelope = self.envelope(AggregationSnapshot {
+            timestamp: end,
+            mode: mode.name().to_string(),
+            interval_start: start,
+            interval_end: end,
+            entries: entries,
+        });
+        match encoding.encode(&envelope) {
+            Ok(encoded) => {
+                self.transport.write(&encoded);
+            },
+            Err(e) => {
+                error!("failed encoding aggregations as {}: {}", encoding.name(), e);
+            }
+        }
+    }
+
+    // Report the aggregations upstream if the reporting interval has elapsed
+    unsafe fn report_aggregations(context: *mut ConsumerContext, session: &mut Session) {
+        let due = match (*context).aggregations {
+            Some(ref reporter) => reporter.due(),
+            None => false,
+        };
+        if due {
+            ConsumerContext::write_aggregations(context, session);
+        }
+    }
+
+    // Report the filter's counters and the script's errors upstream if the
+    // reporting interval has elapsed and records have been discarded or
+    // errors raised since the last report
+    fn report_stats(&mut self) {
+        if let Some(stats) = self.filter.report_due() {
+            let record = self.status.stats_record(stats);
+            if self::status::is_news(&record, &self.reported) {
+                self.write_stats(&record);
+                self.reported = record;
+            }
 
-                if dtrace_go(handle) != 0 {
-                    error!("could not start dtrace instrumentation {}",
-                        dtrace_errmsg(handle, dtrace_errno(handle)));
-                    dtrace_close(handle);
+            if let Some(sizes) = self.drops.interval(&self.status.drops, &self.sizes) {
+                if !self.target.as_ref().map_or(true, |target| target.restartable()) {
+                    warn!("{} drops persist, not restarting as it kills the command",
+                        self.metadata.script_id);
                     return;
                 }
-                info!("dtrace instrumentation started...");
-               
-                let mut handler = TransportBridge::new(
-                    //"../transport/unix_socket/target/debug/libddtrace_unix_socket.so");
-                    "../transport/tcp/target/debug/libddtrace_tcp.so");
-                handler.open(script.as_str());
-
-                unsafe {
-                    if dtrace_handle_drop(handle, drop_handler,
-                       ::std::ptr::null_mut()) == -1 {
-                        error!("failed to register dtrace drop handler");
-                        dtrace_close(handle);
-                        return;
-                    }
+                info!("{} drops persist, restarting with {:?}", self.metadata.script_id, sizes);
+                self.restart = Some(sizes);
+            }
+        }
+    }
+}
 
-                    let lib_ptr: *mut ::std::os::raw::c_void =
-                        &mut handler as *mut _ as *mut ::std::os::raw::c_void;
-                    if dtrace_handle_buffered(handle, buffered_handler, lib_ptr) == -1 {
-                        error!("failed to register dtrace buffered handler");
-                        dtrace_close(handle);
-                        return;
-                    }
-                
-                    let mut done = false;
-                    while {
-                        if done == false {
-                           dtrace_sleep(handle);
-                        }
+// The script's transport is closed with its context
+impl Drop for ConsumerContext {
+    fn drop(&mut self) {
+        self.transport.close();
+    }
+}
 
-                        trace!("dtrace work...");
-                        match dtrace_work(handle, ::std::ptr::null_mut(),
-                            chew, chewrec, lib_ptr ){
-//                            handle as *mut ::std::os::raw::c_void) {
-                            dtrace_workstatus_t::DTRACE_WORKSTATUS_ERROR => {
-                                if dtrace_errno(handle) != EINTR {
-                                    error!("dtrace_work failed {}",
-                                           dtrace_errmsg(handle,
-                                           dtrace_errno(handle)));
-                                    done = true;
-                                }
-                            },
-                            dtrace_workstatus_t::DTRACE_WORKSTATUS_OKAY => {
-                                done = false;
-                            },
-                            dtrace_workstatus_t::DTRACE_WORKSTATUS_DONE => {
-                                done = true;
-                            }
-                        }
+const DT_OPEN_FCN: &'static[u8] = b"dt_transport_open";
+const DT_CLOSE_FCN: &'static[u8] = b"dt_transport_close";
+const DT_WRITE_FCN: &'static[u8] = b"dt_transport_write";
+const DT_FLUSH_FCN: &'static[u8] = b"dt_transport_flush";
 
-                        done = match rx.try_recv() {
-                            Ok(ref msg) if *msg == InstrumentationThreadMessage::Stop => {
-                                true
-                            },
-                            Ok(_) => {
-                                done
-                            },
-                            Err(error) if error == mpsc::TryRecvError::Empty => {
-                                done
-                            },
-                            Err(error) => {
-                                error!("{}", error);
-                                false
-                            },
-                        };
-
-                        done == false
-                    } {}
-                }
+// Transport plugin used when the instrumentation doesn't specify one
+const DEFAULT_TRANSPORT_PLUGIN: &'static str =
+    "../transport/tcp/target/debug/libddtrace_tcp.so";
 
-                // Close the transport handler
-                handler.close();
+// Returns the endpoint's kern.hostuuid
+fn hostuuid() -> Option<String> {
+    match sysctl::value("kern.hostuuid") {
+        Ok(sysctl::CtlValue::String(hostuuid)) => Some(hostuuid),
+        _ => None,
+    }
+}
 
-                info!("dtrace stopping");
-                dtrace_stop(handle);
+// Returns the current wall-clock time in nanoseconds since the epoch
+fn now_nanos() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64
+}
 
-                info!("dtrace closing");
-                dtrace_close(handle);
-            },
-            None => {
-                error!("Failed decoding TOML config");
-                return;
-            }
-        }
+// Run the instrumentation script until it exits or is stopped, returning
+// the reason it failed (if it did)
+pub fn instrument_endpoint(endpoint: Endpoint, script_id: String, script: String,
+    rx: mpsc::Receiver<InstrumentationThreadMessage>) -> Result<(), InstrumentationError> {
+
+    let result = instrument(&DTraceBackend, &open_transport, endpoint, script_id, script, rx);
+    if let Err(ref e) = result {
+        error!("{}", e);
     }
+    result
 }
 
-unsafe extern fn drop_handler(
-   data : *const self::libdtrace::dtrace_dropdata_t,
-   arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
-   
-   error!("{:?}", data);
-   0 // DTRACE_HANDLE_OK
+// Enumerate the endpoint's probes
+pub fn probe_catalog() -> Result<ProbeCatalog, InstrumentationError> {
+    let session = DTraceBackend.open()?;
+    Ok(ProbeCatalog::new(&*session))
 }
 
-unsafe extern fn buffered_handler(
-   bufdata : *const self::libdtrace::dtrace_bufdata_t,
-   arg: *mut ::std::os::raw::c_void) -> ::std::os::raw::c_int {
-       
-   // Write the records upstream using the specified transport handler
-   let handler = arg as *const TransportBridge;
-   (* handler).write(CStr::from_ptr((* bufdata).dtbda_buffered).to_bytes())
+// Publish the endpoint's probe catalog, refreshing it when kernel modules
+// are loaded or unloaded, until stopped
+pub fn watch_probe_catalog(interval: Duration, publish: &Fn(&ProbeCatalog),
+    rx: mpsc::Receiver<InstrumentationThreadMessage>) -> Result<(), InstrumentationError> {
+
+    self::catalog::watch(&DTraceBackend, interval, publish, &rx)
+        .map_err(InstrumentationError::from)
 }
-       
-unsafe extern fn chew(data: *const self::libdtrace::dtrace_probedata_t,
-    arg: *mut ::std::os::raw::c_void) -> i32 {
-    
-    info!("chew");
-
-    let pd: *mut self::libdtrace::dtrace_probedesc_t = (* data).dtpda_pdesc;
-    //trace!("id = {}", (* pd).dtpd_id); timestamp
-
-    //trace!("id = {}", (* data).processorid_t); cpu
-    trace!("id = {}", (* pd).dtpd_id);
-    trace!("func = {:?}", CStr::from_ptr((* pd).dtpd_func.as_ptr()));
-    trace!("name = {:?}", CStr::from_ptr((* pd).dtpd_name.as_ptr()));
-
-    // Consume this record - DTRACE_CONSUME_THIS
-    return DTRACE_CONSUME_THIS;
+
+// Compile the instrumentation script without enabling its probes,
+// describing the compiled program (or the reason it failed to compile)
+pub fn validate_script(endpoint: Endpoint, script_id: String, script: String)
+    -> Result<ProgramInfo, InstrumentationError> {
+
+    validate(&DTraceBackend, endpoint, script_id, script)
 }
 
-unsafe extern fn chewrec(_data: *const self::libdtrace::dtrace_probedata_t,
-    rec: *const self::libdtrace::dtrace_recdesc_t,
-    arg: *mut ::std::os::raw::c_void) -> i32 {
+fn validate(backend: &Backend, endpoint: Endpoint, script_id: String, script: String)
+    -> Result<ProgramInfo, InstrumentationError> {
 
-    trace!("chewing DTrace record");
+    let instrumentation = decode_instrumentation(script.as_str())?;
+    let program = match Program::new(&instrumentation, &endpoint,
+        hostuuid().as_ref().map(|uuid| uuid.as_str()), script_id.as_str()) {
+        Ok(program) => program,
+        Err(e) => return Err(InstrumentationError::Config(e)),
+    };
 
-    if rec.is_null() {
-        // Consume next record - DTRACE_CONSUME_NEXT
-        trace!("consume next");
-       
-        // Flush the records upstream using the specified transport handler
-        let handler = arg as *const TransportBridge;
-        (* handler).flush();
+    let mut session = backend.open()?;
+    program.set_options(&mut *session, &program.sizes)?;
+    program.compile(&mut *session)?;
+    Ok(session.program_info())
+}
+
+fn decode_instrumentation(script: &str) -> Result<Instrumentation, InstrumentationError> {
+    let config = match toml::decode_str::<Config>(script) {
+        Some(config) => config,
+        None => return Err(InstrumentationError::Config("failed decoding TOML".to_string())),
+    };
+
+    match config.instrumentation {
+        Some(instrumentation) => Ok(instrumentation),
+        None => Err(InstrumentationError::Config("missing [instrumentation] table".to_string())),
+    }
+}
+
+// Load the transport plugin and open it with the instrumentation's
+// configuration
+fn open_transport(transport_plugin: &str, config: &str) -> Result<Box<Transport>, String> {
+    let mut transport = TransportBridge::new(transport_plugin)?;
+    transport.open(config)?;
+    Ok(Box::new(transport))
+}
 
-        return DTRACE_CONSUME_NEXT;
+// Run the instrumentation script using the tracing backend, sending its
+// records upstream on the transport opened for it
+fn instrument(backend: &Backend,
+    open_transport: &Fn(&str, &str) -> Result<Box<Transport>, String>,
+    endpoint: Endpoint, script_id: String, script: String,
+    rx: mpsc::Receiver<InstrumentationThreadMessage>) -> Result<(), InstrumentationError> {
+
+    let metadata = Metadata {
+        hostname: endpoint.hostname.clone(),
+        hostuuid: hostuuid().unwrap_or_default(),
+        agent_name: endpoint.agent_name.to_string(),
+        agent_version: endpoint.agent_version.to_string(),
+        script_id: script_id,
+        run_epoch: now_nanos() / 1_000_000,
+    };
+
+    // Parse the configuration file specifying where the DTrace records are to
+    // be sent
+    let instrumentation = decode_instrumentation(script.as_str())?;
+    let transport_plugin = instrumentation.transport.clone()
+        .unwrap_or(DEFAULT_TRANSPORT_PLUGIN.to_string());
+    let format = match instrumentation.format.as_ref() {
+        Some(name) => match OutputFormat::from_name(name) {
+            Some(format) => format,
+            None => return Err(InstrumentationError::Config(
+                format!("unknown output format {}", name))),
+        },
+        None => OutputFormat::Text,
+    };
+    let cdm = if format == OutputFormat::Cdm {
+        let host_id = hostuuid()
+            .and_then(|uuid| Uuid::parse_str(uuid.as_str()).ok())
+            .unwrap_or(Uuid::nil());
+        let cdm_config = instrumentation.cdm.as_ref();
+        Some(CdmEncoder::new(host_id,
+            cdm_config.and_then(|c| c.fields.clone()).unwrap_or(Vec::new()),
+            cdm_config.and_then(|c| c.schema_id)))
     } else {
-        let action = (* rec).dtrd_action;
-        trace!("chewrec() record action = {}", action);
-        if action == 2 { 
-            // Consume next record - DTRACE_CONSUME_NEXT
-            trace!("chewrec() consume next");
-       
-            // Flush the records upstream using the specified transport handler
-            let handler = arg as *const TransportBridge;
-            (* handler).flush();
-
-            return DTRACE_CONSUME_NEXT;
-        } else {
-            // Consume this record - DTRACE_CONSUME_THIS
-            trace!("chewrec() consume this");
-            return DTRACE_CONSUME_THIS;
-        }
+        None
+    };
+    let aggregation_mode = match instrumentation.aggregation_mode.as_ref() {
+        Some(name) => match AggregationMode::from_name(name) {
+            Some(mode) => mode,
+            None => return Err(InstrumentationError::Config(
+                format!("unknown aggregation mode {}", name))),
+        },
+        None => AggregationMode::Cumulative,
+    };
+    let aggregations = match instrumentation.aggregation_interval {
+        Some(_) if format == OutputFormat::Cdm => {
+            warn!("aggregations cannot be reported as CDM records");
+            None
+        },
+        Some(interval) => {
+            match AggregationReporter::new(interval, aggregation_mode,
+                metadata.run_epoch * 1_000_000,
+                instrumentation.aggregations.as_ref()) {
+                Ok(reporter) => Some(reporter),
+                Err(e) => return Err(InstrumentationError::Config(
+                    format!("invalid aggregation configuration: {}", e))),
+            }
+        },
+        None => None,
+    };
+    let stack_format = match instrumentation.stack_format.as_ref() {
+        Some(name) => match StackFormat::from_name(name) {
+            Some(stack_format) => stack_format,
+            None => return Err(InstrumentationError::Config(
+                format!("unknown stack format {}", name))),
+        },
+        None => StackFormat::Symbols,
+    };
+    let drops = match DropMonitor::new(instrumentation.drops.as_ref()) {
+        Ok(drops) => drops,
+        Err(e) => return Err(InstrumentationError::Config(
+            format!("invalid drops configuration: {}", e))),
+    };
+    let policy = match OptionPolicy::new(instrumentation.option_policy.as_ref()) {
+        Ok(policy) => policy,
+        Err(e) => return Err(InstrumentationError::Config(
+            format!("invalid option policy: {}", e))),
+    };
+    let program = match Program::new(&instrumentation, &endpoint,
+        hostuuid().as_ref().map(|uuid| uuid.as_str()), metadata.script_id.as_str()) {
+        Ok(program) => program,
+        Err(e) => return Err(InstrumentationError::Config(e)),
+    };
+    let target = match Target::new(instrumentation.target_pid,
+        instrumentation.command.as_ref()) {
+        Ok(target) => target,
+        Err(e) => return Err(InstrumentationError::Config(e)),
+    };
+    let filter = match RecordFilter::new(instrumentation.rate_limit.as_ref(),
+        instrumentation.sampling.as_ref(), instrumentation.stats_interval) {
+        Ok(filter) => filter,
+        Err(e) => return Err(InstrumentationError::Config(e)),
+    };
+
+    let transport = match open_transport(transport_plugin.as_str(), script.as_str()) {
+        Ok(transport) => transport,
+        Err(e) => return Err(InstrumentationError::Transport(e)),
+    };
+
+    let mut context = ConsumerContext {
+        transport: transport,
+        filter: filter,
+        format: format,
+        cdm: cdm,
+        metadata: metadata,
+        aggregations: aggregations,
+        symbolizer: Symbolizer::new(stack_format),
+        status: Default::default(),
+        reported: Default::default(),
+        drops: drops,
+        sizes: program.sizes,
+        restart: None,
+        policy: policy,
+        program: program,
+        cache: endpoint.cache.as_ref().map(|dir| DofCache::new(dir)),
+        // As with dtrace -c and -p, tracing stops once the target is gone
+        stop_on_exit: instrumentation.stop_on_exit.unwrap_or(target.is_some()),
+        target: target,
+        stopped: false,
+        pending: None,
+        firing: None,
+        new_record: true,
+    };
+
+    let result = trace(backend, &mut context, &rx);
+
+    // Report the final counters (the transport is closed with the context)
+    let record = context.status.stats_record(context.filter.stats());
+    if self::status::is_news(&record, &Default::default()) {
+        context.write_stats(&record);
     }
+    result.map_err(InstrumentationError::from)
 }
 
+// Trace the D program until it exits or is stopped, restarting with
+// larger buffers if drops persist
+fn trace(backend: &Backend, context: &mut ConsumerContext,
+    rx: &mpsc::Receiver<InstrumentationThreadMessage>) -> Result<(), DTraceError> {
+
+    let mut failure = None;
+
+    loop {
+        let mut session = backend.open()?;
+        info!("dtrace initialized");
+
+        context.program.set_options(&mut *session, &context.sizes)?;
+        info!("dtrace options set");
+
+        // Options set by the script's #pragmas are subject to the policy
+        let tracked = context.tracked_options();
+        let defaults: Vec<i64> = tracked.iter().map(|option|
+            session.getopt(option).unwrap_or(DTRACEOPT_UNSET)).collect();
+
+        // The target is attached before compiling, for $target to be its
+        // pid (so the compiled program isn't cached)
+        let cache = match context.target {
+            Some(ref target) => {
+                let pid = target.attach(&mut *session)?;
+                info!("dtrace target pid {}", pid);
+                None
+            },
+            None => context.cache.as_ref(),
+        };
+
+        context.program.load(&mut *session, cache)?;
+        info!("dtrace program compiled");
+
+        for (option, old) in tracked.iter().zip(defaults) {
+            if let Some(new) = session.getopt(option) {
+                context.review_option(&mut *session, option, old, new);
+            }
+        }
+
+        session.exec()?;
+        info!("dtrace probes enables");
+
+        // Until the session is closed, the consumer callbacks write through
+        // context_ptr, so the context is only reached through it
+        let context_ptr = context as *mut ConsumerContext;
+        unsafe { session.register(context_ptr)? };
+        session.go()?;
+        session.proc_continue();
+        info!("dtrace instrumentation started...");
+
+        let mut done = false;
+        while {
+            if done == false {
+               session.sleep();
+            }
+
+            trace!("dtrace work...");
+            match unsafe { session.work(context_ptr) } {
+                Ok(WorkStatus::Okay) => {
+                    done = false;
+                },
+                Ok(WorkStatus::Done) => {
+                    done = true;
+                },
+                Err(e) => {
+                    failure = Some(e);
+                    done = true;
+                }
+            }
+
+            unsafe {
+                ConsumerContext::report_aggregations(context_ptr, &mut *session);
+                (*context_ptr).report_stats();
+                if (*context_ptr).stopped {
+                    done = true;
+                }
+            }
+
+            done = match rx.try_recv() {
+                Ok(ref msg) if *msg == InstrumentationThreadMessage::Stop => {
+                    true
+                },
+                Ok(_) => {
+                    done
+                },
+                Err(error) if error == mpsc::TryRecvError::Empty => {
+                    done
+                },
+                Err(error) => {
+                    error!("{}", error);
+                    false
+                },
+            };
+
+            done == false && unsafe { (*context_ptr).restart.is_none() }
+        } {}
+        unsafe {
+            if done {
+                // The script exited or was stopped
+                (*context_ptr).restart = None;
+            }
+
+            // Report the aggregations before they are lost with the session
+            if (*context_ptr).aggregations.is_some() {
+                ConsumerContext::write_aggregations(context_ptr, &mut *session);
+            }
+        }
+
+        info!("dtrace stopping");
+        session.stop()?;
+        session.proc_release();
+
+        info!("dtrace closing");
+        drop(session);
+
+        if let Some(e) = failure {
+            return Err(e);
+        }
+
+        match context.restart.take() {
+            Some(sizes) => context.sizes = sizes,
+            None => return Ok(()),
+        }
+    }
+}
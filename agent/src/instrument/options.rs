@@ -0,0 +1,158 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::collections::HashMap;
+use rustc_serialize::{Decodable, Decoder};
+use super::drops::parse_size;
+
+// Value of an option that hasn't been set (from sys/dtrace.h)
+pub const DTRACEOPT_UNSET: i64 = -2;
+
+// Names of the options held by a DTrace handle, in the order of their
+// DTRACEOPT_* indexes (from sys/dtrace.h)
+pub const DTRACEOPT_NAMES: &'static [&'static str] = &[
+    "bufsize", "bufpolicy", "dynvarsize", "aggsize", "specsize", "nspec", "strsize",
+    "cleanrate", "cpu", "bufresize", "grabanon", "flowindent", "quiet", "stackframes",
+    "ustackframes", "aggrate", "switchrate", "statusrate", "destructive", "stackindent",
+    "rawbytes", "jstackframes", "jstackstrsize", "aggsortkey", "aggsortrev", "aggsortpos",
+    "aggsortkeypos", "temporal", "agghist", "aggpack", "aggzoom", "zone", "buflimit",
+];
+
+// Value of a DTrace option set by the [instrumentation.options] table,
+// such as switchrate = "10hz", strsize = 256 or quiet = true
+#[derive(Clone, Debug, PartialEq)]
+pub enum OptionValue {
+    Flag(bool),
+    Number(i64),
+    Text(String),
+}
+
+// The TOML decoder tries each variant in turn, so the value's type picks
+// the variant
+impl Decodable for OptionValue {
+    fn decode<D: Decoder>(d: &mut D) -> Result<OptionValue, D::Error> {
+        d.read_enum("OptionValue", |d| {
+            d.read_enum_variant(&["Flag", "Number", "Text"], |d, i| match i {
+                0 => Ok(OptionValue::Flag(d.read_enum_variant_arg(0, Decodable::decode)?)),
+                1 => Ok(OptionValue::Number(d.read_enum_variant_arg(0, Decodable::decode)?)),
+                _ => Ok(OptionValue::Text(d.read_enum_variant_arg(0, Decodable::decode)?)),
+            })
+        })
+    }
+}
+
+impl OptionValue {
+    // The value passed to dtrace_setopt (None if the option is to be left
+    // unset)
+    pub fn setopt_value(&self) -> Option<String> {
+        match *self {
+            // Like dtrace -x option, setting a flag without a value
+            OptionValue::Flag(true) => Some(String::new()),
+            OptionValue::Flag(false) => None,
+            OptionValue::Number(n) => Some(n.to_string()),
+            OptionValue::Text(ref text) => Some(text.clone()),
+        }
+    }
+}
+
+// Operator policy on the options a D program may set with #pragma or
+// setopt(): options capped at a maximum value and options that the
+// program may not change at all
+#[derive(Debug)]
+pub struct OptionPolicyConfig {
+    max: Option<HashMap<String, String>>,
+    deny: Option<Vec<String>>,
+}
+
+impl Decodable for OptionPolicyConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<OptionPolicyConfig, D::Error> {
+        d.read_struct("OptionPolicyConfig", 2, |d| Ok(OptionPolicyConfig {
+            max: d.read_struct_field("max", 0, Decodable::decode)?,
+            deny: d.read_struct_field("deny", 1, Decodable::decode)?,
+        }))
+    }
+}
+
+pub struct OptionPolicy {
+    max: HashMap<String, i64>,
+    deny: Vec<String>,
+}
+
+impl OptionPolicy {
+    pub fn new(config: Option<&OptionPolicyConfig>) -> Result<OptionPolicy, String> {
+        let mut max = HashMap::new();
+        if let Some(caps) = config.and_then(|c| c.max.as_ref()) {
+            for (option, value) in caps {
+                match parse_size(value) {
+                    Some(value) => {
+                        max.insert(option.clone(), value as i64);
+                    },
+                    None => return Err(format!("invalid maximum {} for option {}", value, option)),
+                }
+            }
+        }
+
+        Ok(OptionPolicy {
+            max: max,
+            deny: config.and_then(|c| c.deny.clone()).unwrap_or_default(),
+        })
+    }
+
+    // Options whose values the policy constrains
+    pub fn options(&self) -> Vec<&str> {
+        self.max.keys().chain(self.deny.iter()).map(|option| option.as_str()).collect()
+    }
+
+    // Review a change of an option from old to new, returns the value to
+    // set instead if the policy vetoes the change (the old value if it was
+    // within policy, otherwise the option's cap)
+    pub fn review(&self, option: &str, old: i64, new: i64) -> Option<i64> {
+        if new == old {
+            return None;
+        }
+
+        if self.deny.iter().any(|denied| denied == option) {
+            return Some(old);
+        }
+
+        match self.max.get(option) {
+            Some(&max) if new > max => {
+                if old != DTRACEOPT_UNSET && old <= max {
+                    Some(old)
+                } else {
+                    Some(max)
+                }
+            },
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::os::raw::c_char;
+use super::backend::Session;
+use super::ddtrace_record::ProcessRecord;
+use super::error::DTraceError;
+use super::libc;
+use super::libdtrace;
+use super::record::c_str;
+
+// Process states (from FreeBSD's libproc.h)
+const PS_UNDEAD: i32 = 4;
+const PS_DEAD: i32 = 5;
+const PS_LOST: i32 = 6;
+
+// The process traced by a script's pid provider and USDT probes, whose pid
+// libdtrace expands $target to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Target {
+    // A running process, grabbed and stopped until tracing starts
+    Pid(i32),
+    // A command launched stopped, which exits with the tracing (as with
+    // dtrace -c)
+    Command(Vec<String>),
+}
+
+impl Target {
+    pub fn new(pid: Option<i32>, command: Option<&Vec<String>>)
+        -> Result<Option<Target>, String> {
+
+        match (pid, command) {
+            (Some(_), Some(_)) => Err("both target_pid and command are set".to_string()),
+            (Some(pid), None) if pid <= 0 => Err(format!("invalid target_pid {}", pid)),
+            (Some(pid), None) => Ok(Some(Target::Pid(pid))),
+            (None, Some(command)) if command.is_empty() => Err("empty command".to_string()),
+            (None, Some(command)) => Ok(Some(Target::Command(command.clone()))),
+            (None, None) => Ok(None),
+        }
+    }
+
+    // Grab or create the process, which must be done before the program
+    // is compiled for $target to be defined, returning its pid
+    pub fn attach(&self, session: &mut Session) -> Result<i32, DTraceError> {
+        match *self {
+            Target::Pid(pid) => session.proc_grab(pid).map(|_| pid),
+            Target::Command(ref command) => session.proc_create(command),
+        }
+    }
+
+    // Whether tracing may be restarted, a launched command being killed
+    // when released
+    pub fn restartable(&self) -> bool {
+        match *self {
+            Target::Pid(_) => true,
+            Target::Command(_) => false,
+        }
+    }
+}
+
+// Returns true if the event means the process can no longer be traced
+pub fn is_gone(event: &str) -> bool {
+    event == "exited" || event == "killed" || event == "lost"
+}
+
+// Describe the state change of a process reported to the proc handler
+// (following dtrace(1)'s prochandler: a message from libdtrace is reported
+// as a notice, otherwise the process has exited or been lost)
+pub unsafe fn process_record(p: *mut libdtrace::ps_prochandle, msg: *const c_char)
+    -> ProcessRecord {
+
+    // libdtrace's ps_prochandle is libproc's proc_handle
+    let p = p as *mut libdtrace::proc_handle;
+    describe_process(libdtrace::proc_getpid(p), msg, libdtrace::proc_state(p),
+        libdtrace::proc_getwstat(p))
+}
+
+// Describe the process given its pid, state and wait status (which is
+// only meaningful once it's dead)
+unsafe fn describe_process(pid: i32, msg: *const c_char, state: i32, wstat: i32)
+    -> ProcessRecord {
+
+    let mut record = ProcessRecord {
+        pid: pid as u64,
+        ..Default::default()
+    };
+
+    if !msg.is_null() {
+        record.event = "notice".to_string();
+        record.message = c_str(msg).trim().to_string();
+        return record;
+    }
+
+    match state {
+        PS_UNDEAD | PS_DEAD => {
+            if libc::WIFSIGNALED(wstat) {
+                record.event = "killed".to_string();
+                record.signal = libc::WTERMSIG(wstat);
+            } else {
+                record.event = "exited".to_string();
+                record.exit_status = libc::WEXITSTATUS(wstat);
+            }
+        },
+        PS_LOST => {
+            record.event = "lost".to_string();
+            record.message = "exec'd set-id or unobservable program".to_string();
+        },
+        state => {
+            record.event = "notice".to_string();
+            record.message = format!("process state {}", state);
+        }
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    #[test]
+    fn describes_process_state_changes() {
+        let msg = CString::new("exec'd /usr/sbin/sshd\n").unwrap();
+        let notice = unsafe { describe_process(4242, msg.as_ptr(), PS_UNDEAD, 0) };
+        assert_eq!((notice.event.as_str(), notice.message.as_str()),
+            ("notice", "exec'd /usr/sbin/sshd"));
+
+        // Wait statuses hold the exit status in the second byte, and the
+        // terminating signal in the low bits
+        let exited = unsafe { describe_process(4242, ptr::null(), PS_DEAD, 3 << 8) };
+        assert_eq!((exited.pid, exited.event.as_str(), exited.exit_status), (4242, "exited", 3));
+        let killed = unsafe { describe_process(4242, ptr::null(), PS_UNDEAD, 9) };
+        assert_eq!((killed.event.as_str(), killed.signal), ("killed", 9));
+        let lost = unsafe { describe_process(4242, ptr::null(), PS_LOST, 0) };
+        assert_eq!(lost.event, "lost");
+        assert!(is_gone(&lost.event));
+    }
+
+    #[test]
+    fn rejects_conflicting_targets() {
+        let command = vec!["/usr/bin/make".to_string()];
+        assert_eq!(Target::new(Some(4242), None), Ok(Some(Target::Pid(4242))));
+        assert_eq!(Target::new(None, Some(&command)), Ok(Some(Target::Command(command.clone()))));
+        assert_eq!(Target::new(None, None), Ok(None));
+        assert!(Target::new(Some(4242), Some(&command)).is_err());
+        assert!(Target::new(Some(0), None).is_err());
+        assert!(Target::new(None, Some(&Vec::new())).is_err());
+    }
+}
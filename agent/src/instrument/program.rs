@@ -0,0 +1,416 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::fs;
+use std::path::{Component, Path};
+use rustc_serialize::{Encodable, Encoder};
+use super::{Endpoint, Instrumentation};
+use super::args::Substitutions;
+use super::backend::Session;
+use super::dof::{CacheKey, DofCache, Fnv};
+use super::drops::{parse_size, BufferSizes};
+use super::error::DTraceError;
+
+// Options set unless the script sets them
+const DEFAULT_OPTIONS: &'static [(&'static str, &'static str)] = &[
+    ("temporal", "4m"),
+    ("arch", "x86_64"),
+];
+
+// The D program of an instrumentation script, with the options and macro
+// arguments it's compiled with
+#[derive(Clone, Debug)]
+pub struct Program {
+    // Library files compiled ahead of the script's fragments
+    pub includes: Vec<String>,
+    pub fragments: Vec<String>,
+    // Macro arguments $0..$n
+    pub args: Vec<String>,
+    // Buffer sizes set by the script, where the automatic resizing starts
+    pub sizes: BufferSizes,
+    // Options set by the script (other than the buffer sizes)
+    pub options: Vec<(String, String)>,
+}
+
+impl Program {
+    pub fn new(instrumentation: &Instrumentation, endpoint: &Endpoint, hostuuid: Option<&str>,
+        script_id: &str) -> Result<Program, String> {
+
+        // The script's program is its script followed by its fragments
+        let fragments: Vec<String> = instrumentation.script.iter()
+            .chain(instrumentation.fragments.iter().flat_map(|fragments| fragments.iter()))
+            .cloned().collect();
+        if fragments.is_empty() {
+            return Err("missing script".to_string());
+        }
+
+        // Includes name files in the endpoint's library
+        let mut includes = Vec::new();
+        for include in instrumentation.includes.clone().unwrap_or_default() {
+            if !Path::new(&include).components().all(|c| match c {
+                Component::Normal(_) => true,
+                _ => false,
+            }) {
+                return Err(format!("include {} is outside the library", include));
+            }
+            let path = Path::new(&endpoint.library).join(include);
+            includes.push(path.to_string_lossy().into_owned());
+        }
+
+        // The script's options are passed to DTrace as they are, except for
+        // the buffer sizes
+        let mut sizes = BufferSizes::default();
+        let mut options = Vec::new();
+        for (option, value) in instrumentation.options.clone().unwrap_or_default() {
+            let value = match value.setopt_value() {
+                Some(value) => value,
+                None => continue,
+            };
+            match sizes.option_mut(option.as_str()) {
+                Some(size) => match parse_size(value.as_str()) {
+                    Some(parsed) => *size = parsed,
+                    None => return Err(format!("invalid size {} for option {}", value, option)),
+                },
+                None => options.push((option, value)),
+            }
+        }
+
+        // The D program's $0 is the quoted kern.hostuuid (as it was before
+        // scripts had arguments, and empty if it's unavailable), followed by
+        // its arguments
+        let substitutions = Substitutions {
+            hostname: endpoint.hostname.as_str(),
+            hostuuid: hostuuid,
+            script_id: script_id,
+        };
+        let mut args = vec![format!("\"{}\"", hostuuid.unwrap_or(""))];
+        for arg in instrumentation.args.clone().unwrap_or_default() {
+            match substitutions.expand(arg.as_str()) {
+                Ok(arg) => args.push(arg),
+                Err(e) => return Err(format!("invalid argument {}: {}", arg, e)),
+            }
+        }
+        if let Some(ref defines) = instrumentation.defines {
+            // Defines are passed to the preprocessor, as with dtrace -C -D,
+            // which includes files from the library
+            options.push(("cpp".to_string(), String::new()));
+            options.push(("incdir".to_string(), endpoint.library.clone()));
+            for (name, value) in defines {
+                match substitutions.expand(value.as_str()) {
+                    Ok(value) => {
+                        options.push(("define".to_string(), format!("{}={}", name, value)));
+                    },
+                    Err(e) => return Err(format!("invalid define {}: {}", name, e)),
+                }
+            }
+        }
+
+        Ok(Program {
+            includes: includes,
+            fragments: fragments,
+            args: args,
+            sizes: sizes,
+            options: options,
+        })
+    }
+
+    // Compile the includes, then the fragments
+    pub fn compile(&self, session: &mut Session) -> Result<(), DTraceError> {
+        for include in &self.includes {
+            session.compile_file(include.as_str(), &self.args)?;
+        }
+        for fragment in &self.fragments {
+            session.compile(fragment.as_str(), &self.args)?;
+        }
+        Ok(())
+    }
+
+    // Compile the program, unless the programs compiled for the same
+    // sources, DTrace version and kernel are cached
+    pub fn load(&self, session: &mut Session, cache: Option<&DofCache>)
+        -> Result<(), DTraceError> {
+
+        let key = cache.and_then(|_| self.cache_key(session));
+        if let (Some(cache), Some(key)) = (cache, key.as_ref()) {
+            if let Some(dof) = cache.load(key) {
+                info!("loaded cached DOF {:016x}", key.hash);
+                session.load_dof(&dof);
+                return Ok(());
+            }
+        }
+
+        self.compile(session)?;
+        if let (Some(cache), Some(key)) = (cache, key.as_ref()) {
+            match session.dof() {
+                Ok(dof) => cache.store(key, &dof),
+                Err(e) => warn!("{}", e),
+            }
+        }
+        Ok(())
+    }
+
+    // The key of the compiled program, hashing the contents of its
+    // includes (None if they can't be read, leaving the compiler to report
+    // it, or if the program is preprocessed, as the files it #includes
+    // aren't known)
+    fn cache_key(&self, session: &Session) -> Option<CacheKey> {
+        if self.options.iter().any(|&(ref option, _)| option == "cpp") {
+            return None;
+        }
+
+        // Each kind of item is preceded by the number of items
+        let mut hash = Fnv::new();
+        hash.add(self.includes.len().to_string().as_bytes());
+        for include in &self.includes {
+            hash.add(&fs::read(include).ok()?);
+        }
+        hash.add(self.fragments.len().to_string().as_bytes());
+        for fragment in &self.fragments {
+            hash.add(fragment.as_bytes());
+        }
+        hash.add(self.args.len().to_string().as_bytes());
+        for arg in &self.args {
+            hash.add(arg.as_bytes());
+        }
+        for &(ref option, ref value) in &self.options {
+            hash.add(option.as_bytes());
+            hash.add(value.as_bytes());
+        }
+        Some(CacheKey {
+            hash: hash.hash(),
+            dtrace: session.dtrace_version(),
+            kernel: session.kernel_build(),
+        })
+    }
+
+    // Set the options the program is compiled with, the buffer sizes and
+    // the defaults are best effort but the script's own options must all
+    // be valid
+    pub fn set_options(&self, session: &mut Session, sizes: &BufferSizes)
+        -> Result<(), DTraceError> {
+
+        for (option, value) in sizes.options() {
+            if let Err(e) = session.setopt(option, value.as_str()) {
+                warn!("{}", e);
+            }
+        }
+        for &(option, value) in DEFAULT_OPTIONS {
+            if !self.options.iter().any(|&(ref set, _)| set == option) {
+                if let Err(e) = session.setopt(option, value) {
+                    warn!("{}", e);
+                }
+            }
+        }
+        for &(ref option, ref value) in &self.options {
+            session.setopt(option, value)?;
+        }
+        Ok(())
+    }
+}
+
+// Stability attributes of a program's probe descriptions or statements
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    pub name: String,
+    pub data: String,
+    pub class: String,
+}
+
+// What dtrace_program_info reports of a compiled program
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgramInfo {
+    // Number of probes the program's descriptions matched
+    pub matches: u32,
+    pub aggregates: u32,
+    pub recgens: u32,
+    pub speculations: u32,
+    pub description: Attributes,
+    pub statement: Attributes,
+}
+
+// Encoded (as JSON in validation results) field by field, as
+// #[derive(RustcEncodable)] would
+impl Encodable for Attributes {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_struct("Attributes", 3, |e| {
+            e.emit_struct_field("name", 0, |e| self.name.encode(e))?;
+            e.emit_struct_field("data", 1, |e| self.data.encode(e))?;
+            e.emit_struct_field("class", 2, |e| self.class.encode(e))
+        })
+    }
+}
+
+impl Encodable for ProgramInfo {
+    fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+        e.emit_struct("ProgramInfo", 6, |e| {
+            e.emit_struct_field("matches", 0, |e| self.matches.encode(e))?;
+            e.emit_struct_field("aggregates", 1, |e| self.aggregates.encode(e))?;
+            e.emit_struct_field("recgens", 2, |e| self.recgens.encode(e))?;
+            e.emit_struct_field("speculations", 3, |e| self.speculations.encode(e))?;
+            e.emit_struct_field("description", 4, |e| self.description.encode(e))?;
+            e.emit_struct_field("statement", 5, |e| self.statement.encode(e))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::backend::Backend;
+    use super::super::decode_instrumentation;
+    use super::super::mock::MockBackend;
+
+    fn endpoint(cache: Option<String>) -> Endpoint {
+        Endpoint {
+            hostname: "db1.example.com".to_string(),
+            library: "/var/db/ddtrace".to_string(),
+            cache: cache,
+            agent_name: "ddtrace",
+            agent_version: "0.1.0",
+        }
+    }
+
+    fn new_program(config: &str) -> Result<Program, String> {
+        let instrumentation = decode_instrumentation(config).ok().unwrap();
+        Program::new(&instrumentation, &endpoint(None), None, "syscalls")
+    }
+
+    #[test]
+    fn expands_macro_arguments() {
+        let program = new_program("
+            [instrumentation]
+            script = \"syscall:::entry { trace($1); }\"
+            args = ['\"${hostname}\"', '${script_id}-42', '10']
+            defines = { LIMIT = '${script_id}' }
+        ").unwrap();
+
+        assert_eq!(program.args, vec!["\"\"", "\"db1.example.com\"", "syscalls-42", "10"]);
+        assert!(program.options.contains(&("define".to_string(), "LIMIT=syscalls".to_string())));
+        assert!(program.options.contains(&("incdir".to_string(), "/var/db/ddtrace".to_string())));
+
+        let instrumentation = decode_instrumentation("
+            [instrumentation]
+            script = \"syscall:::entry { trace($0); }\"
+        ").ok().unwrap();
+        let hostuuid = "b3f5e1d2-8f3a-11e7-a3a5-0800279c1a47";
+        let program = Program::new(&instrumentation, &endpoint(None), Some(hostuuid), "syscalls");
+        assert_eq!(program.unwrap().args, vec![format!("\"{}\"", hostuuid)]);
+
+        let invalid = new_program("
+            [instrumentation]
+            script = \"syscall:::entry { trace($1); }\"
+            args = ['${nodename}']
+        ");
+        assert!(invalid.is_err());
+    }
+
+    #[test]
+    fn compiles_includes_before_fragments() {
+        let backend = MockBackend::default();
+        let program = new_program("
+            [instrumentation]
+            script = \"syscall:::entry { trace(pid); }\"
+            fragments = ['syscall:::return { trace(arg0); }']
+            includes = ['net.d', 'procs/ancestors.d']
+        ").unwrap();
+
+        let mut session = backend.open().unwrap();
+        program.compile(&mut *session).unwrap();
+        assert_eq!(*backend.compiled.borrow(), vec!["/var/db/ddtrace/net.d",
+            "/var/db/ddtrace/procs/ancestors.d", "syscall:::entry { trace(pid); }",
+            "syscall:::return { trace(arg0); }"]);
+
+        let outside = new_program("
+            [instrumentation]
+            script = \"syscall:::entry { trace(pid); }\"
+            includes = ['../secrets.d']
+        ");
+        assert!(outside.is_err());
+    }
+
+    #[test]
+    fn reuses_cached_programs_until_the_kernel_changes() {
+        let dir = ::std::env::temp_dir()
+            .join(format!("ddtrace-program-{}", ::std::process::id()));
+        let cache = DofCache::new(&dir.to_string_lossy());
+        let program = new_program("
+            [instrumentation]
+            script = \"syscall:::entry { trace(pid); }\"
+        ").unwrap();
+        let info = ProgramInfo { matches: 1, ..Default::default() };
+        // The backend, and the info of the program it compiled or loaded
+        let load = |kernel: &str, info: &ProgramInfo| {
+            let backend = MockBackend {
+                kernel: kernel.to_string(),
+                info: info.clone(),
+                ..Default::default()
+            };
+            let mut session = backend.open().unwrap();
+            program.load(&mut *session, Some(&cache)).unwrap();
+            let loaded = session.program_info();
+            (backend, loaded)
+        };
+
+        let (compiled, _) = load("11.0-RELEASE-p1", &info);
+        assert_eq!(compiled.compiled.borrow().len(), 1);
+        let (cached, cached_info) = load("11.0-RELEASE-p1", &ProgramInfo::default());
+        assert!(cached.compiled.borrow().is_empty());
+        assert_eq!(*cached.loaded.borrow(), vec![b"syscall:::entry { trace(pid); }".to_vec()]);
+        assert_eq!(cached_info, info);
+        let (upgraded, _) = load("11.0-RELEASE-p2", &info);
+        assert_eq!(upgraded.compiled.borrow().len(), 1);
+        assert!(upgraded.loaded.borrow().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compiles_preprocessed_programs_uncached() {
+        let dir = ::std::env::temp_dir()
+            .join(format!("ddtrace-cpp-{}", ::std::process::id()));
+        let cache = DofCache::new(&dir.to_string_lossy());
+        let program = new_program("
+            [instrumentation]
+            script = \"syscall:::entry /pid == LIMIT/ { trace(pid); }\"
+            defines = { LIMIT = '100' }
+        ").unwrap();
+
+        for _ in 0..2 {
+            let backend = MockBackend::default();
+            let mut session = backend.open().unwrap();
+            program.load(&mut *session, Some(&cache)).unwrap();
+            assert_eq!(backend.compiled.borrow().len(), 1);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::time::{Duration, Instant};
+use rustc_serialize::{Decodable, Decoder};
+use super::rand::{self, Rng};
+
+// Default interval between reports of the filter's counters
+const DEFAULT_REPORT_INTERVAL: u64 = 10;
+
+#[derive(Debug)]
+pub struct RateLimitConfig {
+    records_per_second: Option<f64>,
+    burst: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct SamplingConfig {
+    probability: Option<f64>,
+    one_in: Option<u64>,
+}
+
+impl Decodable for RateLimitConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<RateLimitConfig, D::Error> {
+        d.read_struct("RateLimitConfig", 2, |d| Ok(RateLimitConfig {
+            records_per_second: d.read_struct_field("records_per_second", 0, Decodable::decode)?,
+            burst: d.read_struct_field("burst", 1, Decodable::decode)?,
+        }))
+    }
+}
+
+impl Decodable for SamplingConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<SamplingConfig, D::Error> {
+        d.read_struct("SamplingConfig", 2, |d| Ok(SamplingConfig {
+            probability: d.read_struct_field("probability", 0, Decodable::decode)?,
+            one_in: d.read_struct_field("one_in", 1, Decodable::decode)?,
+        }))
+    }
+}
+
+// Token bucket refilled at records_per_second up to burst tokens;
+// each record forwarded upstream consumes a single token
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            capacity: capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill);
+        let elapsed_secs = elapsed.as_secs() as f64 +
+            elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+        self.tokens = (self.tokens + elapsed_secs * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+enum Sampler {
+    Probability(f64),
+    OneIn(u64, u64),
+}
+
+impl Sampler {
+    fn sample(&mut self) -> bool {
+        match *self {
+            Sampler::Probability(p) => rand::thread_rng().next_f64() < p,
+            Sampler::OneIn(n, ref mut seen) => {
+                *seen += 1;
+                if *seen >= n {
+                    *seen = 0;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+// Counters maintained by the RecordFilter
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FilterStats {
+    pub passed: u64,
+    pub sampled_out: u64,
+    pub rate_limited: u64,
+}
+
+// Per-instrumentation filter applied to every record before it is written
+// to the transport. Records are first sampled, those selected by the
+// sampler are then subject to the rate limit.
+pub struct RecordFilter {
+    bucket: Option<TokenBucket>,
+    sampler: Option<Sampler>,
+    stats: FilterStats,
+    report_interval: Duration,
+    last_report: Instant,
+}
+
+impl RecordFilter {
+    pub fn new(rate_limit: Option<&RateLimitConfig>,
+        sampling: Option<&SamplingConfig>,
+        report_interval: Option<u64>) -> Result<RecordFilter, String> {
+
+        let bucket = match rate_limit.map(|config| (config.records_per_second, config.burst)) {
+            Some((Some(rate), _)) if !(rate > 0.0) || !rate.is_finite() =>
+                return Err(format!("invalid rate limit: records_per_second {}", rate)),
+            Some((Some(_), Some(burst))) if !(burst >= 1.0) || !burst.is_finite() =>
+                return Err(format!("invalid rate limit: burst {}", burst)),
+            Some((Some(rate), burst)) =>
+                Some(TokenBucket::new(rate, burst.unwrap_or(rate).max(1.0))),
+            Some((None, Some(_))) =>
+                return Err("invalid rate limit: burst without records_per_second".to_string()),
+            Some((None, None)) | None => None,
+        };
+
+        let sampler = match sampling.map(|config| (config.probability, config.one_in)) {
+            Some((Some(p), None)) if p >= 0.0 && p <= 1.0 => Some(Sampler::Probability(p)),
+            Some((Some(p), None)) =>
+                return Err(format!("invalid sampling: probability {} not within [0, 1]", p)),
+            Some((None, Some(n))) if n > 0 => Some(Sampler::OneIn(n, 0)),
+            Some((None, Some(n))) => return Err(format!("invalid sampling: one_in {}", n)),
+            Some((Some(_), Some(_))) =>
+                return Err("invalid sampling: both probability and one_in given".to_string()),
+            Some((None, None)) | None => None,
+        };
+
+        Ok(RecordFilter {
+            bucket: bucket,
+            sampler: sampler,
+            stats: Default::default(),
+            report_interval: Duration::from_secs(
+                report_interval.unwrap_or(DEFAULT_REPORT_INTERVAL)),
+            last_report: Instant::now(),
+        })
+    }
+
+    // Returns true if the record is to be forwarded upstream
+    pub fn accept(&mut self) -> bool {
+        if let Some(ref mut sampler) = self.sampler {
+            if !sampler.sample() {
+                self.stats.sampled_out += 1;
+                return false;
+            }
+        }
+
+        if let Some(ref mut bucket) = self.bucket {
+            if !bucket.try_acquire() {
+                self.stats.rate_limited += 1;
+                return false;
+            }
+        }
+
+        self.stats.passed += 1;
+        true
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        self.stats
+    }
+
+    // Returns the counters once per report interval
+    pub fn report_due(&mut self) -> Option<FilterStats> {
+        if self.last_report.elapsed() < self.report_interval {
+            return None;
+        }
+        self.last_report = Instant::now();
+        Some(self.stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use super::{RateLimitConfig, RecordFilter, Sampler, SamplingConfig, TokenBucket};
+
+    #[test]
+    fn bucket_allows_bursts_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1.0, 3.0);
+        let acquired = (0..5).filter(|_| bucket.try_acquire_at(start)).count();
+        assert_eq!(acquired, 3);
+    }
+
+    #[test]
+    fn bucket_refills_at_rate_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 4.0);
+        assert_eq!((0..4).filter(|_| bucket.try_acquire_at(start)).count(), 4);
+        assert!(!bucket.try_acquire_at(start));
+
+        // Half a second refills a single token
+        let later = start + Duration::from_millis(500);
+        assert!(bucket.try_acquire_at(later));
+        assert!(!bucket.try_acquire_at(later));
+
+        // An idle minute refills no more than the bucket's capacity
+        let idle = later + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| bucket.try_acquire_at(idle)).count(), 4);
+    }
+
+    #[test]
+    fn samples_one_in_n() {
+        let mut sampler = Sampler::OneIn(3, 0);
+        let sampled: Vec<bool> = (0..6).map(|_| sampler.sample()).collect();
+        assert_eq!(sampled, vec![false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn samples_with_probability() {
+        let mut never = Sampler::Probability(0.0);
+        let mut always = Sampler::Probability(1.0);
+        assert!((0..100).all(|_| !never.sample() && always.sample()));
+
+        let mut half = Sampler::Probability(0.5);
+        let sampled = (0..10_000).filter(|_| half.sample()).count();
+        assert!(sampled > 4_000 && sampled < 6_000, "sampled {}", sampled);
+    }
+
+    #[test]
+    fn filter_samples_before_rate_limiting() {
+        let rate_limit = RateLimitConfig { records_per_second: Some(0.001), burst: Some(2.0) };
+        let sampling = SamplingConfig { probability: None, one_in: Some(2) };
+        let mut filter = RecordFilter::new(Some(&rate_limit), Some(&sampling), None).unwrap();
+
+        // Half are sampled out, and of those sampled only the burst passes
+        let accepted = (0..10).filter(|_| filter.accept()).count();
+        assert_eq!(accepted, 2);
+        let stats = filter.stats();
+        assert_eq!((stats.passed, stats.sampled_out, stats.rate_limited), (2, 5, 3));
+    }
+
+    #[test]
+    fn filter_rejects_invalid_configuration() {
+        let sampling = |probability, one_in| SamplingConfig {
+            probability: probability, one_in: one_in };
+        let rate_limit = |rate, burst| RateLimitConfig {
+            records_per_second: rate, burst: burst };
+
+        for config in &[sampling(Some(0.5), Some(2)), sampling(None, Some(0)),
+            sampling(Some(-0.1), None), sampling(Some(1.5), None)] {
+            assert!(RecordFilter::new(None, Some(config), None).is_err(), "{:?}", config);
+        }
+        for config in &[rate_limit(Some(0.0), None), rate_limit(Some(-1.0), None),
+            rate_limit(Some(10.0), Some(0.5)), rate_limit(None, Some(2.0))] {
+            assert!(RecordFilter::new(Some(config), None, None).is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn filter_without_configuration_passes_everything() {
+        let mut filter = RecordFilter::new(None, None, None).unwrap();
+        assert!((0..10).all(|_| filter.accept()));
+        assert_eq!(filter.stats().passed, 10);
+        assert_eq!(filter.report_due(), None);
+    }
+}
@@ -0,0 +1,349 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::slice;
+use super::ddtrace_record::{Encoding, ErrorRecord, ProbeRecord, RecordValue};
+use super::libdtrace;
+
+// DTrace action kinds (from sys/dtrace.h)
+pub const DTRACEACT_DIFEXPR: u16 = 0x0001;
+pub const DTRACEACT_EXIT: u16 = 0x0002;
+pub const DTRACEACT_PRINTF: u16 = 0x0003;
+pub const DTRACEACT_PRINTA: u16 = 0x0004;
+pub const DTRACEACT_TRACEMEM: u16 = 0x0006;
+pub const DTRACEACT_USTACK: u16 = 0x0101;
+pub const DTRACEACT_JSTACK: u16 = 0x0102;
+pub const DTRACEACT_USYM: u16 = 0x0103;
+pub const DTRACEACT_UMOD: u16 = 0x0104;
+pub const DTRACEACT_UADDR: u16 = 0x0105;
+pub const DTRACEACT_SYSTEM: u16 = 0x0202;
+pub const DTRACEACT_FREOPEN: u16 = 0x0203;
+pub const DTRACEACT_STACK: u16 = 0x0401;
+pub const DTRACEACT_SYM: u16 = 0x0402;
+pub const DTRACEACT_MOD: u16 = 0x0403;
+
+// Return values of the dtrace_work() probe and record callbacks
+pub const DTRACE_CONSUME_THIS: i32 = 0;
+pub const DTRACE_CONSUME_NEXT: i32 = 1;
+
+// Output format of the records sent upstream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    // Pre-formatted text as produced by libdtrace
+    Text,
+    // One structured record per probe firing
+    Structured(Encoding),
+    // CADETS Common Data Model records (Avro binary)
+    Cdm,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "text" => Some(OutputFormat::Text),
+            "cdm" => Some(OutputFormat::Cdm),
+            _ => Encoding::from_name(name).map(OutputFormat::Structured),
+        }
+    }
+}
+
+// Start a structured record for the probe firing described by data
+pub unsafe fn probe_record(data: *const libdtrace::dtrace_probedata_t)
+    -> ProbeRecord {
+
+    let pd = (* data).dtpda_pdesc;
+    ProbeRecord {
+        id: (* pd).dtpd_id,
+        provider: c_str((* pd).dtpd_provider.as_ptr()),
+        module: c_str((* pd).dtpd_mod.as_ptr()),
+        function: c_str((* pd).dtpd_func.as_ptr()),
+        name: c_str((* pd).dtpd_name.as_ptr()),
+        cpu: (* data).dtpda_cpu,
+        timestamp: (* data).dtpda_timestamp,
+        values: Vec::new(),
+        output: String::new(),
+    }
+}
+
+// Describe the runtime fault reported to the error handler
+pub unsafe fn error_record(data: *const libdtrace::dtrace_errdata_t) -> ErrorRecord {
+    let fault = libdtrace::dtrace_faultstr((* data).dteda_handle, (* data).dteda_fault);
+    describe_error(data, fault)
+}
+
+// Describe the fault, given libdtrace's description of the fault code
+unsafe fn describe_error(data: *const libdtrace::dtrace_errdata_t, fault: *const c_char)
+    -> ErrorRecord {
+
+    let mut record = ErrorRecord {
+        offset: (* data).dteda_offset,
+        ..Default::default()
+    };
+
+    let pd = (* data).dteda_pdesc;
+    if !pd.is_null() {
+        record.provider = c_str((* pd).dtpd_provider.as_ptr());
+        record.module = c_str((* pd).dtpd_mod.as_ptr());
+        record.function = c_str((* pd).dtpd_func.as_ptr());
+        record.name = c_str((* pd).dtpd_name.as_ptr());
+    }
+
+    if !fault.is_null() {
+        record.fault = c_str(fault);
+    }
+    if !(* data).dteda_msg.is_null() {
+        record.message = c_str((* data).dteda_msg).trim_right().to_string();
+    }
+    record
+}
+
+pub unsafe fn c_str(ptr: *const c_char) -> String {
+    CStr::from_ptr(ptr).to_string_lossy().into_owned()
+}
+
+// The string, unless the pointer is null
+pub fn optional_str(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(unsafe { c_str(ptr) })
+    }
+}
+
+unsafe fn read_u64s(addr: *const u8, count: usize) -> Vec<u64> {
+    (0..count)
+        .map(|i| ::std::ptr::read_unaligned((addr as *const u64).offset(i as isize)))
+        .collect()
+}
+
+// Decode the value of a trace() record. DTrace doesn't record the D type,
+// so (following dt_print_datum) integers are inferred from the record size
+// and strings from the contents.
+fn decode_datum(bytes: &[u8]) -> RecordValue {
+    match bytes.len() {
+        1 => RecordValue::Int(bytes[0] as i8 as i64),
+        2 => RecordValue::Int(unsafe {
+            ::std::ptr::read_unaligned(bytes.as_ptr() as *const i16) } as i64),
+        4 => RecordValue::Int(unsafe {
+            ::std::ptr::read_unaligned(bytes.as_ptr() as *const i32) } as i64),
+        8 => RecordValue::Int(unsafe {
+            ::std::ptr::read_unaligned(bytes.as_ptr() as *const i64) }),
+        _ => {
+            match decode_string(bytes) {
+                Some(s) => RecordValue::String(s),
+                None => RecordValue::Bytes(bytes.to_vec()),
+            }
+        }
+    }
+}
+
+// A record is treated as a string if it consists of printable characters
+// followed only by NUL bytes
+fn decode_string(bytes: &[u8]) -> Option<String> {
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    if len == 0 ||
+        !bytes[..len].iter().all(|b| (*b >= 0x20 && *b < 0x7f) || *b == b'\t' || *b == b'\n') ||
+        !bytes[len..].iter().all(|b| *b == 0) {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&bytes[..len]).into_owned())
+}
+
+// Decode the record described by rec from the probe data
+// (returns None for records whose output is produced by libdtrace itself,
+// such as printf(), or which carry no data)
+pub unsafe fn decode_record(data: *const libdtrace::dtrace_probedata_t,
+    rec: *const libdtrace::dtrace_recdesc_t) -> Option<RecordValue> {
+
+    decode_value((* data).dtpda_data as *const u8, rec)
+}
+
+// Decode the record described by rec stored at addr (probe data or the
+// keys of an aggregation)
+pub unsafe fn decode_value(addr: *const u8, rec: *const libdtrace::dtrace_recdesc_t)
+    -> Option<RecordValue> {
+
+    let size = (* rec).dtrd_size as usize;
+    if (* rec).dtrd_format != 0 || size == 0 {
+        return None;
+    }
+
+    match (* rec).dtrd_action {
+        DTRACEACT_DIFEXPR => {
+            Some(decode_datum(slice::from_raw_parts(addr, size)))
+        },
+        DTRACEACT_EXIT => {
+            Some(RecordValue::Exit(::std::ptr::read_unaligned(addr as *const i32)))
+        },
+        DTRACEACT_TRACEMEM => {
+            Some(RecordValue::Bytes(slice::from_raw_parts(addr, size).to_vec()))
+        },
+        DTRACEACT_STACK => {
+            // Kernel stack frames (zero terminated if fewer than the depth)
+            let frames = read_u64s(addr, size / 8).into_iter()
+                .take_while(|pc| *pc != 0)
+                .collect();
+            Some(RecordValue::Stack(frames))
+        },
+        DTRACEACT_USTACK | DTRACEACT_JSTACK => {
+            // User stacks are prefixed with the pid of the process
+            let values = read_u64s(addr, size / 8);
+            if values.is_empty() {
+                return None;
+            }
+            let frames = values[1..].iter().cloned()
+                .take_while(|pc| *pc != 0)
+                .collect();
+            Some(RecordValue::UserStack { pid: values[0], frames: frames })
+        },
+        DTRACEACT_SYM | DTRACEACT_MOD => {
+            Some(RecordValue::Address(::std::ptr::read_unaligned(addr as *const u64)))
+        },
+        DTRACEACT_USYM | DTRACEACT_UMOD | DTRACEACT_UADDR => {
+            let values = read_u64s(addr, 2);
+            Some(RecordValue::UserAddress { pid: values[0], addr: values[1] })
+        },
+        DTRACEACT_PRINTF | DTRACEACT_PRINTA | DTRACEACT_SYSTEM |
+        DTRACEACT_FREOPEN => {
+            None
+        },
+        action => {
+            trace!("ignoring record with action {}", action);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::ptr;
+
+    fn set_str(field: &mut [c_char], value: &str) {
+        for (c, b) in field.iter_mut().zip(value.bytes()) {
+            *c = b as c_char;
+        }
+    }
+
+    fn rec(action: u16, size: usize) -> libdtrace::dtrace_recdesc_t {
+        libdtrace::dtrace_recdesc_t {
+            dtrd_action: action,
+            dtrd_size: size as u32,
+            ..Default::default()
+        }
+    }
+
+    fn decode(action: u16, data: &[u8]) -> Option<RecordValue> {
+        unsafe { decode_value(data.as_ptr(), &rec(action, data.len())) }
+    }
+
+    fn u64s(values: &[u64]) -> Vec<u8> {
+        values.iter().flat_map(|value| {
+            (0..8).map(move |i| (value >> (i * 8)) as u8)
+        }).collect()
+    }
+
+    #[test]
+    fn decodes_trace_values() {
+        assert_eq!(decode(DTRACEACT_DIFEXPR, &[0xfe]), Some(RecordValue::Int(-2)));
+        assert_eq!(decode(DTRACEACT_DIFEXPR, &812i32.to_le_bytes()),
+            Some(RecordValue::Int(812)));
+        assert_eq!(decode(DTRACEACT_DIFEXPR, &u64s(&[1 << 40])),
+            Some(RecordValue::Int(1 << 40)));
+        assert_eq!(decode(DTRACEACT_DIFEXPR, b"sshd\0\0\0\0\0"),
+            Some(RecordValue::String("sshd".to_string())));
+        assert_eq!(decode(DTRACEACT_DIFEXPR, b"\x01\x02\x03"),
+            Some(RecordValue::Bytes(vec![1, 2, 3])));
+        assert_eq!(decode(DTRACEACT_EXIT, &3i32.to_le_bytes()), Some(RecordValue::Exit(3)));
+    }
+
+    #[test]
+    fn decodes_stacks_and_addresses() {
+        assert_eq!(decode(DTRACEACT_STACK, &u64s(&[0xffff0010, 0xffff0020, 0, 0])),
+            Some(RecordValue::Stack(vec![0xffff0010, 0xffff0020])));
+        assert_eq!(decode(DTRACEACT_USTACK, &u64s(&[4242, 0x400010, 0])),
+            Some(RecordValue::UserStack { pid: 4242, frames: vec![0x400010] }));
+        assert_eq!(decode(DTRACEACT_SYM, &u64s(&[0xffff0010])),
+            Some(RecordValue::Address(0xffff0010)));
+        assert_eq!(decode(DTRACEACT_UADDR, &u64s(&[4242, 0x400010])),
+            Some(RecordValue::UserAddress { pid: 4242, addr: 0x400010 }));
+    }
+
+    #[test]
+    fn skips_records_formatted_by_libdtrace() {
+        assert_eq!(decode(DTRACEACT_PRINTF, &[0; 8]), None);
+        assert_eq!(decode(DTRACEACT_DIFEXPR, &[]), None);
+
+        let formatted = libdtrace::dtrace_recdesc_t {
+            dtrd_format: 1,
+            ..rec(DTRACEACT_DIFEXPR, 8)
+        };
+        assert_eq!(unsafe { decode_value([0; 8].as_ptr(), &formatted) }, None);
+    }
+
+    #[test]
+    fn describes_runtime_faults() {
+        let mut pd = libdtrace::dtrace_probedesc_t::default();
+        set_str(&mut pd.dtpd_provider, "syscall");
+        set_str(&mut pd.dtpd_mod, "freebsd");
+        set_str(&mut pd.dtpd_func, "open");
+        set_str(&mut pd.dtpd_name, "entry");
+        let msg = CString::new("invalid address (0x0) in action #1\n").unwrap();
+        let fault = CString::new("invalid address").unwrap();
+        let data = libdtrace::dtrace_errdata_t {
+            dteda_pdesc: &mut pd,
+            dteda_offset: 12,
+            dteda_msg: msg.as_ptr(),
+            ..Default::default()
+        };
+
+        let record = unsafe { describe_error(&data, fault.as_ptr()) };
+        assert_eq!(record, ErrorRecord {
+            provider: "syscall".to_string(),
+            module: "freebsd".to_string(),
+            function: "open".to_string(),
+            name: "entry".to_string(),
+            fault: "invalid address".to_string(),
+            offset: 12,
+            message: "invalid address (0x0) in action #1".to_string(),
+        });
+
+        // Faults outside of a probe (such as in a BEGIN clause's
+        // predicate) have no probe description
+        let data = libdtrace::dtrace_errdata_t::default();
+        let record = unsafe { describe_error(&data, ptr::null()) };
+        assert_eq!(record, ErrorRecord::default());
+    }
+}
@@ -0,0 +1,75 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::collections::BTreeMap;
+use super::ddtrace_record::StatsRecord;
+use super::ratelimit::FilterStats;
+
+// Status of a running instrumentation script, reported upstream together
+// with the filter's counters
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScriptStatus {
+    // Runtime faults raised by the D program
+    pub errors: u64,
+    // Cumulative count of the records dropped by DTrace, by kind of drop
+    pub drops: BTreeMap<String, u64>,
+    // Last lifecycle event reported for each traced process, by pid
+    pub processes: BTreeMap<u64, String>,
+    // Effective value of the DTrace options set by the agent or the script
+    pub options: BTreeMap<String, i64>,
+}
+
+impl ScriptStatus {
+    pub fn stats_record(&self, stats: FilterStats) -> StatsRecord {
+        StatsRecord {
+            passed: stats.passed,
+            sampled_out: stats.sampled_out,
+            rate_limited: stats.rate_limited,
+            errors: self.errors,
+            drops: self.drops.clone(),
+            processes: self.processes.clone(),
+            options: self.options.clone(),
+        }
+    }
+}
+
+// Returns true if the stats record reports discarded or dropped records or
+// errors, process lifecycle or option changes beyond those already
+// reported
+pub fn is_news(record: &StatsRecord, reported: &StatsRecord) -> bool {
+    record.sampled_out != reported.sampled_out ||
+        record.rate_limited != reported.rate_limited ||
+        record.errors != reported.errors ||
+        record.drops != reported.drops ||
+        record.processes != reported.processes ||
+        record.options != reported.options
+}
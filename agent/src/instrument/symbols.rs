@@ -0,0 +1,255 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+use std::collections::HashMap;
+use std::os::raw::c_char;
+use super::ddtrace_record::{fold_stack, Frame, RecordValue};
+use super::backend::Session;
+use super::libdtrace;
+use super::record::{c_str, optional_str};
+
+// Maximum number of addresses held in each of the symbol caches
+// (the cache is emptied once full)
+const SYMBOL_CACHE_SIZE: usize = 65536;
+
+// Length of the buffer dtrace_uaddr2str() formats user symbols into
+const UADDR_BUFSIZE: usize = 256;
+
+// How stack() and ustack() records are reported
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackFormat {
+    // Raw addresses
+    Raw,
+    // Frames resolved to module, symbol and offset
+    Symbols,
+    // Resolved frames folded into a single line for flame graphs
+    Folded,
+}
+
+impl StackFormat {
+    pub fn from_name(name: &str) -> Option<StackFormat> {
+        match name {
+            "raw" => Some(StackFormat::Raw),
+            "symbols" => Some(StackFormat::Symbols),
+            "folded" => Some(StackFormat::Folded),
+            _ => None,
+        }
+    }
+}
+
+// Resolves the addresses in stack records to symbols, caching the
+// symbols of kernel and (per process) user addresses
+pub struct Symbolizer {
+    format: StackFormat,
+    kernel: HashMap<u64, Frame>,
+    user: HashMap<(u64, u64), Frame>,
+}
+
+impl Symbolizer {
+    pub fn new(format: StackFormat) -> Symbolizer {
+        Symbolizer {
+            format: format,
+            kernel: HashMap::new(),
+            user: HashMap::new(),
+        }
+    }
+
+    // Replace the raw addresses of a stack record with its symbols
+    // (other records are returned unchanged)
+    pub fn symbolize(&mut self, session: &Session, value: RecordValue) -> RecordValue {
+        if self.format == StackFormat::Raw {
+            return value;
+        }
+
+        match value {
+            RecordValue::Stack(addrs) => {
+                let frames = addrs.iter().map(|addr| self.kernel_frame(session, *addr)).collect();
+                self.stack(None, frames)
+            },
+            RecordValue::UserStack { pid, frames: addrs } => {
+                let frames = addrs.iter()
+                    .map(|addr| self.user_frame(session, pid, *addr))
+                    .collect();
+                self.stack(Some(pid), frames)
+            },
+            value => value,
+        }
+    }
+
+    fn stack(&self, pid: Option<u64>, frames: Vec<Frame>) -> RecordValue {
+        match (self.format, pid) {
+            (StackFormat::Folded, _) => RecordValue::Folded(fold_stack(&frames)),
+            (_, Some(pid)) => RecordValue::UserSymbolStack { pid: pid, frames: frames },
+            (_, None) => RecordValue::SymbolStack(frames),
+        }
+    }
+
+    fn kernel_frame(&mut self, session: &Session, addr: u64) -> Frame {
+        if let Some(frame) = self.kernel.get(&addr) {
+            return frame.clone();
+        }
+
+        let frame = session.lookup_kernel(addr);
+        if self.kernel.len() >= SYMBOL_CACHE_SIZE {
+            self.kernel.clear();
+        }
+        self.kernel.insert(addr, frame.clone());
+        frame
+    }
+
+    fn user_frame(&mut self, session: &Session, pid: u64, addr: u64) -> Frame {
+        if let Some(frame) = self.user.get(&(pid, addr)) {
+            return frame.clone();
+        }
+
+        let frame = session.lookup_user(pid, addr);
+        if self.user.len() >= SYMBOL_CACHE_SIZE {
+            self.user.clear();
+        }
+        self.user.insert((pid, addr), frame.clone());
+        frame
+    }
+}
+
+// Resolve a kernel address with dtrace_lookup_by_addr()
+pub fn lookup_kernel(handle: *mut libdtrace::dtrace_hdl_t, addr: u64) -> Frame {
+    let mut sym: libdtrace::GElf_Sym = Default::default();
+    let mut info: libdtrace::dtrace_syminfo_t = Default::default();
+    let found = unsafe {
+        libdtrace::dtrace_lookup_by_addr(handle, addr, &mut sym, &mut info) == 0
+    };
+    if !found {
+        return Frame { addr: addr, ..Default::default() };
+    }
+
+    Frame {
+        addr: addr,
+        module: optional_str(info.dts_object).unwrap_or_default(),
+        symbol: optional_str(info.dts_name).unwrap_or_default(),
+        offset: addr - sym.st_value,
+    }
+}
+
+// Resolve a user address with dtrace_uaddr2str(), which formats it as
+// module`symbol+0xoffset, module`0xaddr or 0xaddr
+pub fn lookup_user(handle: *mut libdtrace::dtrace_hdl_t, pid: u64, addr: u64) -> Frame {
+    let mut buf = [0 as c_char; UADDR_BUFSIZE];
+    let formatted = unsafe {
+        libdtrace::dtrace_uaddr2str(handle, pid as libdtrace::pid_t, addr,
+            buf.as_mut_ptr(), UADDR_BUFSIZE as ::std::os::raw::c_int);
+        c_str(buf.as_ptr())
+    };
+    parse_uaddr(addr, &formatted)
+}
+
+fn parse_uaddr(addr: u64, formatted: &str) -> Frame {
+    let mut frame = Frame { addr: addr, ..Default::default() };
+    let (module, symbol) = match formatted.find('`') {
+        Some(i) => (&formatted[..i], &formatted[i + 1..]),
+        None => ("", formatted),
+    };
+    frame.module = module.to_string();
+    if symbol.starts_with("0x") {
+        return frame;
+    }
+
+    match symbol.rfind("+0x") {
+        Some(i) => {
+            frame.symbol = symbol[..i].to_string();
+            frame.offset = u64::from_str_radix(&symbol[i + 3..], 16).unwrap_or(0);
+        },
+        None => frame.symbol = symbol.to_string(),
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::backend::Backend;
+    use super::super::mock::MockBackend;
+
+    fn frame(addr: u64, module: &str, symbol: &str, offset: u64) -> Frame {
+        Frame {
+            addr: addr,
+            module: module.to_string(),
+            symbol: symbol.to_string(),
+            offset: offset,
+        }
+    }
+
+    #[test]
+    fn parses_formatted_user_addresses() {
+        assert_eq!(parse_uaddr(0x400a10, "libc.so.7`malloc+0x10"),
+            frame(0x400a10, "libc.so.7", "malloc", 0x10));
+        assert_eq!(parse_uaddr(0x400a00, "libc.so.7`malloc"),
+            frame(0x400a00, "libc.so.7", "malloc", 0));
+        assert_eq!(parse_uaddr(0x400a00, "sshd`0x400a00"), frame(0x400a00, "sshd", "", 0));
+        assert_eq!(parse_uaddr(0x400a00, "0x400a00"), frame(0x400a00, "", "", 0));
+    }
+
+    #[test]
+    fn caches_resolved_addresses() {
+        let backend = MockBackend::default();
+        let session = backend.open().unwrap();
+        let mut symbolizer = Symbolizer::new(StackFormat::Symbols);
+
+        let stack = RecordValue::Stack(vec![0xffff0010, 0xffff0020, 0xffff0010]);
+        assert_eq!(symbolizer.symbolize(&*session, stack.clone()), RecordValue::SymbolStack(vec![
+            frame(0xffff0010, "kernel", "", 0), frame(0xffff0020, "kernel", "", 0),
+            frame(0xffff0010, "kernel", "", 0)]));
+        symbolizer.symbolize(&*session, stack);
+        let ustack = RecordValue::UserStack { pid: 812, frames: vec![0x400a10] };
+        symbolizer.symbolize(&*session, ustack.clone());
+        symbolizer.symbolize(&*session, ustack);
+
+        // Each address is looked up once
+        assert_eq!(*backend.lookups.borrow(), vec![0xffff0010, 0xffff0020, 0x400a10]);
+    }
+
+    #[test]
+    fn folds_stacks_outermost_frame_first() {
+        let backend = MockBackend::default();
+        let session = backend.open().unwrap();
+        let stack = RecordValue::Stack(vec![0xffff0010, 0xffff0020]);
+
+        let mut folded = Symbolizer::new(StackFormat::Folded);
+        assert_eq!(folded.symbolize(&*session, stack.clone()),
+            RecordValue::Folded("kernel`0xffff0020;kernel`0xffff0010".to_string()));
+
+        // Raw stacks and other records are left alone
+        let mut raw = Symbolizer::new(StackFormat::Raw);
+        assert_eq!(raw.symbolize(&*session, stack.clone()), stack);
+        assert_eq!(folded.symbolize(&*session, RecordValue::Int(812)), RecordValue::Int(812));
+        assert_eq!(backend.lookups.borrow().len(), 2);
+    }
+}
@@ -47,17 +47,28 @@ extern crate chan_signal;
 extern crate dtrace_rust;
 
 extern crate libloading;
+extern crate flate2;
 
 use std::ffi::CString;
-use dtrace_rust::instrument::InstrumentationThreadMessage;
-use dtrace_rust::instrument::instrument_endpoint;
+use dtrace_rust::instrument::{Endpoint, InstrumentationError, InstrumentationThreadMessage,
+    ProbeCatalog, ProbePattern, ProgramInfo};
+use dtrace_rust::instrument::{instrument_endpoint, probe_catalog, validate_script,
+    watch_probe_catalog};
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use docopt::Docopt;
+use rustc_serialize::{json, Decodable, Decoder, Encodable, Encoder};
 use std::collections;
 use std::default::Default;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
 use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
 use std::time::Duration;
-use zookeeper::{acls, CreateMode, Watcher, WatchedEvent, WatchedEventType, ZkResult, ZkState, ZooKeeper};
+use zookeeper::{acls, CreateMode, Watcher, WatchedEvent, WatchedEventType, ZkError, ZkResult,
+    ZkState, ZooKeeper};
 use zookeeper::recipes::cache::{PathChildrenCache, PathChildrenCacheEvent};
 use chan_signal::Signal;
 
@@ -72,10 +83,14 @@ DTrace Rust consumer
 
 Usage:
     ddtrace_rust [options]
+    ddtrace_rust validate [options] <script>
+    ddtrace_rust probes [options] [<pattern>]
 
 Options:
     -h, --help  Displays this message    
     -z <zookeeper_cluster>, --zookeeper <zookeeper_cluster>  Zookeeper cluster 
+    -l <library>, --library <library>  Directory of the D files scripts include [default: library]
+    -c <cache>, --cache <cache>  Directory of the cached compiled programs [default: cache]
 ";
 
 // Host agent information
@@ -86,18 +101,42 @@ const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 const DDTRACE_PATH: &'static str = "/ddtrace";
 const DDTRACE_ENDPOINTS_PATH: &'static str = "/ddtrace/endpoints";
 const DDTRACE_INSTRUMENTATION_PATH: &'static str = "/ddtrace/instrumentation";
+const DDTRACE_STATUS_PATH: &'static str = "/ddtrace/status";
+const DDTRACE_VALIDATION_PATH: &'static str = "/ddtrace/validation";
+const DDTRACE_LIBRARY_PATH: &'static str = "/ddtrace/library";
+const DDTRACE_CATALOG_PATH: &'static str = "/ddtrace/catalog";
+const DDTRACE_QUERIES_PATH: &'static str = "/ddtrace/queries";
+
+// Interval between checks for loaded or unloaded kernel modules
+const CATALOG_REFRESH_SECS: u64 = 60;
+
+// ZooKeeper's default limit on the data of a node (jute.maxbuffer), less
+// room for the rest of the request
+const ZK_MAX_DATA: usize = 1023 * 1024;
 
 struct InstrumentedEndpoint {
    instrumentation: Mutex<collections::HashMap<String, Instrumentation>>,
+   // Last status of each script, by script id
+   status: Mutex<collections::HashMap<String, ScriptState>>,
+   // Latest probe catalog, against which queries are answered
+   catalog: Mutex<Option<ProbeCatalog>>,
    name: String,
+   // Directory of the D files scripts include
+   library: String,
+   // Directory of the cached compiled programs
+   cache: String,
    zk: Arc<ZooKeeper>,
 }
 
 impl InstrumentedEndpoint {
-   fn new(zk: Arc<ZooKeeper>) -> InstrumentedEndpoint {
+   fn new(zk: Arc<ZooKeeper>, library: String, cache: String) -> InstrumentedEndpoint {
       InstrumentedEndpoint {
          instrumentation: Mutex::new(collections::HashMap::new()),
+         status: Mutex::new(collections::HashMap::new()),
+         catalog: Mutex::new(None),
          name: ddtrace_gethostname().unwrap(), 
+         library: library,
+         cache: cache,
          zk: zk,
       }
    }
@@ -108,9 +147,153 @@ struct Instrumentation {
    script: String,
 }
 
-#[derive(RustcDecodable)]
+// Status of an instrumentation script, published to the script's node
+// under the endpoint's status path
+#[derive(Clone, Debug)]
+struct ScriptState {
+   state: &'static str,
+   error: Option<ScriptError>,
+}
+
+// Failure of an instrumentation script, with the D compiler's location of
+// the fault when the script failed to compile
+#[derive(Clone, Debug)]
+struct ScriptError {
+   kind: &'static str,
+   message: String,
+   tag: Option<String>,
+   file: Option<String>,
+   line: Option<u32>,
+}
+
+impl ScriptError {
+   fn new(e: &InstrumentationError) -> ScriptError {
+      let compile = e.compile_error();
+      ScriptError {
+         kind: e.kind(),
+         message: match compile {
+            Some(c) => c.message.clone(),
+            None => e.to_string(),
+         },
+         tag: compile.and_then(|c| c.tag.clone()),
+         file: compile.and_then(|c| c.file.clone()),
+         line: compile.and_then(|c| c.line),
+      }
+   }
+}
+
+impl ScriptState {
+   fn running() -> ScriptState {
+      ScriptState { state: "running", error: None }
+   }
+
+   fn finished(result: &Result<(), InstrumentationError>) -> ScriptState {
+      match *result {
+         Ok(()) => ScriptState { state: "stopped", error: None },
+         Err(ref e) => ScriptState { state: "failed", error: Some(ScriptError::new(e)) },
+      }
+   }
+}
+
+// Outcome of compiling a script without running it
+#[derive(Debug)]
+struct Validation {
+   valid: bool,
+   program: Option<ProgramInfo>,
+   error: Option<ScriptError>,
+}
+
+impl Validation {
+   fn new(result: Result<ProgramInfo, InstrumentationError>) -> Validation {
+      match result {
+         Ok(info) => Validation { valid: true, program: Some(info), error: None },
+         Err(e) => Validation { valid: false, program: None, error: Some(ScriptError::new(&e)) },
+      }
+   }
+}
+
+// Answer to a probe query, which fails until the endpoint has published
+// its first catalog (rather than matching no probes)
+#[derive(Debug)]
+struct QueryResult {
+   probes: Option<Vec<String>>,
+   error: Option<String>,
+}
+
+impl QueryResult {
+   fn new(probes: Option<Vec<String>>) -> QueryResult {
+      match probes {
+         Some(probes) => QueryResult { probes: Some(probes), error: None },
+         None => QueryResult { probes: None, error: Some("catalog not ready".to_string()) },
+      }
+   }
+}
+
 struct Args {
     flag_z: String,
+    flag_library: String,
+    flag_cache: String,
+    cmd_validate: bool,
+    arg_script: String,
+    cmd_probes: bool,
+    arg_pattern: Option<String>,
+}
+
+// The JSON published to ZooKeeper and the command line arguments are
+// encoded and decoded field by field, as #[derive(RustcEncodable)] and
+// #[derive(RustcDecodable)] did before current compilers dropped them
+impl Encodable for ScriptState {
+   fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+      e.emit_struct("ScriptState", 2, |e| {
+         e.emit_struct_field("state", 0, |e| self.state.encode(e))?;
+         e.emit_struct_field("error", 1, |e| self.error.encode(e))
+      })
+   }
+}
+
+impl Encodable for ScriptError {
+   fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+      e.emit_struct("ScriptError", 5, |e| {
+         e.emit_struct_field("kind", 0, |e| self.kind.encode(e))?;
+         e.emit_struct_field("message", 1, |e| self.message.encode(e))?;
+         e.emit_struct_field("tag", 2, |e| self.tag.encode(e))?;
+         e.emit_struct_field("file", 3, |e| self.file.encode(e))?;
+         e.emit_struct_field("line", 4, |e| self.line.encode(e))
+      })
+   }
+}
+
+impl Encodable for Validation {
+   fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+      e.emit_struct("Validation", 3, |e| {
+         e.emit_struct_field("valid", 0, |e| self.valid.encode(e))?;
+         e.emit_struct_field("program", 1, |e| self.program.encode(e))?;
+         e.emit_struct_field("error", 2, |e| self.error.encode(e))
+      })
+   }
+}
+
+impl Encodable for QueryResult {
+   fn encode<E: Encoder>(&self, e: &mut E) -> Result<(), E::Error> {
+      e.emit_struct("QueryResult", 2, |e| {
+         e.emit_struct_field("probes", 0, |e| self.probes.encode(e))?;
+         e.emit_struct_field("error", 1, |e| self.error.encode(e))
+      })
+   }
+}
+
+impl Decodable for Args {
+   fn decode<D: Decoder>(d: &mut D) -> Result<Args, D::Error> {
+      d.read_struct("Args", 7, |d| Ok(Args {
+         flag_z: d.read_struct_field("flag_z", 0, Decodable::decode)?,
+         flag_library: d.read_struct_field("flag_library", 1, Decodable::decode)?,
+         flag_cache: d.read_struct_field("flag_cache", 2, Decodable::decode)?,
+         cmd_validate: d.read_struct_field("cmd_validate", 3, Decodable::decode)?,
+         arg_script: d.read_struct_field("arg_script", 4, Decodable::decode)?,
+         cmd_probes: d.read_struct_field("cmd_probes", 5, Decodable::decode)?,
+         arg_pattern: d.read_struct_field("arg_pattern", 6, Decodable::decode)?,
+      }))
+   }
 }
 
 struct LoggingWatcher;
@@ -197,6 +380,281 @@ fn register_endpoint(endpoint: Arc<InstrumentedEndpoint>) -> ZkResult<String> {
     Ok(value)
 } 
 
+fn create_persistent(zk: &ZooKeeper, path: &str) -> ZkResult<()> {
+    match zk.create(path, Vec::new(), acls::OPEN_ACL_UNSAFE.clone(), CreateMode::Persistent) {
+        Ok(_) | Err(ZkError::NodeExists) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn publish_status(endpoint: &InstrumentedEndpoint, script_id: &str, status: ScriptState)
+    -> ZkResult<()> {
+
+    // Publish the status in the script's ephemeral status node, so that
+    // whoever deployed the script sees its failures, and the status of all
+    // the endpoint's scripts in the endpoint's status node (the lock is held
+    // so that concurrent updates are published in order)
+    let data = json::encode(&status).unwrap().into_bytes();
+    let mut scripts = endpoint.status.lock().unwrap();
+    scripts.insert(script_id.to_string(), status);
+
+    let endpoint_status_path = format!("{}/{}", DDTRACE_STATUS_PATH, endpoint.name);
+    try!(create_persistent(&endpoint.zk, DDTRACE_STATUS_PATH));
+    try!(create_persistent(&endpoint.zk, endpoint_status_path.as_ref()));
+    try!(endpoint.zk.set_data(endpoint_status_path.as_ref(),
+        status_report(&scripts).into_bytes(), -1));
+
+    let script_status_path = format!("{}/{}", endpoint_status_path, script_id);
+    match endpoint.zk.set_data(script_status_path.as_ref(), data.clone(), -1) {
+        Ok(_) => Ok(()),
+        Err(ZkError::NoNode) => {
+            try!(endpoint.zk.create(
+                script_status_path.as_ref(),
+                data,
+                acls::OPEN_ACL_UNSAFE.clone(),
+                CreateMode::Ephemeral));
+            Ok(())
+        },
+        Err(e) => Err(e),
+    }
+}
+
+// The status of each of the endpoint's scripts, by script id
+fn status_report(status: &collections::HashMap<String, ScriptState>) -> String {
+    let scripts: collections::BTreeMap<&String, &ScriptState> = status.iter().collect();
+    json::encode(&scripts).unwrap()
+}
+
+fn process_library(endpoint: Arc<InstrumentedEndpoint>) -> ZkResult<()> {
+
+    // Keep the endpoint's library in step with the D files published in
+    // the library path, so scripts may include them
+    if let Err(e) = fs::create_dir_all(&endpoint.library) {
+        error!("failed creating library {}: {}", endpoint.library, e);
+    }
+
+    let mut pcc = PathChildrenCache::new(endpoint.zk.clone(), DDTRACE_LIBRARY_PATH).unwrap();
+
+    let _pcc_subscription = pcc.add_listener(move |e| {
+        match e {
+            PathChildrenCacheEvent::ChildAdded(file, file_data) |
+            PathChildrenCacheEvent::ChildUpdated(file, file_data) => {
+                let name = file.rsplit('/').next().unwrap_or("");
+                let path = Path::new(&endpoint.library).join(name);
+                match fs::File::create(&path).and_then(|mut f| f.write_all(&file_data[..])) {
+                    Ok(_) => info!("updated library file {}", name),
+                    Err(e) => error!("failed writing library file {:?}: {}", path, e),
+                }
+            },
+            PathChildrenCacheEvent::ChildRemoved(file) => {
+                let name = file.rsplit('/').next().unwrap_or("");
+                let path = Path::new(&endpoint.library).join(name);
+                match fs::remove_file(&path) {
+                    Ok(_) => info!("removed library file {}", name),
+                    Err(e) => warn!("failed removing library file {:?}: {}", path, e),
+                }
+            },
+            _ => { trace!("PathChildrenCacheEvent {:?}", e); }
+        }
+    });
+
+    try!(pcc.start());
+    info!("cache started {}", DDTRACE_LIBRARY_PATH);
+    Ok(())
+}
+
+fn process_validation(endpoint: Arc<InstrumentedEndpoint>) -> ZkResult<()> {
+
+    // Compile the scripts submitted to the endpoint's validation path,
+    // publishing the outcome in each request's ephemeral result node
+    let endpoint_validation_path = format!("{}/{}",
+        DDTRACE_VALIDATION_PATH, endpoint.name);
+
+    let mut pcc = PathChildrenCache::new(endpoint.zk.clone(),
+        endpoint_validation_path.as_ref()).unwrap();
+
+    let _pcc_subscription = pcc.add_listener(move |e| {
+        match e {
+            PathChildrenCacheEvent::ChildAdded(request, script_data) => {
+                let script_str = String::from_utf8_lossy(
+                    &script_data[..]).into_owned();
+                let script_id = request.rsplit('/').next().unwrap_or("").to_string();
+                let validated = Endpoint {
+                    hostname: endpoint.name.clone(),
+                    library: endpoint.library.clone(),
+                    cache: None,
+                    agent_name: NAME,
+                    agent_version: VERSION,
+                };
+
+                // Compile the script in a thread of its own rather than on
+                // the cache's event thread (the result node is ephemeral, so
+                // it's removed with the endpoint's session)
+                let validation_endpoint = endpoint.clone();
+                let builder = thread::Builder::new();
+                if let Err(e) = builder.spawn(move || {
+                    let validation = Validation::new(
+                        validate_script(validated, script_id, script_str));
+                    info!("validated {}: {}", request, validation.valid);
+                    let data = json::encode(&validation).unwrap().into_bytes();
+                    if let Err(e) = validation_endpoint.zk.create(
+                        format!("{}/result", request).as_ref(),
+                        data,
+                        acls::OPEN_ACL_UNSAFE.clone(),
+                        CreateMode::Ephemeral) {
+                        warn!("failed publishing validation of {}: {:?}", request, e);
+                    } }) {
+                    error!("failed spawning validation thread: {}", e);
+                }
+            },
+            _ => { trace!("PathChildrenCacheEvent {:?}", e); }
+        }
+    });
+
+    try!(pcc.start());
+    info!("cache started {}", endpoint_validation_path);
+    Ok(())
+}
+
+// The catalog gzipped, in chunks of at most the given size
+fn catalog_chunks(catalog: &ProbeCatalog, size: usize) -> io::Result<Vec<Vec<u8>>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+    try!(encoder.write_all(catalog.to_json().as_bytes()));
+    let data = try!(encoder.finish());
+    Ok(data.chunks(size).map(|chunk| chunk.to_vec()).collect())
+}
+
+// Delete the node and its children, ignoring those already deleted
+fn delete_tree(zk: &ZooKeeper, path: &str) -> ZkResult<()> {
+    let children = match zk.get_children(path, false) {
+        Ok(children) => children,
+        Err(ZkError::NoNode) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for child in children {
+        try!(delete_tree(zk, format!("{}/{}", path, child).as_ref()));
+    }
+    match zk.delete(path, -1) {
+        Ok(_) | Err(ZkError::NoNode) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// The data of the endpoint's catalog node: the generation holding the
+// current catalog and its number of chunks
+fn catalog_generation(generation: &str, count: usize) -> Vec<u8> {
+    format!("{} {}", generation, count).into_bytes()
+}
+
+fn publish_catalog(endpoint: &InstrumentedEndpoint, chunks: Vec<Vec<u8>>) -> ZkResult<()> {
+
+    // Publish the gzipped catalog in the ephemeral chunk nodes 0..n of a
+    // new generation node of the endpoint's catalog node (the full listing
+    // can exceed ZooKeeper's limit on node data even compressed), then
+    // switch the catalog node to that generation, so that readers never
+    // see a mix of two catalogs' chunks
+    try!(create_persistent(&endpoint.zk, DDTRACE_CATALOG_PATH));
+    let catalog_path = format!("{}/{}", DDTRACE_CATALOG_PATH, endpoint.name);
+    try!(create_persistent(&endpoint.zk, catalog_path.as_ref()));
+
+    let generation_path = try!(endpoint.zk.create(
+        format!("{}/generation-", catalog_path).as_ref(),
+        Vec::new(),
+        acls::OPEN_ACL_UNSAFE.clone(),
+        CreateMode::PersistentSequential));
+    let generation = generation_path.rsplit('/').next().unwrap_or("").to_string();
+
+    let count = chunks.len();
+    let published = chunks.into_iter().enumerate().map(|(index, chunk)| {
+        endpoint.zk.create(
+            format!("{}/{}", generation_path, index).as_ref(),
+            chunk,
+            acls::OPEN_ACL_UNSAFE.clone(),
+            CreateMode::Ephemeral)
+    }).collect::<ZkResult<Vec<String>>>();
+    if let Err(e) = published.and_then(|_| endpoint.zk.set_data(catalog_path.as_ref(),
+        catalog_generation(&generation, count), -1)) {
+        let _ = delete_tree(&endpoint.zk, generation_path.as_ref());
+        return Err(e);
+    }
+
+    // Remove the earlier generations (including any left by an agent that
+    // exited before removing them)
+    for child in try!(endpoint.zk.get_children(catalog_path.as_ref(), false)) {
+        if child != generation {
+            try!(delete_tree(&endpoint.zk, format!("{}/{}", catalog_path, child).as_ref()));
+        }
+    }
+    Ok(())
+}
+
+fn process_catalog(endpoint: Arc<InstrumentedEndpoint>)
+    -> Result<mpsc::Sender<InstrumentationThreadMessage>, String> {
+
+    // Enumerate the endpoint's probes in a thread of its own, publishing
+    // the catalog again whenever kernel modules are loaded or unloaded
+    let (tx, rx) = mpsc::channel();
+    let builder = thread::Builder::new();
+    match builder.spawn(move || {
+        let publish = |catalog: &ProbeCatalog| {
+            info!("publishing catalog of {} probes", catalog.probes.len());
+            match catalog_chunks(catalog, ZK_MAX_DATA) {
+                Ok(chunks) => if let Err(e) = publish_catalog(&endpoint, chunks) {
+                    warn!("failed publishing probe catalog: {:?}", e);
+                },
+                Err(e) => warn!("failed compressing probe catalog: {}", e),
+            }
+            *endpoint.catalog.lock().unwrap() = Some(catalog.clone());
+        };
+        let interval = Duration::from_secs(CATALOG_REFRESH_SECS);
+        if let Err(e) = watch_probe_catalog(interval, &publish, rx) {
+            error!("stopped refreshing probe catalog: {}", e);
+        } }) {
+        Ok(_child) => Ok(tx),
+        Err(e) => Err(format!("failed spawning probe catalog thread: {}", e)),
+    }
+}
+
+fn process_queries(endpoint: Arc<InstrumentedEndpoint>) -> ZkResult<()> {
+
+    // Answer the probe patterns submitted to the endpoint's queries path
+    // (provider:module:function:name, each a glob) from its latest catalog,
+    // in each query's ephemeral result node
+    let endpoint_queries_path = format!("{}/{}", DDTRACE_QUERIES_PATH, endpoint.name);
+
+    let mut pcc = PathChildrenCache::new(endpoint.zk.clone(),
+        endpoint_queries_path.as_ref()).unwrap();
+
+    let _pcc_subscription = pcc.add_listener(move |e| {
+        match e {
+            PathChildrenCacheEvent::ChildAdded(request, pattern_data) => {
+                let pattern = ProbePattern::parse(
+                    String::from_utf8_lossy(&pattern_data[..]).trim());
+                let result = QueryResult::new(endpoint.catalog.lock().unwrap().as_ref()
+                    .map(|catalog| catalog.query(&pattern).iter()
+                        .map(|probe| probe.to_string()).collect()));
+                match result.probes {
+                    Some(ref probes) => info!("queried {}: {} probes", request, probes.len()),
+                    None => warn!("queried {} before the probe catalog", request),
+                }
+                let data = json::encode(&result).unwrap().into_bytes();
+                if let Err(e) = endpoint.zk.create(
+                    format!("{}/result", request).as_ref(),
+                    data,
+                    acls::OPEN_ACL_UNSAFE.clone(),
+                    CreateMode::Ephemeral) {
+                    warn!("failed publishing query result of {}: {:?}", request, e);
+                }
+            },
+            _ => { trace!("PathChildrenCacheEvent {:?}", e); }
+        }
+    });
+
+    try!(pcc.start());
+    info!("cache started {}", endpoint_queries_path);
+    Ok(())
+}
+
 fn process_instrumentation(endpoint: Arc<InstrumentedEndpoint>) -> ZkResult<()> { 
 
     // Process all instrumentation present in the endpoint's Zookeeper path 
@@ -215,12 +673,31 @@ fn process_instrumentation(endpoint: Arc<InstrumentedEndpoint>) -> ZkResult<()>
                 let script_str_copy = script_str.clone();
                 info!("received event {}", script_str_copy);
 
+                // The script is identified by its ZooKeeper node name
+                let script_id = script.rsplit('/').next().unwrap_or("").to_string();
+                let instrumented = Endpoint {
+                    hostname: endpoint.name.clone(),
+                    library: endpoint.library.clone(),
+                    cache: Some(endpoint.cache.clone()),
+                    agent_name: NAME,
+                    agent_version: VERSION,
+                };
+
                 // Start a new thread for the requested instrumentation 
                 let (tx, rx): (mpsc::Sender<InstrumentationThreadMessage>,
                     mpsc::Receiver<InstrumentationThreadMessage>) = mpsc::channel();
+                if let Err(e) = publish_status(&endpoint, &script_id, ScriptState::running()) {
+                    warn!("failed publishing status of {}: {:?}", script_id, e);
+                }
+                let status_endpoint = endpoint.clone();
                 let builder = thread::Builder::new();       
                 match builder.spawn(move || {
-                    instrument_endpoint(script_str, rx); }) {
+                    let result = instrument_endpoint(instrumented, script_id.clone(),
+                        script_str, rx);
+                    if let Err(e) = publish_status(&status_endpoint, &script_id,
+                        ScriptState::finished(&result)) {
+                        warn!("failed publishing status of {}: {:?}", script_id, e);
+                    } }) {
                     Ok(_child) => {
                         trace!("spawned instrumentation thread");
 
@@ -278,13 +755,54 @@ fn main() {
       .and_then(|d| d.decode())
       .unwrap_or_else(|e| e.exit());
 
+   // Compile the script without running it, reporting the program
+   if args.cmd_validate {
+      let script_id = Path::new(&args.arg_script).file_stem()
+         .map(|stem| stem.to_string_lossy().into_owned())
+         .unwrap_or_default();
+      let validated = Endpoint {
+         hostname: ddtrace_gethostname().unwrap_or_default(),
+         library: args.flag_library.clone(),
+         cache: None,
+         agent_name: NAME,
+         agent_version: VERSION,
+      };
+      let mut script = String::new();
+      if let Err(e) = fs::File::open(&args.arg_script)
+         .and_then(|mut file| file.read_to_string(&mut script)) {
+         eprintln!("could not read {}: {}", args.arg_script, e);
+         process::exit(2);
+      }
+      let validation = Validation::new(validate_script(validated, script_id, script));
+      println!("{}", json::as_pretty_json(&validation));
+      process::exit(if validation.valid { 0 } else { 1 });
+   }
+
+   // List the endpoint's probes matching the pattern, as dtrace -l does
+   if args.cmd_probes {
+      let pattern = ProbePattern::parse(args.arg_pattern.as_ref().map_or("", |p| p.as_ref()));
+      match probe_catalog() {
+         Ok(catalog) => {
+            for probe in catalog.query(&pattern) {
+               println!("{}", probe);
+            }
+            process::exit(0);
+         },
+         Err(e) => {
+            eprintln!("could not list probes: {}", e);
+            process::exit(2);
+         }
+      }
+   }
+
    // Create a connection to ZooKeeper
    info!("connecting to ZooKeeper {}", args.flag_z);
    match ZooKeeper::connect(&*args.flag_z, Duration::from_secs(5),
       LoggingWatcher) {
       Ok(zk) => {
          let endpoint_arc = Arc::new(
-            InstrumentedEndpoint::new(Arc::new(zk)));
+            InstrumentedEndpoint::new(Arc::new(zk), args.flag_library.clone(),
+               args.flag_cache.clone()));
 
          // Register for changes in the ZooKeeper state
          // (currently unused, but could re-establish connections and so on)
@@ -300,6 +818,19 @@ fn main() {
          match register_endpoint(endpoint) {
              Ok(value) => {
 
+                 if let Err(e) = process_library(endpoint_arc.clone()) {
+                     warn!("error watching library {:?}", e);
+                 }
+                 if let Err(e) = process_validation(endpoint_arc.clone()) {
+                     warn!("error watching validation requests {:?}", e);
+                 }
+                 let catalog = process_catalog(endpoint_arc.clone());
+                 if let Err(ref e) = catalog {
+                     warn!("{}", e);
+                 }
+                 if let Err(e) = process_queries(endpoint_arc.clone()) {
+                     warn!("error watching probe queries {:?}", e);
+                 }
                  match process_instrumentation(endpoint_arc.clone()) {
                      Ok(_subscription) => {
                          info!("value {}", value);
@@ -317,6 +848,11 @@ fn main() {
                          error!("error registering endpoint with Zookeeper {:?}", e);
                      }
                  }
+
+                 // Stop refreshing the probe catalog
+                 if let Ok(tx) = catalog {
+                     let _ = tx.send(InstrumentationThreadMessage::Stop);
+                 }
              },
              Err(e) => {
                  error!("error registering endpoint with Zookeeper {:?}", e);
@@ -346,3 +882,62 @@ fn main() {
    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dtrace_rust::instrument::ProbeDescription;
+    use flate2::read::GzDecoder;
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn reports_the_status_of_every_script() {
+        let mut status = collections::HashMap::new();
+        status.insert("syscalls".to_string(), ScriptState::running());
+        status.insert("sshd".to_string(), ScriptState::finished(
+            &Err(InstrumentationError::Config("missing script".to_string()))));
+
+        let report = Json::from_str(&status_report(&status)).unwrap();
+        let state = |script: &str| report.find_path(&[script, "state"])
+            .and_then(|state| state.as_string()).map(|state| state.to_string());
+        assert_eq!(state("syscalls"), Some("running".to_string()));
+        assert_eq!(state("sshd"), Some("failed".to_string()));
+        assert_eq!(report.find_path(&["sshd", "error", "kind"]).and_then(|k| k.as_string()),
+            Some("config"));
+        assert_eq!(report.find_path(&["syscalls", "error"]), Some(&Json::Null));
+    }
+
+    #[test]
+    fn fails_queries_before_the_first_catalog() {
+        let result = |probes| Json::from_str(&json::encode(&QueryResult::new(probes)).unwrap())
+            .unwrap();
+        let ready = result(Some(vec!["syscall:freebsd:read:entry".to_string()]));
+        assert_eq!(ready.find("probes").and_then(|p| p.as_array()).map(|p| p.len()), Some(1));
+        assert_eq!(ready.find("error"), Some(&Json::Null));
+        let unready = result(None);
+        assert_eq!(unready.find("probes"), Some(&Json::Null));
+        assert_eq!(unready.find("error").and_then(|e| e.as_string()), Some("catalog not ready"));
+    }
+
+    #[test]
+    fn splits_gzipped_catalog_into_chunks() {
+        let probe = |function: &str| ProbeDescription {
+            provider: "syscall".to_string(),
+            module: "freebsd".to_string(),
+            function: function.to_string(),
+            name: "entry".to_string(),
+        };
+        let catalog = ProbeCatalog {
+            probes: vec![probe("read"), probe("readv"), probe("write"), probe("writev")],
+            ..Default::default()
+        };
+
+        let chunks = catalog_chunks(&catalog, 64).unwrap();
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 64));
+
+        let data: Vec<u8> = chunks.concat();
+        let mut json = String::new();
+        GzDecoder::new(&data[..]).unwrap().read_to_string(&mut json).unwrap();
+        assert_eq!(json, catalog.to_json());
+    }
+}
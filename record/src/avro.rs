@@ -0,0 +1,600 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+// Avro binary encoding driven by a JSON schema
+//
+// Values are written against the schema's field, symbol and union branch
+// names (rather than their positions), so that a datum can't silently
+// diverge from the schema consumers decode it with: a value that doesn't
+// fit the schema, or a missing field without a default, is an error.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use serde_json::{self, Value as Json};
+
+// An Avro datum
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Int(i32),
+    Long(i64),
+    String(String),
+    Bytes(Vec<u8>),
+    Fixed(Vec<u8>),
+    // An enum's symbol
+    Enum(String),
+    Array(Vec<Value>),
+    Map(BTreeMap<String, Value>),
+    // A named record's fields (fields omitted take the schema's default)
+    Record(String, Vec<(String, Value)>),
+}
+
+impl Value {
+    // The record's field
+    pub fn field(&self, name: &str) -> Option<&Value> {
+        match *self {
+            Value::Record(_, ref fields) => fields.iter()
+                .find(|&(field, _)| field == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Type {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Array(Box<Type>),
+    Map(Box<Type>),
+    Union(Vec<Type>),
+    // A reference to a named type, by its full name
+    Named(String),
+}
+
+#[derive(Clone, Debug)]
+struct Field {
+    name: String,
+    schema: Type,
+    default: Option<Json>,
+}
+
+#[derive(Clone, Debug)]
+enum Named {
+    Record(Vec<Field>),
+    Enum(Vec<String>),
+    Fixed(usize),
+}
+
+// A parsed Avro schema
+#[derive(Clone, Debug)]
+pub struct Schema {
+    root: Type,
+    names: HashMap<String, Named>,
+}
+
+// The unqualified part of a full name
+fn short_name(fullname: &str) -> &str {
+    fullname.rsplit('.').next().unwrap_or(fullname)
+}
+
+impl Schema {
+    pub fn parse(schema: &str) -> Result<Schema, String> {
+        let json: Json = serde_json::from_str(schema).map_err(|e| e.to_string())?;
+        let mut names = HashMap::new();
+        let root = parse_type(&json, "", &mut names)?;
+        let schema = Schema { root, names };
+        schema.check(&schema.root, &mut HashSet::new())?;
+        Ok(schema)
+    }
+
+    // Check that every named type referenced is defined
+    fn check(&self, schema: &Type, checked: &mut HashSet<String>) -> Result<(), String> {
+        match *schema {
+            Type::Array(ref items) | Type::Map(ref items) => self.check(items, checked),
+            Type::Union(ref branches) =>
+                branches.iter().try_for_each(|branch| self.check(branch, checked)),
+            // Records may refer to themselves, so each is checked once
+            Type::Named(ref name) if checked.insert(name.clone()) => match self.names.get(name) {
+                Some(Named::Record(fields)) =>
+                    fields.iter().try_for_each(|field| self.check(&field.schema, checked)),
+                Some(_) => Ok(()),
+                None => Err(format!("undefined type {}", name)),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    // Encode the value as a datum of the schema
+    pub fn encode(&self, value: &Value) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        self.write(&self.root, value, &mut buf)?;
+        Ok(buf)
+    }
+
+    // Decode a datum of the schema, returning it and the remaining data
+    pub fn decode<'a>(&self, data: &'a [u8]) -> Result<(Value, &'a [u8]), String> {
+        let mut data = data;
+        let value = self.read(&self.root, &mut data)?;
+        Ok((value, data))
+    }
+
+    // Whether the value is an instance of the schema (choosing the branch
+    // of a union)
+    fn matches(&self, schema: &Type, value: &Value) -> bool {
+        match (schema, value) {
+            (Type::Null, Value::Null) |
+            (Type::Boolean, Value::Boolean(_)) |
+            (Type::Int, Value::Int(_)) |
+            (Type::Long, Value::Long(_)) |
+            (Type::Long, Value::Int(_)) |
+            (Type::String, Value::String(_)) |
+            (Type::Bytes, Value::Bytes(_)) |
+            (Type::Array(_), Value::Array(_)) |
+            (Type::Map(_), Value::Map(_)) => true,
+            (Type::Named(name), value) => match (self.names.get(name), value) {
+                (Some(Named::Record(_)), Value::Record(record, _)) =>
+                    short_name(name) == short_name(record),
+                (Some(Named::Enum(symbols)), Value::Enum(symbol)) =>
+                    symbols.contains(symbol),
+                (Some(Named::Fixed(size)), Value::Fixed(bytes)) => bytes.len() == *size,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn write(&self, schema: &Type, value: &Value, buf: &mut Vec<u8>) -> Result<(), String> {
+        match (schema, value) {
+            (Type::Null, Value::Null) => Ok(()),
+            (Type::Boolean, Value::Boolean(b)) => {
+                buf.push(*b as u8);
+                Ok(())
+            },
+            (Type::Int, Value::Int(v)) => {
+                write_long(buf, *v as i64);
+                Ok(())
+            },
+            (Type::Long, Value::Long(v)) => {
+                write_long(buf, *v);
+                Ok(())
+            },
+            (Type::Long, Value::Int(v)) => {
+                write_long(buf, *v as i64);
+                Ok(())
+            },
+            (Type::String, Value::String(s)) => {
+                write_bytes(buf, s.as_bytes());
+                Ok(())
+            },
+            (Type::Bytes, Value::Bytes(bytes)) => {
+                write_bytes(buf, bytes);
+                Ok(())
+            },
+            (Type::Array(items), Value::Array(values)) => {
+                if !values.is_empty() {
+                    write_long(buf, values.len() as i64);
+                    for value in values {
+                        self.write(items, value, buf)?;
+                    }
+                }
+                write_long(buf, 0);
+                Ok(())
+            },
+            (Type::Map(items), Value::Map(values)) => {
+                if !values.is_empty() {
+                    write_long(buf, values.len() as i64);
+                    for (key, value) in values {
+                        write_bytes(buf, key.as_bytes());
+                        self.write(items, value, buf)?;
+                    }
+                }
+                write_long(buf, 0);
+                Ok(())
+            },
+            (Type::Union(branches), value) => {
+                match branches.iter().position(|branch| self.matches(branch, value)) {
+                    Some(index) => {
+                        write_long(buf, index as i64);
+                        self.write(&branches[index], value, buf)
+                    },
+                    None => Err(format!("no union branch for {:?}", value)),
+                }
+            },
+            (Type::Named(name), value) => self.write_named(name, value, buf),
+            (schema, value) => Err(format!("{:?} is not a {:?}", value, schema)),
+        }
+    }
+
+    fn write_named(&self, name: &str, value: &Value, buf: &mut Vec<u8>) -> Result<(), String> {
+        match (self.names.get(name), value) {
+            (Some(Named::Record(fields)), Value::Record(record, values))
+                if short_name(record) == short_name(name) => {
+
+                if let Some((unknown, _)) = values.iter()
+                    .find(|&(field, _)| !fields.iter().any(|f| f.name == *field)) {
+                    return Err(format!("{} has no field {}", name, unknown));
+                }
+                for field in fields {
+                    match value.field(&field.name) {
+                        Some(value) => self.write(&field.schema, value, buf)?,
+                        None => match field.default {
+                            Some(ref default) => {
+                                let value = self.default_value(&field.schema, default)?;
+                                self.write(&field.schema, &value, buf)?;
+                            },
+                            None => return Err(format!("{} missing field {}", name, field.name)),
+                        },
+                    }
+                }
+                Ok(())
+            },
+            (Some(Named::Enum(symbols)), Value::Enum(symbol)) => {
+                match symbols.iter().position(|s| s == symbol) {
+                    Some(index) => {
+                        write_long(buf, index as i64);
+                        Ok(())
+                    },
+                    None => Err(format!("{} has no symbol {}", name, symbol)),
+                }
+            },
+            (Some(Named::Fixed(size)), Value::Fixed(bytes)) if bytes.len() == *size => {
+                buf.extend_from_slice(bytes);
+                Ok(())
+            },
+            (Some(_), value) => Err(format!("{:?} is not a {}", value, name)),
+            (None, _) => Err(format!("undefined type {}", name)),
+        }
+    }
+
+    // The value of a field's default (a union's default is of its first
+    // branch)
+    fn default_value(&self, schema: &Type, default: &Json) -> Result<Value, String> {
+        let value = match (schema, default) {
+            (Type::Null, &Json::Null) => Value::Null,
+            (Type::Boolean, &Json::Bool(b)) => Value::Boolean(b),
+            (Type::Int, Json::Number(n)) if n.is_i64() =>
+                Value::Int(n.as_i64().unwrap() as i32),
+            (Type::Long, Json::Number(n)) if n.is_i64() =>
+                Value::Long(n.as_i64().unwrap()),
+            (Type::String, Json::String(s)) => Value::String(s.clone()),
+            (Type::Array(items), Json::Array(values)) => Value::Array(
+                values.iter().map(|v| self.default_value(items, v))
+                    .collect::<Result<Vec<Value>, String>>()?),
+            (Type::Map(items), Json::Object(values)) => Value::Map(
+                values.iter().map(|(k, v)| Ok((k.clone(), self.default_value(items, v)?)))
+                    .collect::<Result<BTreeMap<String, Value>, String>>()?),
+            (Type::Union(branches), default) if !branches.is_empty() =>
+                self.default_value(&branches[0], default)?,
+            (Type::Named(name), Json::String(symbol)) => match self.names.get(name) {
+                Some(Named::Enum(_)) => Value::Enum(symbol.clone()),
+                _ => return Err(format!("unsupported default for {}", name)),
+            },
+            (schema, default) => return Err(format!("invalid default {} for {:?}",
+                default, schema)),
+        };
+        Ok(value)
+    }
+
+    fn read(&self, schema: &Type, data: &mut &[u8]) -> Result<Value, String> {
+        let value = match *schema {
+            Type::Null => Value::Null,
+            Type::Boolean => Value::Boolean(take(data, 1)?[0] != 0),
+            Type::Int => Value::Int(read_long(data)? as i32),
+            Type::Long => Value::Long(read_long(data)?),
+            Type::Float => Value::Bytes(take(data, 4)?.to_vec()),
+            Type::Double => Value::Bytes(take(data, 8)?.to_vec()),
+            Type::Bytes => Value::Bytes(read_bytes(data)?.to_vec()),
+            Type::String => Value::String(String::from_utf8(read_bytes(data)?.to_vec())
+                .map_err(|e| e.to_string())?),
+            Type::Array(ref items) => {
+                let mut values = Vec::new();
+                while let Some(count) = read_block(data)? {
+                    for _ in 0..count {
+                        values.push(self.read(items, data)?);
+                    }
+                }
+                Value::Array(values)
+            },
+            Type::Map(ref items) => {
+                let mut values = BTreeMap::new();
+                while let Some(count) = read_block(data)? {
+                    for _ in 0..count {
+                        let key = String::from_utf8(read_bytes(data)?.to_vec())
+                            .map_err(|e| e.to_string())?;
+                        values.insert(key, self.read(items, data)?);
+                    }
+                }
+                Value::Map(values)
+            },
+            Type::Union(ref branches) => {
+                let index = read_long(data)?;
+                match branches.get(index as usize) {
+                    Some(branch) if index >= 0 => self.read(branch, data)?,
+                    _ => return Err(format!("invalid union branch {}", index)),
+                }
+            },
+            Type::Named(ref name) => match self.names.get(name) {
+                Some(Named::Record(fields)) => {
+                    let mut values = Vec::new();
+                    for field in fields {
+                        values.push((field.name.clone(), self.read(&field.schema, data)?));
+                    }
+                    Value::Record(short_name(name).to_string(), values)
+                },
+                Some(Named::Enum(symbols)) => {
+                    let index = read_long(data)?;
+                    match symbols.get(index as usize) {
+                        Some(symbol) if index >= 0 => Value::Enum(symbol.clone()),
+                        _ => return Err(format!("invalid {} symbol {}", name, index)),
+                    }
+                },
+                Some(Named::Fixed(size)) => Value::Fixed(take(data, *size)?.to_vec()),
+                None => return Err(format!("undefined type {}", name)),
+            },
+        };
+        Ok(value)
+    }
+}
+
+// The full name of a named type, defined in the namespace
+fn fullname(name: &str, namespace: &str) -> String {
+    if name.contains('.') || namespace.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", namespace, name)
+    }
+}
+
+fn parse_type(json: &Json, namespace: &str, names: &mut HashMap<String, Named>)
+    -> Result<Type, String> {
+
+    match *json {
+        Json::String(ref name) => Ok(match name.as_str() {
+            "null" => Type::Null,
+            "boolean" => Type::Boolean,
+            "int" => Type::Int,
+            "long" => Type::Long,
+            "float" => Type::Float,
+            "double" => Type::Double,
+            "bytes" => Type::Bytes,
+            "string" => Type::String,
+            name => Type::Named(fullname(name, namespace)),
+        }),
+        Json::Array(ref branches) => Ok(Type::Union(branches.iter()
+            .map(|branch| parse_type(branch, namespace, names))
+            .collect::<Result<Vec<Type>, String>>()?)),
+        Json::Object(ref object) => {
+            let kind = object.get("type").ok_or("type without a type")?;
+            let kind = match *kind {
+                Json::String(ref kind) => kind.as_str(),
+                ref other => return parse_type(other, namespace, names),
+            };
+            match kind {
+                "array" => Ok(Type::Array(Box::new(parse_type(
+                    object.get("items").ok_or("array without items")?, namespace, names)?))),
+                "map" => Ok(Type::Map(Box::new(parse_type(
+                    object.get("values").ok_or("map without values")?, namespace, names)?))),
+                "record" | "error" | "enum" | "fixed" => {
+                    let name = object.get("name").and_then(Json::as_str)
+                        .ok_or("named type without a name")?;
+                    let namespace = object.get("namespace").and_then(Json::as_str)
+                        .unwrap_or(namespace);
+                    let fullname = fullname(name, namespace);
+                    // Names within the type are relative to its namespace
+                    let namespace = match fullname.rfind('.') {
+                        Some(dot) => fullname[..dot].to_string(),
+                        None => String::new(),
+                    };
+                    let named = match kind {
+                        "enum" => Named::Enum(object.get("symbols").and_then(Json::as_array)
+                            .ok_or("enum without symbols")?
+                            .iter().map(|s| s.as_str().map(|s| s.to_string())
+                                .ok_or_else(|| "invalid symbol".to_string()))
+                            .collect::<Result<Vec<String>, String>>()?),
+                        "fixed" => Named::Fixed(object.get("size").and_then(Json::as_u64)
+                            .ok_or("fixed without a size")? as usize),
+                        _ => {
+                            // Defined before its fields, which may refer to it
+                            names.insert(fullname.clone(), Named::Record(Vec::new()));
+                            let mut fields = Vec::new();
+                            for field in object.get("fields").and_then(Json::as_array)
+                                .ok_or("record without fields")? {
+                                fields.push(Field {
+                                    name: field.get("name").and_then(Json::as_str)
+                                        .ok_or("field without a name")?.to_string(),
+                                    schema: parse_type(field.get("type")
+                                        .ok_or("field without a type")?, &namespace, names)?,
+                                    default: field.get("default").cloned(),
+                                });
+                            }
+                            Named::Record(fields)
+                        },
+                    };
+                    names.insert(fullname.clone(), named);
+                    Ok(Type::Named(fullname))
+                },
+                primitive => parse_type(&Json::String(primitive.to_string()), namespace, names),
+            }
+        },
+        ref other => Err(format!("invalid schema {}", other)),
+    }
+}
+
+// int and long are zig-zag encoded variable length integers
+pub fn write_long(buf: &mut Vec<u8>, v: i64) {
+    let mut n = ((v << 1) ^ (v >> 63)) as u64;
+    while n & !0x7f != 0 {
+        buf.push(((n & 0x7f) | 0x80) as u8);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_long(buf, bytes.len() as i64);
+    buf.extend_from_slice(bytes);
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if data.len() < len {
+        return Err("truncated datum".to_string());
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+fn read_long(data: &mut &[u8]) -> Result<i64, String> {
+    let mut n: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let byte = take(data, 1)?[0];
+        if shift > 63 {
+            return Err("invalid variable length integer".to_string());
+        }
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = read_long(data)?;
+    if len < 0 {
+        return Err("negative length".to_string());
+    }
+    take(data, len as usize)
+}
+
+// The number of items in the next block of an array or map (None at the
+// end), skipping the byte size of negatively counted blocks
+fn read_block(data: &mut &[u8]) -> Result<Option<u64>, String> {
+    match read_long(data)? {
+        0 => Ok(None),
+        count if count < 0 => {
+            read_long(data)?;
+            Ok(Some(count.unsigned_abs()))
+        },
+        count => Ok(Some(count as u64)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"{
+        "type": "record", "name": "Datum", "namespace": "test.avro",
+        "fields": [
+            { "name": "id", "type": { "type": "fixed", "name": "ID", "size": 2 } },
+            { "name": "kind", "type": { "type": "enum", "name": "Kind",
+                "symbols": [ "A", "B" ] }, "default": "B" },
+            { "name": "parent", "type": [ "null", "ID" ], "default": null },
+            { "name": "body", "type": [
+                { "type": "record", "name": "Text", "fields": [
+                    { "name": "text", "type": "string" } ] },
+                { "type": "record", "name": "Count", "fields": [
+                    { "name": "count", "type": "long" },
+                    { "name": "tags", "type": { "type": "array", "items": "string" },
+                      "default": [] } ] } ] },
+            { "name": "version", "type": "string", "default": "1" }
+        ]
+    }"#;
+
+    fn count(n: i64) -> Value {
+        Value::Record("Count".to_string(), vec![("count".to_string(), Value::Long(n))])
+    }
+
+    #[test]
+    fn zigzag_encodes_longs() {
+        let mut buf = Vec::new();
+        for v in &[0, -1, 1, -64, 64, 1000] {
+            write_long(&mut buf, *v);
+        }
+        assert_eq!(buf, vec![0x00, 0x01, 0x02, 0x7f, 0x80, 0x01, 0xd0, 0x0f]);
+
+        let mut data = &buf[..];
+        let decoded: Vec<i64> = (0..6).map(|_| read_long(&mut data).unwrap()).collect();
+        assert_eq!(decoded, vec![0, -1, 1, -64, 64, 1000]);
+    }
+
+    #[test]
+    fn encodes_by_name_with_defaults() {
+        let schema = Schema::parse(SCHEMA).unwrap();
+        let datum = Value::Record("Datum".to_string(), vec![
+            ("body".to_string(), count(3)),
+            ("id".to_string(), Value::Fixed(vec![7, 8])),
+        ]);
+        let encoded = schema.encode(&datum).unwrap();
+        assert_eq!(encoded, vec![7, 8, 0x02, 0x00, 0x02, 0x06, 0x00, 0x02, b'1']);
+
+        let (decoded, rest) = schema.decode(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.field("kind"), Some(&Value::Enum("B".to_string())));
+        assert_eq!(decoded.field("parent"), Some(&Value::Null));
+        assert_eq!(decoded.field("body").and_then(|body| body.field("count")),
+            Some(&Value::Long(3)));
+    }
+
+    #[test]
+    fn rejects_values_not_in_schema() {
+        let schema = Schema::parse(SCHEMA).unwrap();
+        let datum = |fields: Vec<(&str, Value)>| Value::Record("Datum".to_string(),
+            fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect());
+
+        // A missing field without a default
+        assert!(schema.encode(&datum(vec![("body", count(1))])).is_err());
+        // An unknown field, symbol and union branch
+        let id = ("id", Value::Fixed(vec![0, 0]));
+        assert!(schema.encode(&datum(vec![id.clone(), ("body", count(1)),
+            ("extra", Value::Null)])).is_err());
+        assert!(schema.encode(&datum(vec![id.clone(), ("body", count(1)),
+            ("kind", Value::Enum("C".to_string()))])).is_err());
+        assert!(schema.encode(&datum(vec![id, ("body", Value::Long(1))])).is_err());
+    }
+
+    #[test]
+    fn rejects_undefined_types() {
+        assert!(Schema::parse(r#"{ "type": "record", "name": "R",
+            "fields": [ { "name": "u", "type": "UUID" } ] }"#).is_err());
+    }
+}
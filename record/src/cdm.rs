@@ -0,0 +1,579 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+// CADETS Common Data Model (CDM) Avro encoding
+//
+// Probe firings from the syscall and audit providers are mapped onto
+// CDM Principal, Subject, FileObject and Event records and written using
+// the Avro binary encoding of the CDM 18 schema in schema/cdm.avsc. DTrace
+// records are untyped, so the meaning of each value traced by the D script
+// is given (positionally) by the names in CdmEncoder's field list.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::hash::Hash;
+pub use uuid::Uuid;
+use avro::{Schema, Value};
+use {Envelope, Error, ProbeRecord, RecordValue};
+
+pub const CDM_VERSION: &str = "18";
+
+// The Avro schema the encoder writes
+pub const SCHEMA: &str = include_str!("../schema/cdm.avsc");
+
+// Schema registry framing magic byte
+const MAGIC_BYTE: u8 = 0;
+
+const SOURCE_FREEBSD_DTRACE_CADETS: &str = "SOURCE_FREEBSD_DTRACE_CADETS";
+
+// Number of principals, processes and files remembered as already
+// emitted; once forgotten, they're emitted again (under the same UUID)
+const SEEN_CAPACITY: usize = 16384;
+
+// Map a system call (or audit event) name onto a CDM EventType
+pub fn event_type(function: &str) -> &'static str {
+    // Audit events are named aue_<syscall>[_<variant>]
+    let syscall = match function.strip_prefix("aue_") {
+        Some(event) => event.split('_').next().unwrap_or(""),
+        None => function,
+    };
+
+    match syscall {
+        "accept" | "accept4" => "EVENT_ACCEPT",
+        "bind" | "bindat" => "EVENT_BIND",
+        "setuid" | "seteuid" | "setgid" | "setegid" | "setreuid" | "setregid" |
+        "setresuid" | "setresgid" | "setlogin" => "EVENT_CHANGE_PRINCIPAL",
+        "access" | "eaccess" | "faccessat" | "stat" | "fstat" | "lstat" |
+        "fstatat" => "EVENT_CHECK_FILE_ATTRIBUTES",
+        "close" | "closefrom" => "EVENT_CLOSE",
+        "connect" | "connectat" => "EVENT_CONNECT",
+        "mkdir" | "mkdirat" | "mkfifo" | "mkfifoat" | "mknod" | "mknodat" |
+        "socket" | "pipe" | "pipe2" => "EVENT_CREATE_OBJECT",
+        "thr_new" => "EVENT_CREATE_THREAD",
+        "dup" | "dup2" => "EVENT_DUP",
+        "execve" | "fexecve" => "EVENT_EXECUTE",
+        "exit" => "EVENT_EXIT",
+        "fcntl" => "EVENT_FCNTL",
+        "fork" | "vfork" | "rfork" | "pdfork" => "EVENT_FORK",
+        "link" | "linkat" | "symlink" | "symlinkat" => "EVENT_LINK",
+        "lseek" => "EVENT_LSEEK",
+        "mmap" => "EVENT_MMAP",
+        "chmod" | "fchmod" | "fchmodat" | "lchmod" | "chown" | "fchown" |
+        "fchownat" | "lchown" | "chflags" | "fchflags" | "lchflags" |
+        "utimes" | "futimes" | "futimesat" | "utimensat" => "EVENT_MODIFY_FILE_ATTRIBUTES",
+        "mount" | "nmount" => "EVENT_MOUNT",
+        "mprotect" => "EVENT_MPROTECT",
+        "open" | "openat" => "EVENT_OPEN",
+        "read" | "pread" | "readv" | "preadv" => "EVENT_READ",
+        "recvfrom" => "EVENT_RECVFROM",
+        "recvmsg" => "EVENT_RECVMSG",
+        "rename" | "renameat" => "EVENT_RENAME",
+        "sendmsg" => "EVENT_SENDMSG",
+        "sendto" => "EVENT_SENDTO",
+        "kill" => "EVENT_SIGNAL",
+        "truncate" | "ftruncate" => "EVENT_TRUNCATE",
+        "unmount" => "EVENT_UMOUNT",
+        "unlink" | "unlinkat" | "rmdir" => "EVENT_UNLINK",
+        "wait4" | "wait6" => "EVENT_WAIT",
+        "write" | "pwrite" | "writev" | "pwritev" => "EVENT_WRITE",
+        _ => "EVENT_OTHER",
+    }
+}
+
+fn named(name: &str, fields: Vec<(&str, Value)>) -> Value {
+    Value::Record(name.to_string(), fields.into_iter()
+        .map(|(field, value)| (field.to_string(), value)).collect())
+}
+
+fn uuid(uuid: &Uuid) -> Value {
+    Value::Fixed(uuid.as_bytes().to_vec())
+}
+
+fn string(s: &str) -> Value {
+    Value::String(s.to_string())
+}
+
+fn optional<T, F: FnOnce(T) -> Value>(value: Option<T>, f: F) -> Value {
+    value.map(f).unwrap_or(Value::Null)
+}
+
+fn properties(properties: BTreeMap<String, String>) -> Value {
+    if properties.is_empty() {
+        Value::Null
+    } else {
+        Value::Map(properties.into_iter().map(|(k, v)| (k, Value::String(v))).collect())
+    }
+}
+
+// The most recently emitted values, up to a capacity
+struct Seen<T> {
+    capacity: usize,
+    values: HashSet<T>,
+    order: VecDeque<T>,
+}
+
+impl<T: Clone + Eq + Hash> Seen<T> {
+    fn new(capacity: usize) -> Seen<T> {
+        Seen { capacity, values: HashSet::new(), order: VecDeque::new() }
+    }
+
+    // Whether the value wasn't already seen, forgetting the oldest value
+    // when full
+    fn insert(&mut self, value: &T) -> bool {
+        if self.values.contains(value) {
+            return false;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+        self.values.insert(value.clone());
+        self.order.push_back(value.clone());
+        true
+    }
+}
+
+// Attributes of a probe firing named by the encoder's field list
+#[derive(Debug, Default)]
+struct Fields {
+    pid: Option<i64>,
+    ppid: Option<i64>,
+    tid: Option<i64>,
+    uid: Option<i64>,
+    gid: Option<i64>,
+    execname: Option<String>,
+    cmdline: Option<String>,
+    path: Option<String>,
+    fd: Option<i64>,
+    size: Option<i64>,
+    properties: BTreeMap<String, String>,
+}
+
+impl Fields {
+    fn new(names: &[String], values: &[RecordValue]) -> Fields {
+        let mut fields: Fields = Default::default();
+        for (name, value) in names.iter().zip(values.iter()) {
+            match (name.as_str(), value) {
+                ("pid", RecordValue::Int(v)) => fields.pid = Some(*v),
+                ("ppid", RecordValue::Int(v)) => fields.ppid = Some(*v),
+                ("tid", RecordValue::Int(v)) => fields.tid = Some(*v),
+                ("uid", RecordValue::Int(v)) => fields.uid = Some(*v),
+                ("gid", RecordValue::Int(v)) => fields.gid = Some(*v),
+                ("fd", RecordValue::Int(v)) => fields.fd = Some(*v),
+                ("size", RecordValue::Int(v)) |
+                ("retval", RecordValue::Int(v)) => fields.size = Some(*v),
+                ("execname", RecordValue::String(v)) => fields.execname = Some(v.clone()),
+                ("cmdline", RecordValue::String(v)) => fields.cmdline = Some(v.clone()),
+                ("path", RecordValue::String(v)) => fields.path = Some(v.clone()),
+                (_, RecordValue::Int(v)) => {
+                    fields.properties.insert(name.clone(), v.to_string());
+                },
+                (_, RecordValue::String(v)) => {
+                    fields.properties.insert(name.clone(), v.clone());
+                },
+                _ => {}
+            }
+        }
+        fields
+    }
+
+    // The process's user ID, if traced
+    fn user_id(&self) -> String {
+        self.uid.map(|uid| uid.to_string()).unwrap_or("unknown".to_string())
+    }
+}
+
+pub struct CdmEncoder {
+    schema: Schema,
+    host_id: Uuid,
+    fields: Vec<String>,
+    schema_id: Option<u32>,
+    sequence: i64,
+    host: bool,
+    principals: Seen<String>,
+    subjects: Seen<i64>,
+    files: Seen<String>,
+}
+
+impl CdmEncoder {
+    // host_id is the endpoint's kern.hostuuid, schema_id (if given) enables
+    // schema registry framing of each datum
+    pub fn new(host_id: Uuid, fields: Vec<String>, schema_id: Option<u32>) -> CdmEncoder {
+        CdmEncoder {
+            schema: Schema::parse(SCHEMA).expect("invalid CDM schema"),
+            host_id,
+            fields,
+            schema_id,
+            sequence: 0,
+            host: false,
+            principals: Seen::new(SEEN_CAPACITY),
+            subjects: Seen::new(SEEN_CAPACITY),
+            files: Seen::new(SEEN_CAPACITY),
+        }
+    }
+
+    fn principal_uuid(&self, user_id: &str) -> Uuid {
+        Uuid::new_v5(&self.host_id, &format!("principal:{}", user_id))
+    }
+
+    fn subject_uuid(&self, pid: i64) -> Uuid {
+        Uuid::new_v5(&self.host_id, &format!("subject:{}", pid))
+    }
+
+    fn file_uuid(&self, path: &str) -> Uuid {
+        Uuid::new_v5(&self.host_id, &format!("file:{}", path))
+    }
+
+    // Encode the probe firing as a sequence of CDM datums, each framed as a
+    // message of its own: the Principal, Subject and FileObject (the first
+    // time the user, process or file is seen) followed by the Event
+    pub fn encode(&mut self, record: &ProbeRecord) -> Result<Vec<Vec<u8>>, Error> {
+        self.encode_with(record, BTreeMap::new())
+    }
+
+    // Encode an enveloped probe firing, preceded by the Host the first time,
+    // recording the envelope's metadata in the Event's properties (the host
+    // is already the datum's hostId)
+    pub fn encode_envelope(&mut self, envelope: &Envelope<ProbeRecord>)
+        -> Result<Vec<Vec<u8>>, Error> {
+
+        let metadata = &envelope.metadata;
+        let mut encoded = Vec::new();
+        if !self.host {
+            let datum = named("Host", vec![
+                ("uuid", uuid(&self.host_id)),
+                ("hostName", string(&metadata.hostname)),
+                ("hostType", Value::Enum("HOST_SERVER".to_string())),
+            ]);
+            encoded.push(self.frame(datum)?);
+            self.host = true;
+        }
+
+        let mut properties = BTreeMap::new();
+        properties.insert("ddtrace_hostname".to_string(), metadata.hostname.clone());
+        properties.insert("ddtrace_agent".to_string(),
+            format!("{}/{}", metadata.agent_name, metadata.agent_version));
+        properties.insert("ddtrace_script".to_string(), metadata.script_id.clone());
+        properties.insert("ddtrace_run_epoch".to_string(), metadata.run_epoch.to_string());
+        properties.insert("ddtrace_emitted_at".to_string(), envelope.emitted_at.to_string());
+        encoded.extend(self.encode_with(&envelope.record, properties)?);
+        Ok(encoded)
+    }
+
+    fn encode_with(&mut self, record: &ProbeRecord,
+        properties: BTreeMap<String, String>) -> Result<Vec<Vec<u8>>, Error> {
+
+        let fields = Fields::new(&self.fields, &record.values);
+        let mut encoded = Vec::new();
+
+        let subject = fields.pid.map(|pid| self.subject_uuid(pid));
+        if let Some(pid) = fields.pid {
+            let user_id = fields.user_id();
+            if self.principals.insert(&user_id) {
+                let datum = self.principal(&user_id, &fields);
+                encoded.push(self.frame(datum)?);
+            }
+            if self.subjects.insert(&pid) {
+                let datum = self.subject(pid, &user_id, &fields, record.timestamp);
+                encoded.push(self.frame(datum)?);
+            }
+        }
+
+        let object = fields.path.as_ref().map(|path| self.file_uuid(path));
+        if let Some(ref path) = fields.path {
+            if self.files.insert(path) {
+                let datum = self.file_object(path, &fields);
+                encoded.push(self.frame(datum)?);
+            }
+        }
+
+        let datum = self.event(record, &fields, subject, object, properties);
+        encoded.push(self.frame(datum)?);
+        self.sequence += 1;
+        Ok(encoded)
+    }
+
+    // Wrap the record in a TCCDMDatum and encode it, preceded by the schema
+    // id if there is one
+    fn frame(&self, datum: Value) -> Result<Vec<u8>, Error> {
+        let datum = named("TCCDMDatum", vec![
+            ("datum", datum),
+            ("CDMVersion", string(CDM_VERSION)),
+            ("hostId", uuid(&self.host_id)),
+            ("source", Value::Enum(SOURCE_FREEBSD_DTRACE_CADETS.to_string())),
+        ]);
+        let datum = self.schema.encode(&datum).map_err(Error::Avro)?;
+        let mut encoded = Vec::with_capacity(datum.len() + 5);
+        if let Some(schema_id) = self.schema_id {
+            encoded.push(MAGIC_BYTE);
+            encoded.extend_from_slice(&[
+                (schema_id >> 24) as u8, (schema_id >> 16) as u8,
+                (schema_id >> 8) as u8, schema_id as u8]);
+        }
+        encoded.extend(datum);
+        Ok(encoded)
+    }
+
+    fn principal(&self, user_id: &str, fields: &Fields) -> Value {
+        named("Principal", vec![
+            ("uuid", uuid(&self.principal_uuid(user_id))),
+            ("type", Value::Enum("PRINCIPAL_LOCAL".to_string())),
+            ("userId", string(user_id)),
+            ("groupIds", Value::Array(fields.gid.iter()
+                .map(|gid| Value::String(gid.to_string())).collect())),
+        ])
+    }
+
+    fn subject(&self, pid: i64, user_id: &str, fields: &Fields, timestamp: u64) -> Value {
+        let mut subject_properties = BTreeMap::new();
+        if let Some(ref execname) = fields.execname {
+            subject_properties.insert("name".to_string(), execname.clone());
+        }
+        named("Subject", vec![
+            ("uuid", uuid(&self.subject_uuid(pid))),
+            ("type", Value::Enum("SUBJECT_PROCESS".to_string())),
+            ("cid", Value::Int(pid as i32)),
+            ("parentSubject", optional(fields.ppid, |ppid| uuid(&self.subject_uuid(ppid)))),
+            ("localPrincipal", uuid(&self.principal_uuid(user_id))),
+            ("startTimestampNanos", Value::Long(timestamp as i64)),
+            ("cmdLine", optional(fields.cmdline.as_ref().or(fields.execname.as_ref()),
+                |s| string(s))),
+            ("properties", properties(subject_properties)),
+        ])
+    }
+
+    fn file_object(&self, path: &str, fields: &Fields) -> Value {
+        let mut object_properties = BTreeMap::new();
+        object_properties.insert("path".to_string(), path.to_string());
+        named("FileObject", vec![
+            ("uuid", uuid(&self.file_uuid(path))),
+            ("baseObject", named("AbstractObject", vec![
+                ("properties", properties(object_properties)),
+            ])),
+            ("type", Value::Enum("FILE_OBJECT_FILE".to_string())),
+            ("fileDescriptor", optional(fields.fd, |fd| Value::Int(fd as i32))),
+        ])
+    }
+
+    fn event(&self, record: &ProbeRecord, fields: &Fields,
+        subject: Option<Uuid>, object: Option<Uuid>,
+        mut event_properties: BTreeMap<String, String>) -> Value {
+
+        let event_uuid = Uuid::new_v5(&self.host_id, &format!("event:{}:{}:{}:{}",
+            record.id, record.cpu, record.timestamp, self.sequence));
+        event_properties.extend(fields.properties.clone());
+        if !record.output.is_empty() {
+            event_properties.insert("output".to_string(), record.output.clone());
+        }
+        named("Event", vec![
+            ("uuid", uuid(&event_uuid)),
+            ("sequence", Value::Long(self.sequence)),
+            ("type", Value::Enum(event_type(record.function.as_str()).to_string())),
+            ("threadId", optional(fields.tid, |tid| Value::Int(tid as i32))),
+            ("subject", optional(subject, |u| uuid(&u))),
+            ("predicateObject", optional(object, |u| uuid(&u))),
+            ("predicateObjectPath", optional(fields.path.as_ref(), |s| string(s))),
+            ("timestampNanos", Value::Long(record.timestamp as i64)),
+            ("name", string(&format!("{}:{}:{}:{}", record.provider, record.module,
+                record.function, record.name))),
+            ("size", optional(fields.size, Value::Long)),
+            ("properties", properties(event_properties)),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_id() -> Uuid {
+        Uuid::parse_str("b3f5e1d2-8f3a-11e7-a3a5-0800279c1a47").unwrap()
+    }
+
+    fn open_entry(pid: i64, path: &str) -> ProbeRecord {
+        ProbeRecord {
+            id: 42,
+            provider: "syscall".to_string(),
+            module: "freebsd".to_string(),
+            function: "open".to_string(),
+            name: "entry".to_string(),
+            cpu: 0,
+            timestamp: 1000,
+            values: vec![
+                RecordValue::Int(pid),
+                RecordValue::Int(1001),
+                RecordValue::String("sshd".to_string()),
+                RecordValue::String(path.to_string()),
+            ],
+            output: String::new(),
+        }
+    }
+
+    fn fields() -> Vec<String> {
+        vec!["pid".to_string(), "uid".to_string(), "execname".to_string(), "path".to_string()]
+    }
+
+    // Decode the datums with the CDM schema, returning each datum's record
+    fn decode(encoded: &[Vec<u8>], framed: bool) -> Vec<Value> {
+        let schema = Schema::parse(SCHEMA).unwrap();
+        encoded.iter().map(|message| {
+            let mut data = &message[..];
+            if framed {
+                assert_eq!(data[0], MAGIC_BYTE);
+                data = &data[5..];
+            }
+            let (datum, rest) = schema.decode(data).unwrap();
+            assert!(rest.is_empty(), "{} bytes after the datum", rest.len());
+            assert_eq!(datum.field("CDMVersion"), Some(&string(CDM_VERSION)));
+            assert_eq!(datum.field("hostId"), Some(&uuid(&host_id())));
+            assert_eq!(datum.field("source"),
+                Some(&Value::Enum(SOURCE_FREEBSD_DTRACE_CADETS.to_string())));
+            datum.field("datum").unwrap().clone()
+        }).collect()
+    }
+
+    fn name(datum: &Value) -> &str {
+        match *datum {
+            Value::Record(ref name, _) => name,
+            _ => panic!("{:?} isn't a record", datum),
+        }
+    }
+
+    #[test]
+    fn maps_syscalls_and_audit_events() {
+        assert_eq!(event_type("openat"), "EVENT_OPEN");
+        assert_eq!(event_type("aue_open_rwtc"), "EVENT_OPEN");
+        assert_eq!(event_type("aue_execve"), "EVENT_EXECUTE");
+        assert_eq!(event_type("dup2"), "EVENT_DUP");
+        assert_eq!(event_type("getpid"), "EVENT_OTHER");
+    }
+
+    #[test]
+    fn maps_syscalls_onto_schema_event_types() {
+        for function in &["accept", "setuid", "stat", "close", "connect", "mkdir", "thr_new",
+            "dup", "execve", "exit", "fcntl", "fork", "link", "lseek", "mmap", "chmod",
+            "mount", "mprotect", "open", "read", "recvfrom", "recvmsg", "rename", "sendmsg",
+            "sendto", "kill", "truncate", "unmount", "unlink", "wait4", "write", "getpid"] {
+            let mut record = open_entry(1, "/");
+            record.function = function.to_string();
+            let mut encoder = CdmEncoder::new(host_id(), vec![], None);
+            let datums = decode(&encoder.encode(&record).unwrap(), false);
+            assert_eq!(datums[0].field("type"),
+                Some(&Value::Enum(event_type(function).to_string())));
+        }
+    }
+
+    #[test]
+    fn encodes_datums_in_schema() {
+        let mut encoder = CdmEncoder::new(host_id(), fields(), None);
+        let datums = decode(&encoder.encode(&open_entry(812, "/etc/passwd")).unwrap(), false);
+
+        let names: Vec<&str> = datums.iter().map(name).collect();
+        assert_eq!(names, vec!["Principal", "Subject", "FileObject", "Event"]);
+        let (principal, subject, file, event) = (&datums[0], &datums[1], &datums[2], &datums[3]);
+
+        assert_eq!(principal.field("userId"), Some(&string("1001")));
+        assert_eq!(subject.field("cid"), Some(&Value::Int(812)));
+        assert_eq!(subject.field("localPrincipal"), principal.field("uuid"));
+        assert_eq!(subject.field("cmdLine"), Some(&string("sshd")));
+        assert_eq!(file.field("type"), Some(&Value::Enum("FILE_OBJECT_FILE".to_string())));
+        assert_eq!(event.field("type"), Some(&Value::Enum("EVENT_OPEN".to_string())));
+        assert_eq!(event.field("sequence"), Some(&Value::Long(0)));
+        assert_eq!(event.field("subject"), subject.field("uuid"));
+        assert_eq!(event.field("predicateObject"), file.field("uuid"));
+        assert_eq!(event.field("predicateObjectPath"), Some(&string("/etc/passwd")));
+        assert_eq!(event.field("timestampNanos"), Some(&Value::Long(1000)));
+        assert_eq!(event.field("name"), Some(&string("syscall:freebsd:open:entry")));
+    }
+
+    #[test]
+    fn emits_subject_and_file_once() {
+        let mut encoder = CdmEncoder::new(host_id(), fields(), None);
+        encoder.encode(&open_entry(812, "/etc/passwd")).unwrap();
+        let datums = decode(&encoder.encode(&open_entry(812, "/etc/passwd")).unwrap(), false);
+        assert_eq!(datums.iter().map(name).collect::<Vec<&str>>(), vec!["Event"]);
+        assert_eq!(datums[0].field("sequence"), Some(&Value::Long(1)));
+    }
+
+    #[test]
+    fn forgets_oldest_seen_values() {
+        let mut seen = Seen::new(2);
+        assert!(seen.insert(&1));
+        assert!(seen.insert(&2));
+        assert!(!seen.insert(&1));
+        assert!(seen.insert(&3));
+        assert_eq!(seen.values.len(), 2);
+        assert!(seen.insert(&1));
+        assert!(!seen.insert(&3));
+    }
+
+    #[test]
+    fn frames_datums_with_schema_id() {
+        let mut encoder = CdmEncoder::new(host_id(), fields(), Some(0x01020304));
+        let encoded = encoder.encode(&open_entry(812, "/etc/passwd")).unwrap();
+        assert_eq!(encoded.len(), 4);
+        for message in &encoded {
+            assert_eq!(&message[..5], &[MAGIC_BYTE, 0x01, 0x02, 0x03, 0x04]);
+        }
+        assert_eq!(decode(&encoded, true).iter().map(name).collect::<Vec<&str>>(),
+            vec!["Principal", "Subject", "FileObject", "Event"]);
+    }
+
+    #[test]
+    fn records_envelope_metadata() {
+        let metadata = ::Metadata {
+            hostname: "db1".to_string(),
+            hostuuid: host_id().hyphenated().to_string(),
+            agent_name: "ddtrace".to_string(),
+            agent_version: "0.1.0".to_string(),
+            script_id: "opens".to_string(),
+            run_epoch: 5,
+        };
+        let envelope = Envelope::new(metadata, 7, open_entry(812, "/etc/passwd"));
+        let mut encoder = CdmEncoder::new(host_id(), vec![], None);
+        let datums = decode(&encoder.encode_envelope(&envelope).unwrap(), false);
+
+        assert_eq!(datums.iter().map(name).collect::<Vec<&str>>(), vec!["Host", "Event"]);
+        assert_eq!(datums[0].field("hostName"), Some(&string("db1")));
+        let mut expected = BTreeMap::new();
+        for (k, v) in &[("ddtrace_agent", "ddtrace/0.1.0"), ("ddtrace_emitted_at", "7"),
+            ("ddtrace_hostname", "db1"), ("ddtrace_run_epoch", "5"),
+            ("ddtrace_script", "opens")] {
+            expected.insert(k.to_string(), string(v));
+        }
+        assert_eq!(datums[1].field("properties"), Some(&Value::Map(expected)));
+
+        // The Host is only emitted once
+        let datums = decode(&encoder.encode_envelope(&envelope).unwrap(), false);
+        assert_eq!(datums.iter().map(name).collect::<Vec<&str>>(), vec!["Event"]);
+    }
+}
@@ -0,0 +1,803 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+// Structured DTrace records and their encodings
+//
+// The agent builds a ProbeRecord from each probe firing (the
+// dtrace_probedata_t and its dtrace_recdesc_t records), alongside records
+// for aggregation snapshots, drops and errors, and encodes them using one of
+// the supported encodings. Consumers use the same crate to decode the
+// records they receive.
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate serde_cbor;
+extern crate rmp_serde;
+extern crate uuid;
+extern crate prost;
+// The generated Protocol Buffers messages refer to ::core
+extern crate core;
+
+pub mod avro;
+pub mod cdm;
+pub mod proto;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Read;
+
+// A typed value decoded from a single DTrace record
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum RecordValue {
+    Int(i64),
+    String(String),
+    Bytes(Vec<u8>),
+    Exit(i32),
+    Stack(Vec<u64>),
+    #[serde(rename = "ustack")]
+    UserStack { pid: u64, frames: Vec<u64> },
+    #[serde(rename = "addr")]
+    Address(u64),
+    #[serde(rename = "uaddr")]
+    UserAddress { pid: u64, addr: u64 },
+    // Kernel stack resolved to symbols (innermost frame first)
+    #[serde(rename = "symstack")]
+    SymbolStack(Vec<Frame>),
+    // User stack resolved to symbols (innermost frame first)
+    #[serde(rename = "usymstack")]
+    UserSymbolStack { pid: u64, frames: Vec<Frame> },
+    // Stack as a folded line, outermost frame first and frames separated by
+    // semicolons (the input format of flame graph tools)
+    Folded(String),
+}
+
+// A stack frame resolved to module, symbol and offset (module and symbol
+// are empty if the address couldn't be resolved)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Frame {
+    pub addr: u64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub module: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub symbol: String,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+impl Frame {
+    // The frame as module`symbol (as printed by DTrace, without the offset)
+    // or the address if it was not resolved
+    pub fn name(&self) -> String {
+        match (self.module.is_empty(), self.symbol.is_empty()) {
+            (false, false) => format!("{}`{}", self.module, self.symbol),
+            (true, false) => self.symbol.clone(),
+            (false, true) => format!("{}`0x{:x}", self.module, self.addr),
+            (true, true) => format!("0x{:x}", self.addr),
+        }
+    }
+}
+
+// Fold a stack (innermost frame first) into a single line with the
+// outermost frame first
+pub fn fold_stack(frames: &[Frame]) -> String {
+    frames.iter().rev().map(Frame::name).collect::<Vec<String>>().join(";")
+}
+
+// A single probe firing and the records it produced
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProbeRecord {
+    pub id: u32,
+    pub provider: String,
+    pub module: String,
+    pub function: String,
+    pub name: String,
+    pub cpu: i32,
+    pub timestamp: u64,
+    pub values: Vec<RecordValue>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub output: String,
+}
+
+// The contents of the script's aggregations at a point in time
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AggregationSnapshot {
+    pub timestamp: u64,
+    // "cumulative" (values since the script started) or "delta" (values
+    // accumulated between interval_start and interval_end)
+    #[serde(default)]
+    pub mode: String,
+    // Wall-clock times bounding the reporting interval (nanoseconds since
+    // the epoch)
+    #[serde(default)]
+    pub interval_start: u64,
+    #[serde(default)]
+    pub interval_end: u64,
+    pub entries: Vec<AggregationEntry>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AggregationEntry {
+    pub name: String,
+    pub function: String,
+    pub keys: Vec<RecordValue>,
+    pub value: i64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub buckets: Vec<Bucket>,
+    // Number of keys combined into this entry when it is the "others"
+    // entry summarising the keys beyond an aggregation's top_n
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub others: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+// A quantize(), lquantize() or llquantize() bucket covering [lower, upper]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub lower: i64,
+    pub upper: i64,
+    pub count: i64,
+}
+
+// Records dropped by DTrace
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct DropRecord {
+    pub kind: String,
+    pub cpu: i32,
+    pub count: u64,
+    pub total: u64,
+    pub message: String,
+}
+
+// A runtime fault in the D program
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    pub provider: String,
+    pub module: String,
+    pub function: String,
+    pub name: String,
+    pub fault: String,
+    pub offset: i32,
+    pub message: String,
+}
+
+// A change in the state of a process controlled by DTrace (traced with the
+// pid or USDT providers): event is one of "notice" (message from libdtrace,
+// such as an exec), "exited" (exit_status holds the exit status),
+// "killed" (signal holds the terminating signal) or "lost" (the process can
+// no longer be traced)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessRecord {
+    pub pid: u64,
+    pub event: String,
+    #[serde(default)]
+    pub exit_status: i32,
+    #[serde(default)]
+    pub signal: i32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub message: String,
+}
+
+// Counters of the records discarded by the agent before transport, of the
+// script's runtime errors and of the records dropped by DTrace, the state
+// of the processes it traces and its effective DTrace options
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsRecord {
+    pub passed: u64,
+    pub sampled_out: u64,
+    pub rate_limited: u64,
+    // Runtime faults raised by the D program
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub errors: u64,
+    // Records dropped by DTrace, by kind of drop
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub drops: BTreeMap<String, u64>,
+    // Last event of each process controlled by DTrace
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub processes: BTreeMap<u64, String>,
+    // Effective value of the DTrace options set by the agent or the script
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, i64>,
+}
+
+// Identifies the endpoint, agent and script that emitted a record
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub hostname: String,
+    pub hostuuid: String,
+    pub agent_name: String,
+    pub agent_version: String,
+    pub script_id: String,
+    // Start time of this run of the script (milliseconds since the epoch)
+    pub run_epoch: u64,
+}
+
+// A record together with the metadata identifying its origin
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    // Wall-clock time the record was emitted (nanoseconds since the epoch)
+    pub emitted_at: u64,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub record: T,
+}
+
+impl<T: Record> Envelope<T> {
+    pub fn new(metadata: Metadata, emitted_at: u64, record: T) -> Envelope<T> {
+        Envelope {
+            metadata,
+            emitted_at,
+            record_type: T::TYPE.to_string(),
+            record,
+        }
+    }
+}
+
+// A record that can be written using any of the supported encodings
+pub trait Record: serde::Serialize + serde::de::DeserializeOwned + Sized {
+    // Record type named in envelopes
+    const TYPE: &'static str;
+
+    fn to_proto(&self) -> proto::Record;
+    fn from_proto(record: proto::Record) -> Result<Self, Error>;
+}
+
+// A top-level message written to a transport: either a bare Record or an
+// Envelope
+pub trait Message: serde::Serialize + serde::de::DeserializeOwned + Sized {
+    fn to_protobuf(&self) -> Vec<u8>;
+    fn from_protobuf(data: &[u8]) -> Result<Self, Error>;
+}
+
+impl<T: Record> Message for T {
+    fn to_protobuf(&self) -> Vec<u8> {
+        prost::Message::encode_to_vec(&self.to_proto())
+    }
+
+    fn from_protobuf(data: &[u8]) -> Result<T, Error> {
+        let record: proto::Record = prost::Message::decode(data)?;
+        T::from_proto(record)
+    }
+}
+
+impl<T: Record> Message for Envelope<T> {
+    fn to_protobuf(&self) -> Vec<u8> {
+        prost::Message::encode_to_vec(&proto::Envelope {
+            hostname: self.metadata.hostname.clone(),
+            hostuuid: self.metadata.hostuuid.clone(),
+            agent_name: self.metadata.agent_name.clone(),
+            agent_version: self.metadata.agent_version.clone(),
+            script_id: self.metadata.script_id.clone(),
+            run_epoch: self.metadata.run_epoch,
+            emitted_at: self.emitted_at,
+            record: Some(self.record.to_proto()),
+        })
+    }
+
+    fn from_protobuf(data: &[u8]) -> Result<Envelope<T>, Error> {
+        let envelope: proto::Envelope = prost::Message::decode(data)?;
+        let record = T::from_proto(envelope.record.ok_or(Error::UnexpectedRecord)?)?;
+        Ok(Envelope {
+            metadata: Metadata {
+                hostname: envelope.hostname,
+                hostuuid: envelope.hostuuid,
+                agent_name: envelope.agent_name,
+                agent_version: envelope.agent_version,
+                script_id: envelope.script_id,
+                run_epoch: envelope.run_epoch,
+            },
+            emitted_at: envelope.emitted_at,
+            record_type: T::TYPE.to_string(),
+            record,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    // One JSON object per line
+    Json,
+    // Concatenated CBOR data items (RFC 7049)
+    Cbor,
+    // Concatenated MessagePack maps
+    MessagePack,
+    // Length-delimited Protocol Buffers Record or Envelope messages
+    // (proto/ddtrace.proto)
+    Protobuf,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            "json" => Some(Encoding::Json),
+            "cbor" => Some(Encoding::Cbor),
+            "msgpack" | "messagepack" => Some(Encoding::MessagePack),
+            "protobuf" | "proto" => Some(Encoding::Protobuf),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+            Encoding::MessagePack => "msgpack",
+            Encoding::Protobuf => "protobuf",
+        }
+    }
+
+    pub fn encode<T: Message>(&self, record: &T) -> Result<Vec<u8>, Error> {
+        match *self {
+            Encoding::Json => {
+                let mut encoded = serde_json::to_vec(record)?;
+                encoded.push(b'\n');
+                Ok(encoded)
+            },
+            Encoding::Cbor => Ok(serde_cbor::to_vec(record)?),
+            Encoding::MessagePack => Ok(rmp_serde::to_vec_named(record)?),
+            Encoding::Protobuf => {
+                let message = record.to_protobuf();
+                let mut encoded = Vec::with_capacity(message.len() + 4);
+                prost::encoding::encode_varint(message.len() as u64, &mut encoded);
+                encoded.extend(message);
+                Ok(encoded)
+            },
+        }
+    }
+
+    pub fn decode<T: Message>(&self, data: &[u8]) -> Result<T, Error> {
+        match *self {
+            Encoding::Json => Ok(serde_json::from_slice(data)?),
+            Encoding::Cbor => Ok(serde_cbor::from_slice(data)?),
+            Encoding::MessagePack => Ok(rmp_serde::from_slice(data)?),
+            Encoding::Protobuf => {
+                let mut data = data;
+                let len = prost::encoding::decode_varint(&mut data)? as usize;
+                if len > data.len() {
+                    return Err(Error::Protobuf(prost::DecodeError::new("truncated message")));
+                }
+                T::from_protobuf(&data[..len])
+            },
+        }
+    }
+
+    // Decode the next record from a stream of concatenated records
+    // (returns Ok(None) at the end of the stream)
+    pub fn decode_from<T, R>(&self, reader: &mut R) -> Result<Option<T>, Error>
+        where T: Message, R: Read {
+
+        if *self == Encoding::Protobuf {
+            return match proto::read_delimited(reader)? {
+                Some(message) => T::from_protobuf(&message).map(Some),
+                None => Ok(None),
+            };
+        }
+
+        let mut peek = [0u8; 1];
+        let mut buffered = Vec::new();
+        loop {
+            match reader.read(&mut peek)? {
+                0 => return Ok(None),
+                _ if *self == Encoding::Json && peek[0].is_ascii_whitespace() => continue,
+                _ => {
+                    buffered.push(peek[0]);
+                    break;
+                }
+            }
+        }
+
+        let mut chained = (&buffered[..]).chain(reader);
+        let record = match *self {
+            Encoding::Json => {
+                let mut stream = serde_json::Deserializer::from_reader(chained)
+                    .into_iter::<T>();
+                stream.next().unwrap_or(Err(
+                    serde::de::Error::custom("unexpected end of stream")))?
+            },
+            Encoding::Cbor => {
+                let mut deserializer = serde_cbor::Deserializer::from_reader(&mut chained);
+                serde::Deserialize::deserialize(&mut deserializer)?
+            },
+            Encoding::MessagePack => {
+                let mut deserializer = rmp_serde::Deserializer::new(&mut chained);
+                serde::Deserialize::deserialize(&mut deserializer)?
+            },
+            Encoding::Protobuf => unreachable!(),
+        };
+        Ok(Some(record))
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Cbor(serde_cbor::Error),
+    MessagePackEncode(rmp_serde::encode::Error),
+    MessagePackDecode(rmp_serde::decode::Error),
+    Protobuf(prost::DecodeError),
+    // The datum doesn't fit the Avro schema
+    Avro(String),
+    // A length-delimited message larger than proto::MAX_MESSAGE_SIZE
+    MessageTooLarge(u64),
+    // The decoded record wasn't of the requested type
+    UnexpectedRecord,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Json(ref e) => write!(f, "JSON error: {}", e),
+            Error::Cbor(ref e) => write!(f, "CBOR error: {}", e),
+            Error::MessagePackEncode(ref e) => write!(f, "MessagePack encode error: {}", e),
+            Error::MessagePackDecode(ref e) => write!(f, "MessagePack decode error: {}", e),
+            Error::Protobuf(ref e) => write!(f, "Protocol Buffers error: {}", e),
+            Error::Avro(ref e) => write!(f, "Avro error: {}", e),
+            Error::MessageTooLarge(len) => write!(f, "message of {} bytes is too large", len),
+            Error::UnexpectedRecord => write!(f, "unexpected record type"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error { Error::Io(e) }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error { Error::Json(e) }
+}
+
+impl From<serde_cbor::Error> for Error {
+    fn from(e: serde_cbor::Error) -> Error { Error::Cbor(e) }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(e: rmp_serde::encode::Error) -> Error { Error::MessagePackEncode(e) }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(e: rmp_serde::decode::Error) -> Error { Error::MessagePackDecode(e) }
+}
+
+impl From<prost::DecodeError> for Error {
+    fn from(e: prost::DecodeError) -> Error { Error::Protobuf(e) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Canned records matching the fixtures in fixtures/
+    fn syscall_entry() -> ProbeRecord {
+        ProbeRecord {
+            id: 42,
+            provider: "syscall".to_string(),
+            module: "freebsd".to_string(),
+            function: "open".to_string(),
+            name: "entry".to_string(),
+            cpu: 1,
+            timestamp: 1497355200000000000,
+            values: vec![
+                RecordValue::String("sshd".to_string()),
+                RecordValue::Int(812),
+                RecordValue::Int(-1),
+            ],
+            output: String::new(),
+        }
+    }
+
+    fn symbolized_stacks() -> ProbeRecord {
+        let frames = vec![
+            Frame { addr: 0xffffffff80b7a2c1, module: "kernel".to_string(),
+                symbol: "vn_open_cred".to_string(), offset: 0x241 },
+            Frame { addr: 0xffffffff80b6d8e3, module: "kernel".to_string(),
+                symbol: "kern_openat".to_string(), offset: 0x123 },
+            Frame { addr: 0xffffffff80ff1c2e, ..Default::default() },
+        ];
+        ProbeRecord {
+            id: 7,
+            provider: "fbt".to_string(),
+            module: "kernel".to_string(),
+            function: "vn_open_cred".to_string(),
+            name: "entry".to_string(),
+            cpu: 3,
+            timestamp: 1497355200000000000,
+            values: vec![
+                RecordValue::SymbolStack(frames.clone()),
+                RecordValue::UserSymbolStack {
+                    pid: 812,
+                    frames: vec![
+                        Frame { addr: 0x800b2a3c4, module: "libc.so.7".to_string(),
+                            symbol: "_open".to_string(), offset: 0xc },
+                        Frame { addr: 0x400f00, module: "sshd".to_string(),
+                            ..Default::default() },
+                    ],
+                },
+                RecordValue::Folded(fold_stack(&frames)),
+            ],
+            output: String::new(),
+        }
+    }
+
+    fn stack_and_printf() -> ProbeRecord {
+        ProbeRecord {
+            id: 7,
+            provider: "fbt".to_string(),
+            module: "kernel".to_string(),
+            function: "vn_open".to_string(),
+            name: "return".to_string(),
+            cpu: 3,
+            timestamp: 1497355200000001234,
+            values: vec![
+                RecordValue::Stack(vec![0xffffffff80a2b3c4, 0xffffffff80a2b000]),
+                RecordValue::UserStack { pid: 812, frames: vec![0x400a10, 0x400b20] },
+                RecordValue::Address(0xffffffff80a2b3c4),
+                RecordValue::UserAddress { pid: 812, addr: 0x400a10 },
+                RecordValue::Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
+                RecordValue::Exit(0),
+            ],
+            output: "vn_open returned 0\n".to_string(),
+        }
+    }
+
+    fn aggregation_snapshot() -> AggregationSnapshot {
+        AggregationSnapshot {
+            timestamp: 1497355260000000000,
+            mode: "delta".to_string(),
+            interval_start: 1497355250000000000,
+            interval_end: 1497355260000000000,
+            entries: vec![
+                AggregationEntry {
+                    name: "syscalls".to_string(),
+                    function: "count".to_string(),
+                    keys: vec![RecordValue::String("sshd".to_string())],
+                    value: 1024,
+                    buckets: vec![],
+                    others: 0,
+                },
+                AggregationEntry {
+                    name: "latency".to_string(),
+                    function: "quantize".to_string(),
+                    keys: vec![],
+                    value: 0,
+                    buckets: vec![
+                        Bucket { lower: 512, upper: 1023, count: 3 },
+                        Bucket { lower: 1024, upper: 2047, count: 17 },
+                    ],
+                    others: 0,
+                },
+                AggregationEntry {
+                    name: "syscalls".to_string(),
+                    function: "count".to_string(),
+                    keys: vec![],
+                    value: 97,
+                    buckets: vec![],
+                    others: 12,
+                },
+            ],
+        }
+    }
+
+    fn drop_record() -> DropRecord {
+        DropRecord {
+            kind: "principal".to_string(),
+            cpu: 2,
+            count: 128,
+            total: 4096,
+            message: "128 drops on CPU 2".to_string(),
+        }
+    }
+
+    fn error_record() -> ErrorRecord {
+        ErrorRecord {
+            provider: "syscall".to_string(),
+            module: "freebsd".to_string(),
+            function: "open".to_string(),
+            name: "entry".to_string(),
+            fault: "invalid address".to_string(),
+            offset: 24,
+            message: "invalid address (0x0) in action #1 at DIF offset 24".to_string(),
+        }
+    }
+
+    fn envelope<T: Record>(record: T) -> Envelope<T> {
+        let metadata = Metadata {
+            hostname: "db1.example.com".to_string(),
+            hostuuid: "4c4c4544-0042-3510-8052-b4c04f4e3232".to_string(),
+            agent_name: "ddtrace".to_string(),
+            agent_version: "0.1.0".to_string(),
+            script_id: "syscalls".to_string(),
+            run_epoch: 1_546_300_800_000,
+        };
+        Envelope::new(metadata, 1_546_300_801_234_567_890, record)
+    }
+
+    const ENCODINGS: [Encoding; 4] = [
+        Encoding::Json, Encoding::Cbor, Encoding::MessagePack, Encoding::Protobuf];
+
+    // Compare the encodings of a record with the golden files in fixtures/
+    // (run with DDTRACE_UPDATE_FIXTURES set to regenerate them following an
+    // intentional schema change)
+    fn check_fixtures<T>(name: &str, record: T)
+        where T: Message + PartialEq + std::fmt::Debug {
+
+        for encoding in ENCODINGS.iter() {
+            let path = format!("{}/fixtures/{}.{}",
+                env!("CARGO_MANIFEST_DIR"), name, encoding.name());
+            let encoded = encoding.encode(&record).unwrap();
+            if std::env::var_os("DDTRACE_UPDATE_FIXTURES").is_some() {
+                std::fs::write(&path, &encoded).unwrap();
+            }
+
+            let fixture = std::fs::read(&path).unwrap();
+            assert_eq!(encoded, fixture, "{} differs from golden file", path);
+            let decoded: T = encoding.decode(&fixture).unwrap();
+            assert_eq!(decoded, record, "{} decoded", path);
+        }
+    }
+
+    #[test]
+    fn probe_record_fixtures() {
+        check_fixtures("syscall_entry", syscall_entry());
+        check_fixtures("stack_and_printf", stack_and_printf());
+        check_fixtures("symbolized_stacks", symbolized_stacks());
+    }
+
+    #[test]
+    fn folds_stacks_outermost_first() {
+        let frames = vec![
+            Frame { addr: 0x10, module: "kernel".to_string(),
+                symbol: "vn_open_cred".to_string(), offset: 4 },
+            Frame { addr: 0x20, module: "kernel".to_string(), ..Default::default() },
+            Frame { addr: 0x30, ..Default::default() },
+        ];
+        assert_eq!(fold_stack(&frames), "0x30;kernel`0x20;kernel`vn_open_cred");
+    }
+
+    #[test]
+    fn aggregation_snapshot_fixtures() {
+        check_fixtures("aggregation_snapshot", aggregation_snapshot());
+    }
+
+    #[test]
+    fn drop_record_fixtures() {
+        check_fixtures("drop", drop_record());
+    }
+
+    #[test]
+    fn error_record_fixtures() {
+        check_fixtures("error", error_record());
+    }
+
+    #[test]
+    fn process_record_fixtures() {
+        check_fixtures("process", ProcessRecord {
+            pid: 812,
+            event: "killed".to_string(),
+            exit_status: 0,
+            signal: 9,
+            message: String::new(),
+        });
+    }
+
+    #[test]
+    fn envelope_fixtures() {
+        check_fixtures("envelope_probe", envelope(syscall_entry()));
+        check_fixtures("envelope_stats", envelope(StatsRecord {
+            passed: 1200,
+            sampled_out: 300,
+            rate_limited: 17,
+            errors: 2,
+            drops: vec![("principal".to_string(), 1450), ("dynamic".to_string(), 3)]
+                .into_iter().collect(),
+            processes: vec![(812, "exited".to_string())].into_iter().collect(),
+            options: vec![("bufsize".to_string(), 4194304), ("switchrate".to_string(), 10)]
+                .into_iter().collect(),
+        }));
+    }
+
+    #[test]
+    fn rejects_unexpected_record_type() {
+        let encoded = Encoding::Protobuf.encode(&drop_record()).unwrap();
+        assert!(Encoding::Protobuf.decode::<ErrorRecord>(&encoded).is_err());
+    }
+
+    #[test]
+    fn rejects_record_value_without_value() {
+        let mut probe = match syscall_entry().to_proto().record {
+            Some(proto::record::Record::Probe(probe)) => probe,
+            _ => unreachable!(),
+        };
+        probe.values.push(proto::RecordValue { value: None });
+        let encoded = prost::Message::encode_to_vec(&proto::Record {
+            record: Some(proto::record::Record::Probe(probe)),
+        });
+        assert!(Encoding::Protobuf.decode::<ProbeRecord>(&encoded).is_err());
+    }
+
+    #[test]
+    fn round_trips_record_stream() {
+        for encoding in ENCODINGS.iter() {
+            let records = vec![syscall_entry(), stack_and_printf(), syscall_entry()];
+            let mut stream = Vec::new();
+            for record in &records {
+                stream.extend(encoding.encode(record).unwrap());
+            }
+
+            let mut reader = &stream[..];
+            let mut decoded = Vec::new();
+            while let Some(record) = encoding.decode_from::<ProbeRecord, _>(&mut reader).unwrap() {
+                decoded.push(record);
+            }
+            assert_eq!(decoded, records, "{} stream", encoding.name());
+        }
+    }
+
+    #[test]
+    fn round_trips_envelope_stream() {
+        for encoding in ENCODINGS.iter() {
+            let envelopes = vec![envelope(syscall_entry()), envelope(stack_and_printf())];
+            let mut stream = Vec::new();
+            for envelope in &envelopes {
+                stream.extend(encoding.encode(envelope).unwrap());
+            }
+
+            let mut reader = &stream[..];
+            let mut decoded = Vec::new();
+            while let Some(envelope) =
+                encoding.decode_from::<Envelope<ProbeRecord>, _>(&mut reader).unwrap() {
+                decoded.push(envelope);
+            }
+            assert_eq!(decoded, envelopes, "{} stream", encoding.name());
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_protobuf_messages() {
+        // A length prefix of 2^32 bytes, followed by no message at all
+        let mut reader = &[0x80, 0x80, 0x80, 0x80, 0x10][..];
+        match Encoding::Protobuf.decode_from::<Envelope<ProbeRecord>, _>(&mut reader) {
+            Err(Error::MessageTooLarge(len)) => assert_eq!(len, 1 << 32),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
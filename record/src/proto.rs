@@ -0,0 +1,384 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+// Protocol Buffers messages
+//
+// The messages are generated from proto/ddtrace.proto (the reference for
+// consumers in other languages) by build.rs.
+
+use std::convert::TryFrom;
+use std::io::Read;
+use {DropRecord, ErrorRecord, ProbeRecord, ProcessRecord, StatsRecord};
+use Frame as RecordFrame;
+
+include!(concat!(env!("OUT_DIR"), "/ddtrace.rs"));
+
+// Largest length-delimited message read from a stream, guarding against
+// allocating whatever a corrupt length prefix claims
+pub const MAX_MESSAGE_SIZE: u64 = 16 * 1024 * 1024;
+
+// Read the next length-delimited message from a stream
+// (returns Ok(None) at the end of the stream)
+pub fn read_delimited<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, ::Error> {
+    let mut len: u64 = 0;
+    let mut shift = 0;
+    let mut byte = [0u8; 1];
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            if shift == 0 {
+                return Ok(None);
+            }
+            return Err(::Error::Io(::std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        len |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(::Error::Protobuf(::prost::DecodeError::new("invalid varint")));
+        }
+    }
+
+    if len > MAX_MESSAGE_SIZE {
+        return Err(::Error::MessageTooLarge(len));
+    }
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf)?;
+    Ok(Some(buf))
+}
+
+impl From<&::RecordValue> for RecordValue {
+    fn from(value: &::RecordValue) -> RecordValue {
+        let value = match *value {
+            ::RecordValue::Int(v) => record_value::Value::Int(v),
+            ::RecordValue::String(ref v) => record_value::Value::String(v.clone()),
+            ::RecordValue::Bytes(ref v) => record_value::Value::Bytes(v.clone()),
+            ::RecordValue::Exit(v) => record_value::Value::Exit(v),
+            ::RecordValue::Stack(ref frames) => record_value::Value::Stack(Stack {
+                frames: frames.clone(),
+            }),
+            ::RecordValue::UserStack { pid, ref frames } => record_value::Value::Ustack(UserStack {
+                pid,
+                frames: frames.clone(),
+            }),
+            ::RecordValue::Address(addr) => record_value::Value::Addr(addr),
+            ::RecordValue::UserAddress { pid, addr } => record_value::Value::Uaddr(UserAddress {
+                pid,
+                addr,
+            }),
+            ::RecordValue::SymbolStack(ref stack) => record_value::Value::Symstack(SymbolStack {
+                frames: frames(stack),
+            }),
+            ::RecordValue::UserSymbolStack { pid, frames: ref stack } =>
+                record_value::Value::Usymstack(UserSymbolStack {
+                    pid,
+                    frames: frames(stack),
+                }),
+            ::RecordValue::Folded(ref v) => record_value::Value::Folded(v.clone()),
+        };
+        RecordValue { value: Some(value) }
+    }
+}
+
+impl TryFrom<RecordValue> for ::RecordValue {
+    type Error = ::Error;
+
+    fn try_from(value: RecordValue) -> Result<::RecordValue, ::Error> {
+        let value = match value.value {
+            Some(record_value::Value::Int(v)) => ::RecordValue::Int(v),
+            Some(record_value::Value::String(v)) => ::RecordValue::String(v),
+            Some(record_value::Value::Bytes(v)) => ::RecordValue::Bytes(v),
+            Some(record_value::Value::Exit(v)) => ::RecordValue::Exit(v),
+            Some(record_value::Value::Stack(stack)) => ::RecordValue::Stack(stack.frames),
+            Some(record_value::Value::Ustack(stack)) => ::RecordValue::UserStack {
+                pid: stack.pid,
+                frames: stack.frames,
+            },
+            Some(record_value::Value::Addr(addr)) => ::RecordValue::Address(addr),
+            Some(record_value::Value::Uaddr(uaddr)) => ::RecordValue::UserAddress {
+                pid: uaddr.pid,
+                addr: uaddr.addr,
+            },
+            Some(record_value::Value::Symstack(stack)) =>
+                ::RecordValue::SymbolStack(record_frames(stack.frames)),
+            Some(record_value::Value::Usymstack(stack)) => ::RecordValue::UserSymbolStack {
+                pid: stack.pid,
+                frames: record_frames(stack.frames),
+            },
+            Some(record_value::Value::Folded(v)) => ::RecordValue::Folded(v),
+            None => return Err(::Error::Protobuf(
+                ::prost::DecodeError::new("RecordValue without a value"))),
+        };
+        Ok(value)
+    }
+}
+
+fn frames(frames: &[RecordFrame]) -> Vec<Frame> {
+    frames.iter().map(|frame| Frame {
+        addr: frame.addr,
+        module: frame.module.clone(),
+        symbol: frame.symbol.clone(),
+        offset: frame.offset,
+    }).collect()
+}
+
+fn record_frames(frames: Vec<Frame>) -> Vec<RecordFrame> {
+    frames.into_iter().map(|frame| RecordFrame {
+        addr: frame.addr,
+        module: frame.module,
+        symbol: frame.symbol,
+        offset: frame.offset,
+    }).collect()
+}
+
+fn values(values: &[::RecordValue]) -> Vec<RecordValue> {
+    values.iter().map(RecordValue::from).collect()
+}
+
+fn record_values(values: Vec<RecordValue>) -> Result<Vec<::RecordValue>, ::Error> {
+    values.into_iter().map(::RecordValue::try_from).collect()
+}
+
+impl ::Record for ProbeRecord {
+    const TYPE: &'static str = "probe";
+
+    fn to_proto(&self) -> Record {
+        Record {
+            record: Some(record::Record::Probe(ProbeFiring {
+                id: self.id,
+                provider: self.provider.clone(),
+                module: self.module.clone(),
+                function: self.function.clone(),
+                name: self.name.clone(),
+                cpu: self.cpu,
+                timestamp: self.timestamp,
+                values: values(&self.values),
+                output: self.output.clone(),
+            })),
+        }
+    }
+
+    fn from_proto(record: Record) -> Result<ProbeRecord, ::Error> {
+        match record.record {
+            Some(record::Record::Probe(probe)) => Ok(ProbeRecord {
+                id: probe.id,
+                provider: probe.provider,
+                module: probe.module,
+                function: probe.function,
+                name: probe.name,
+                cpu: probe.cpu,
+                timestamp: probe.timestamp,
+                values: record_values(probe.values)?,
+                output: probe.output,
+            }),
+            _ => Err(::Error::UnexpectedRecord),
+        }
+    }
+}
+
+impl ::Record for ::AggregationSnapshot {
+    const TYPE: &'static str = "aggregation";
+
+    fn to_proto(&self) -> Record {
+        let entries = self.entries.iter().map(|entry| AggregationEntry {
+            name: entry.name.clone(),
+            function: entry.function.clone(),
+            keys: values(&entry.keys),
+            value: entry.value,
+            buckets: entry.buckets.iter().map(|bucket| Bucket {
+                lower: bucket.lower,
+                upper: bucket.upper,
+                count: bucket.count,
+            }).collect(),
+            others: entry.others,
+        }).collect();
+
+        Record {
+            record: Some(record::Record::Aggregation(AggregationSnapshot {
+                timestamp: self.timestamp,
+                entries,
+                mode: self.mode.clone(),
+                interval_start: self.interval_start,
+                interval_end: self.interval_end,
+            })),
+        }
+    }
+
+    fn from_proto(record: Record) -> Result<::AggregationSnapshot, ::Error> {
+        match record.record {
+            Some(record::Record::Aggregation(snapshot)) => Ok(::AggregationSnapshot {
+                timestamp: snapshot.timestamp,
+                mode: snapshot.mode,
+                interval_start: snapshot.interval_start,
+                interval_end: snapshot.interval_end,
+                entries: snapshot.entries.into_iter().map(|entry| Ok(::AggregationEntry {
+                    name: entry.name,
+                    function: entry.function,
+                    keys: record_values(entry.keys)?,
+                    value: entry.value,
+                    buckets: entry.buckets.into_iter().map(|bucket| ::Bucket {
+                        lower: bucket.lower,
+                        upper: bucket.upper,
+                        count: bucket.count,
+                    }).collect(),
+                    others: entry.others,
+                })).collect::<Result<Vec<::AggregationEntry>, ::Error>>()?,
+            }),
+            _ => Err(::Error::UnexpectedRecord),
+        }
+    }
+}
+
+impl ::Record for DropRecord {
+    const TYPE: &'static str = "drop";
+
+    fn to_proto(&self) -> Record {
+        Record {
+            record: Some(record::Record::Drop(Drop {
+                kind: self.kind.clone(),
+                cpu: self.cpu,
+                count: self.count,
+                total: self.total,
+                message: self.message.clone(),
+            })),
+        }
+    }
+
+    fn from_proto(record: Record) -> Result<DropRecord, ::Error> {
+        match record.record {
+            Some(record::Record::Drop(drop)) => Ok(DropRecord {
+                kind: drop.kind,
+                cpu: drop.cpu,
+                count: drop.count,
+                total: drop.total,
+                message: drop.message,
+            }),
+            _ => Err(::Error::UnexpectedRecord),
+        }
+    }
+}
+
+impl ::Record for ErrorRecord {
+    const TYPE: &'static str = "error";
+
+    fn to_proto(&self) -> Record {
+        Record {
+            record: Some(record::Record::Error(Error {
+                provider: self.provider.clone(),
+                module: self.module.clone(),
+                function: self.function.clone(),
+                name: self.name.clone(),
+                fault: self.fault.clone(),
+                offset: self.offset,
+                message: self.message.clone(),
+            })),
+        }
+    }
+
+    fn from_proto(record: Record) -> Result<ErrorRecord, ::Error> {
+        match record.record {
+            Some(record::Record::Error(error)) => Ok(ErrorRecord {
+                provider: error.provider,
+                module: error.module,
+                function: error.function,
+                name: error.name,
+                fault: error.fault,
+                offset: error.offset,
+                message: error.message,
+            }),
+            _ => Err(::Error::UnexpectedRecord),
+        }
+    }
+}
+
+impl ::Record for StatsRecord {
+    const TYPE: &'static str = "stats";
+
+    fn to_proto(&self) -> Record {
+        Record {
+            record: Some(record::Record::Stats(Stats {
+                passed: self.passed,
+                sampled_out: self.sampled_out,
+                rate_limited: self.rate_limited,
+                errors: self.errors,
+                drops: self.drops.clone(),
+                processes: self.processes.clone(),
+                options: self.options.clone(),
+            })),
+        }
+    }
+
+    fn from_proto(record: Record) -> Result<StatsRecord, ::Error> {
+        match record.record {
+            Some(record::Record::Stats(stats)) => Ok(StatsRecord {
+                passed: stats.passed,
+                sampled_out: stats.sampled_out,
+                rate_limited: stats.rate_limited,
+                errors: stats.errors,
+                drops: stats.drops,
+                processes: stats.processes,
+                options: stats.options,
+            }),
+            _ => Err(::Error::UnexpectedRecord),
+        }
+    }
+}
+
+impl ::Record for ProcessRecord {
+    const TYPE: &'static str = "process";
+
+    fn to_proto(&self) -> Record {
+        Record {
+            record: Some(record::Record::Process(Process {
+                pid: self.pid,
+                event: self.event.clone(),
+                exit_status: self.exit_status,
+                signal: self.signal,
+                message: self.message.clone(),
+            })),
+        }
+    }
+
+    fn from_proto(record: Record) -> Result<ProcessRecord, ::Error> {
+        match record.record {
+            Some(record::Record::Process(process)) => Ok(ProcessRecord {
+                pid: process.pid,
+                event: process.event,
+                exit_status: process.exit_status,
+                signal: process.signal,
+                message: process.message,
+            }),
+            _ => Err(::Error::UnexpectedRecord),
+        }
+    }
+}
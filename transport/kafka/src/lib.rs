@@ -42,11 +42,20 @@ use kafka::producer::{Producer, Record};
 use std::ffi::CStr;
 use std::io::BufWriter;
 use std::io::Write;
+use rustc_serialize::{Decodable, Decoder};
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug)]
 struct Config {
 }
 
+// Decoded as #[derive(RustcDecodable)] did before current compilers
+// dropped it
+impl Decodable for Config {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Config, D::Error> {
+        d.read_struct("Config", 0, |_| Ok(Config {}))
+    }
+}
+
 /*
 impl Write for Producer {
     fn write(&mut self, buf: &[u8]) -> Result<usize>{
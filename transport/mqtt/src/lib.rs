@@ -0,0 +1,597 @@
+/*-
+ * Copyright (c) 2017 (Graeme Jenkinson)
+ * All rights reserved.
+ *
+ * This software was developed by BAE Systems, the University of Cambridge
+ * Computer Laboratory, and Memorial University under DARPA/AFRL contract
+ * FA8650-15-C-7558 ("CADETS"), as part of the DARPA Transparent Computing
+ * (TC) research program.
+ *
+ * Redistribution and use in source and binary forms, with or without
+ * modification, are permitted provided that the following conditions
+ * are met:
+ * 1. Redistributions of source code must retain the above copyright
+ *    notice, this list of conditions and the following disclaimer.
+ * 2. Redistributions in binary form must reproduce the above copyright
+ *    notice, this list of conditions and the following disclaimer in the
+ *    documentation and/or other materials provided with the distribution.
+ *
+ * THIS SOFTWARE IS PROVIDED BY THE AUTHOR AND CONTRIBUTORS ``AS IS'' AND
+ * ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+ * IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE
+ * ARE DISCLAIMED.  IN NO EVENT SHALL THE AUTHOR OR CONTRIBUTORS BE LIABLE
+ * FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+ * DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS
+ * OR SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION)
+ * HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT
+ * LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY
+ * OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
+ * SUCH DAMAGE.
+ *
+ */
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate lazy_static;
+extern crate libc;
+extern crate toml;
+extern crate rustc_serialize;
+extern crate rand;
+extern crate rumqttc;
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicIsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use rand::Rng;
+use rustc_serialize::{Decodable, Decoder};
+use rumqttc::{Client, Connection, ConnectionError, Event, MqttOptions, Outgoing, QoS};
+
+static SUCCESS: i32 = 0;
+static ERR_INVALID_HANDLE: i32 = -1;
+static ERR_INVALID_CONFIG: i32 = -2;
+static ERR_PUBLISH: i32 = -3;
+
+// Defaults used when the configuration omits a value
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TOPIC: &str = "ddtrace/{hostname}/{script}";
+const DEFAULT_SCRIPT_NAME: &str = "default";
+const DEFAULT_KEEPALIVE: u64 = 30;
+const REQUEST_CAPACITY: usize = 64;
+// Interval between attempts to reconnect to an unreachable broker
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Config {
+    instrumentation: Option<Instrumentation>,
+}
+
+#[derive(Debug)]
+struct Instrumentation {
+    name: Option<String>,
+    server: Option<ServerConfig>,
+}
+
+#[derive(Debug)]
+struct ServerConfig {
+    host: Option<String>,
+    port: Option<u16>,
+    topic: Option<String>,
+    qos: Option<u8>,
+    client_id: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    keepalive: Option<u64>,
+}
+
+// The configuration is decoded as the other transports' is, though without
+// #[derive(RustcDecodable)], which current compilers no longer provide
+impl Decodable for Config {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Config, D::Error> {
+        d.read_struct("Config", 1, |d| Ok(Config {
+            instrumentation: d.read_struct_field("instrumentation", 0, Decodable::decode)?,
+        }))
+    }
+}
+
+impl Decodable for Instrumentation {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Instrumentation, D::Error> {
+        d.read_struct("Instrumentation", 2, |d| Ok(Instrumentation {
+            name: d.read_struct_field("name", 0, Decodable::decode)?,
+            server: d.read_struct_field("server", 1, Decodable::decode)?,
+        }))
+    }
+}
+
+impl Decodable for ServerConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ServerConfig, D::Error> {
+        d.read_struct("ServerConfig", 8, |d| Ok(ServerConfig {
+            host: d.read_struct_field("host", 0, Decodable::decode)?,
+            port: d.read_struct_field("port", 1, Decodable::decode)?,
+            topic: d.read_struct_field("topic", 2, Decodable::decode)?,
+            qos: d.read_struct_field("qos", 3, Decodable::decode)?,
+            client_id: d.read_struct_field("client_id", 4, Decodable::decode)?,
+            username: d.read_struct_field("username", 5, Decodable::decode)?,
+            password: d.read_struct_field("password", 6, Decodable::decode)?,
+            keepalive: d.read_struct_field("keepalive", 7, Decodable::decode)?,
+        }))
+    }
+}
+
+// A connection to the MQTT broker
+// (DTrace records written to the publisher are buffered until the
+// next flush, at which point they are published as a single message)
+struct Publisher {
+    client: Client,
+    topic: String,
+    qos: QoS,
+    buffer: Vec<u8>,
+    // Messages (and their bytes) dropped because the client's request
+    // queue was full, as it is whilst the broker is unreachable
+    dropped: u64,
+    dropped_bytes: u64,
+    // Set when the publisher is closed, ending the connection's event loop
+    // (even if the broker was never reached)
+    closed: Arc<AtomicBool>,
+}
+
+impl Publisher {
+    // Queue the buffered records for publication without blocking (the
+    // DTrace consumer mustn't stall on an unreachable broker), dropping
+    // them if the request queue is full
+    fn publish(&mut self) -> Result<(), rumqttc::ClientError> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let payload = ::std::mem::take(&mut self.buffer);
+        let len = payload.len() as u64;
+        let result = self.client.try_publish(self.topic.as_str(), self.qos, false, payload);
+        if result.is_err() {
+            self.dropped += 1;
+            self.dropped_bytes += len;
+            warn!("Dropped {} messages ({} bytes) to {} so far", self.dropped,
+                self.dropped_bytes, self.topic);
+        }
+        result
+    }
+}
+
+struct Context {
+    next_handle: AtomicIsize,
+    // Publishers are locked individually, so that the map's lock is never
+    // held whilst publishing
+    handle_map: Mutex<HashMap<i32, Arc<Mutex<Publisher>>>>,
+}
+
+// The publisher for the handle (the map is only locked for the lookup)
+fn publisher(handle: i32) -> Option<Arc<Mutex<Publisher>>> {
+    CONTEXT.handle_map.lock().unwrap().get(&handle).cloned()
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context {
+            handle_map: Mutex::new(HashMap::new()),
+            next_handle: AtomicIsize::new(
+                rand::thread_rng().gen_range::<isize>(1, i16::MAX as isize)),
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONTEXT: Context = Context::new();
+}
+
+extern "C" {
+   fn gethostname(name: *mut libc::c_char, size: libc::size_t)
+      -> libc::c_int;
+}
+
+fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    let err = unsafe {
+        gethostname(buf.as_mut_ptr(), buf.len() as libc::size_t)
+    };
+    if err == 0 {
+        unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned()
+    } else {
+        "localhost".to_string()
+    }
+}
+
+// Expand the {hostname} and {script} placeholders in a topic template
+fn expand_topic(template: &str, hostname: &str, script: &str) -> String {
+    template
+        .replace("{hostname}", hostname)
+        .replace("{script}", script)
+}
+
+fn qos_from_level(level: u8) -> Option<QoS> {
+    match level {
+        0 => Some(QoS::AtMostOnce),
+        1 => Some(QoS::AtLeastOnce),
+        2 => Some(QoS::ExactlyOnce),
+        _ => None,
+    }
+}
+
+// Drive the MQTT event loop until the client disconnects (or is closed
+// whilst the broker is unreachable)
+// (rumqttc only sends and acknowledges packets whilst the connection
+// is being polled)
+fn drive_connection(mut connection: Connection, closed: Arc<AtomicBool>) {
+    for notification in connection.iter() {
+        match notification {
+            Ok(Event::Outgoing(Outgoing::Disconnect)) |
+            Err(ConnectionError::RequestsDone) => {
+                break;
+            },
+            Ok(event) => {
+                trace!("MQTT event {:?}", event);
+            },
+            Err(err) => {
+                error!("MQTT connection error {:?}", err);
+                // A disconnect is only sent once connected, so stop
+                // retrying once the publisher is closed
+                if closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                // Back off before rumqttc attempts to reconnect
+                thread::sleep(RECONNECT_INTERVAL);
+            }
+        }
+    }
+    trace!("MQTT event loop finished");
+}
+
+fn open_publisher(name: Option<String>, server: ServerConfig)
+    -> Option<Publisher> {
+
+    let host = match server.host {
+        Some(host) => host,
+        None => {
+            error!("MQTT configuration missing broker host");
+            return None;
+        }
+    };
+    let port = server.port.unwrap_or(DEFAULT_PORT);
+
+    let qos = match qos_from_level(server.qos.unwrap_or(0)) {
+        Some(qos) => qos,
+        None => {
+            error!("MQTT QoS must be 0, 1 or 2");
+            return None;
+        }
+    };
+
+    let hostname = hostname();
+    let script = name.unwrap_or(DEFAULT_SCRIPT_NAME.to_string());
+    let topic = expand_topic(
+        server.topic.as_deref().unwrap_or(DEFAULT_TOPIC),
+        hostname.as_str(), script.as_str());
+    let client_id = server.client_id.unwrap_or(
+        format!("ddtrace-{}-{}", hostname, script));
+
+    let mut options = MqttOptions::new(client_id, host.as_str(), port);
+    options.set_keep_alive(Duration::from_secs(
+        server.keepalive.unwrap_or(DEFAULT_KEEPALIVE)));
+    if let Some(username) = server.username {
+        options.set_credentials(username, server.password.unwrap_or_default());
+    }
+
+    info!("Connecting to MQTT broker {}:{} (topic {})", host, port, topic);
+    let (client, connection) = Client::new(options, REQUEST_CAPACITY);
+    let closed = Arc::new(AtomicBool::new(false));
+    let connection_closed = closed.clone();
+    match thread::Builder::new()
+        .name("ddtrace-mqtt".to_string())
+        .spawn(move || { drive_connection(connection, connection_closed); }) {
+        Ok(_) => {
+            Some(Publisher {
+                client,
+                topic,
+                qos,
+                buffer: Vec::new(),
+                dropped: 0,
+                dropped_bytes: 0,
+                closed,
+            })
+        },
+        Err(e) => {
+            error!("Failed spawning MQTT event loop {:?}", e);
+            None
+        }
+    }
+}
+
+#[no_mangle]
+pub fn dt_transport_init() -> i32
+{
+   SUCCESS
+}
+
+#[no_mangle]
+pub fn dt_transport_fini() -> i32
+{
+   SUCCESS
+}
+
+/// # Safety
+///
+/// config_raw must point to a NUL terminated string.
+#[no_mangle]
+pub unsafe fn dt_transport_open(config_raw: * const std::os::raw::c_char) -> i32
+{
+    // Read the configuration (a TOML formated string)
+    if let Ok(config_str) = CStr::from_ptr(config_raw).to_str() {
+        trace!("MQTT configuration {:?}", config_str);
+
+        if let Some(config) = toml::decode_str::<Config>(config_str) {
+            if let Some(publisher) = config.instrumentation
+                .and_then(|instrumentation| {
+                    let name = instrumentation.name;
+                    instrumentation.server.map(|server| (name, server))
+                })
+                .and_then(|(name, server)| open_publisher(name, server)) {
+
+                let handle = CONTEXT.next_handle.fetch_add(1, Ordering::SeqCst) as i32;
+                trace!("Storing new connection handle {}", handle);
+                CONTEXT.handle_map.lock().unwrap()
+                    .insert(handle, Arc::new(Mutex::new(publisher)));
+                handle
+            } else {
+                ERR_INVALID_CONFIG
+            }
+        } else {
+            ERR_INVALID_CONFIG
+        }
+    } else {
+       ERR_INVALID_CONFIG
+    }
+}
+
+#[no_mangle]
+pub fn dt_transport_close(handle: i32) -> i32
+{
+    // Remove the publisher from the CONTEXT handle_map, publishing any
+    // buffered records before disconnecting from the broker (once the
+    // map's lock is released)
+    let removed = CONTEXT.handle_map.lock().unwrap().remove(&handle);
+    if let Some(publisher) = removed {
+        let mut publisher = publisher.lock().unwrap();
+        if let Err(err) = publisher.publish() {
+            error!("Failed publishing buffered records to {}: {:?}",
+                publisher.topic, err);
+        }
+        if publisher.dropped > 0 {
+            warn!("Dropped {} messages ({} bytes) to {}", publisher.dropped,
+                publisher.dropped_bytes, publisher.topic);
+        }
+        trace!("Closing connection to MQTT broker (topic {})", publisher.topic);
+        if let Err(err) = publisher.client.try_disconnect() {
+            error!("Failed disconnecting from MQTT broker {:?}", err);
+        }
+        publisher.closed.store(true, Ordering::SeqCst);
+        SUCCESS
+    } else {
+        error!("Connection handle invalid");
+        ERR_INVALID_HANDLE
+    }
+}
+
+#[no_mangle]
+pub fn dt_transport_write(handle: i32, data: &[u8]) -> i32
+{
+    // Lookup the publisher corresponding to the handle
+    if let Some(publisher) = publisher(handle) {
+        // Buffer the DTrace records until the next flush
+        publisher.lock().unwrap().buffer.extend_from_slice(data);
+        SUCCESS
+    } else {
+        error!("Connection handle invalid");
+        ERR_INVALID_HANDLE
+    }
+}
+
+#[no_mangle]
+pub fn dt_transport_flush(handle: i32) -> i32
+{
+    if let Some(publisher) = publisher(handle) {
+        let mut publisher = publisher.lock().unwrap();
+        match publisher.publish() {
+            Ok(_) => {
+                trace!("Successfully published to {}", publisher.topic);
+                SUCCESS
+            },
+            Err(err) => {
+                error!("Failed publishing to {}: {:?}", publisher.topic, err);
+                ERR_PUBLISH
+            }
+        }
+    } else {
+        error!("Connection handle invalid");
+        ERR_INVALID_HANDLE
+    }
+}
+
+#[no_mangle]
+pub fn dt_transport_writeall(handle: i32, data: &[u8]) -> i32
+{
+    dt_transport_write(handle, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn expands_topic_template() {
+        assert_eq!(expand_topic("ddtrace/{hostname}/{script}", "host1", "syscalls"),
+            "ddtrace/host1/syscalls");
+        assert_eq!(expand_topic("fixed", "host1", "syscalls"), "fixed");
+    }
+
+    #[test]
+    fn maps_qos_levels() {
+        assert_eq!(qos_from_level(0), Some(QoS::AtMostOnce));
+        assert_eq!(qos_from_level(1), Some(QoS::AtLeastOnce));
+        assert_eq!(qos_from_level(2), Some(QoS::ExactlyOnce));
+        assert_eq!(qos_from_level(3), None);
+    }
+
+    #[test]
+    fn parses_server_config() {
+        let config = toml::decode_str::<Config>(r#"
+            [instrumentation]
+            name = "syscalls"
+            script = "syscall:::entry { trace(execname); }"
+
+            [instrumentation.server]
+            host = "localhost"
+            port = 1883
+            topic = "edge/{hostname}"
+            qos = 1
+            username = "ddtrace"
+            password = "secret"
+            keepalive = 10
+            "#).unwrap();
+        let instrumentation = config.instrumentation.unwrap();
+        assert_eq!(instrumentation.name, Some("syscalls".to_string()));
+        let server = instrumentation.server.unwrap();
+        assert_eq!(server.host, Some("localhost".to_string()));
+        assert_eq!(server.qos, Some(1));
+        assert_eq!(server.keepalive, Some(10));
+        assert_eq!(server.client_id, None);
+    }
+
+    // Read an MQTT control packet, returning its type and variable header
+    // and payload
+    fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let packet_type = byte[0] >> 4;
+
+        // The remaining length is a variable length integer
+        let mut length = 0;
+        let mut shift = 0;
+        loop {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (packet_type, body)
+    }
+
+    // Accept one client, acknowledging its connection and QoS 1 publishes
+    // until it disconnects, and return the messages published
+    fn broker(listener: TcpListener) -> Vec<(String, Vec<u8>)> {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut published = Vec::new();
+        loop {
+            match read_packet(&mut stream) {
+                // CONNECT
+                (1, _) => stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap(),
+                // PUBLISH
+                (3, body) => {
+                    let topic_len = ((body[0] as usize) << 8) | body[1] as usize;
+                    let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+                    let id = &body[2 + topic_len..4 + topic_len];
+                    stream.write_all(&[0x40, 0x02, id[0], id[1]]).unwrap();
+                    published.push((topic, body[4 + topic_len..].to_vec()));
+                },
+                // PINGREQ
+                (12, _) => stream.write_all(&[0xd0, 0x00]).unwrap(),
+                // DISCONNECT
+                (14, _) => return published,
+                (packet_type, _) => panic!("unexpected packet type {}", packet_type),
+            }
+        }
+    }
+
+    #[test]
+    fn publishes_flushed_records_to_broker() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let broker = thread::spawn(move || broker(listener));
+
+        let config = CString::new(format!(r#"
+            [instrumentation]
+            name = "syscalls"
+
+            [instrumentation.server]
+            host = "127.0.0.1"
+            port = {}
+            topic = "ddtrace/test/{{script}}"
+            qos = 1
+            "#, port)).unwrap();
+        let handle = unsafe { dt_transport_open(config.as_ptr()) };
+        assert!(handle > 0);
+        assert_eq!(dt_transport_write(handle, b"record 1\n"), SUCCESS);
+        assert_eq!(dt_transport_write(handle, b"record 2\n"), SUCCESS);
+        assert_eq!(dt_transport_flush(handle), SUCCESS);
+        assert_eq!(dt_transport_close(handle), SUCCESS);
+        assert_eq!(dt_transport_flush(handle), ERR_INVALID_HANDLE);
+
+        assert_eq!(broker.join().unwrap(), vec![("ddtrace/test/syscalls".to_string(),
+            b"record 1\nrecord 2\n".to_vec())]);
+    }
+
+    #[test]
+    fn drops_records_whilst_broker_is_unreachable() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config = CString::new(format!(r#"
+            [instrumentation.server]
+            host = "127.0.0.1"
+            port = {}
+            "#, port)).unwrap();
+        let handle = unsafe { dt_transport_open(config.as_ptr()) };
+        assert!(handle > 0);
+
+        // Flushing never blocks, records beyond the request queue's
+        // capacity are dropped and counted
+        let flushed = (0..2 * REQUEST_CAPACITY).map(|_| {
+            assert_eq!(dt_transport_write(handle, b"record\n"), SUCCESS);
+            dt_transport_flush(handle)
+        }).collect::<Vec<i32>>();
+        assert!(flushed.contains(&ERR_PUBLISH));
+        let dropped = publisher(handle).unwrap().lock().unwrap().dropped;
+        assert_eq!(dropped as usize, flushed.iter().filter(|&&r| r == ERR_PUBLISH).count());
+        assert_eq!(dt_transport_close(handle), SUCCESS);
+    }
+
+    #[test]
+    fn stops_reconnecting_once_closed() {
+        // Nothing listens on the port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let (_client, connection) = Client::new(
+            MqttOptions::new("ddtrace-test", "127.0.0.1", port), REQUEST_CAPACITY);
+
+        // Returns after the first failed connection attempt
+        drive_connection(connection, Arc::new(AtomicBool::new(true)));
+    }
+
+    #[test]
+    fn rejects_invalid_config() {
+        let config = CString::new("[instrumentation.server]\nqos = 1").unwrap();
+        assert_eq!(unsafe { dt_transport_open(config.as_ptr()) }, ERR_INVALID_CONFIG);
+        let config = CString::new("[instrumentation.server]\nhost = \"localhost\"\nqos = 3")
+            .unwrap();
+        assert_eq!(unsafe { dt_transport_open(config.as_ptr()) }, ERR_INVALID_CONFIG);
+    }
+}
@@ -48,23 +48,51 @@ use std::net::SocketAddr;
 use std::str::FromStr;
 use std::ffi::CStr;
 use rand::Rng;
+use rustc_serialize::{Decodable, Decoder};
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug)]
 struct Config {
     instrumentation: Option<Instrumentation>,
 }
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug)]
 struct Instrumentation {
     server: Option<ServerConfig>,
 }
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug)]
 struct ServerConfig {
     ip: Option<String>,
     port: Option<u16>,
 }
 
+// Decoded field by field, as #[derive(RustcDecodable)] did before current
+// compilers dropped it
+impl Decodable for Config {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Config, D::Error> {
+        d.read_struct("Config", 1, |d| Ok(Config {
+            instrumentation: d.read_struct_field("instrumentation", 0, Decodable::decode)?,
+        }))
+    }
+}
+
+impl Decodable for Instrumentation {
+    fn decode<D: Decoder>(d: &mut D) -> Result<Instrumentation, D::Error> {
+        d.read_struct("Instrumentation", 1, |d| Ok(Instrumentation {
+            server: d.read_struct_field("server", 0, Decodable::decode)?,
+        }))
+    }
+}
+
+impl Decodable for ServerConfig {
+    fn decode<D: Decoder>(d: &mut D) -> Result<ServerConfig, D::Error> {
+        d.read_struct("ServerConfig", 2, |d| Ok(ServerConfig {
+            ip: d.read_struct_field("ip", 0, Decodable::decode)?,
+            port: d.read_struct_field("port", 1, Decodable::decode)?,
+        }))
+    }
+}
+
 struct Context {
     conn_id: i32,
     handle_map: Mutex<HashMap<i32, BufWriter<TcpStream>>>,
@@ -85,9 +113,23 @@ lazy_static! {
 
 #[cfg(test)]
 mod tests {
+    use super::Config;
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn decodes_server_configuration() {
+        let config = ::toml::decode_str::<Config>(r#"
+            [instrumentation.server]
+            ip = "127.0.0.1"
+            port = 9999
+        "#).unwrap();
+        let server = config.instrumentation.unwrap().server.unwrap();
+        assert_eq!(server.ip, Some("127.0.0.1".to_string()));
+        assert_eq!(server.port, Some(9999));
+    }
 }
 
 #[no_mangle]